@@ -0,0 +1,131 @@
+//! Control-flow graph export, behind `wlvm cfg`.
+
+use crate::optimizer::jump_target;
+use crate::Instruction;
+use crate::Instruction::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// A straight sequence of instructions, only entered at its start.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    /// Index of the first instruction.
+    pub start: usize,
+    /// Index past the last instruction.
+    pub end: usize,
+    /// Blocks control may go to next, with the label of the edge.
+    pub edges: Vec<(usize, &'static str)>,
+}
+
+/// Splits a program into basic blocks, at labels and at jump targets.
+pub fn blocks(program: &[Instruction], labels: &BTreeMap<String, i32>) -> Vec<Block> {
+    let in_program = |t: i32| t >= 1 && t as usize <= program.len();
+    let mut leader = vec![false; program.len()];
+    if !program.is_empty() {
+        leader[0] = true;
+    }
+    for value in labels.values() {
+        if in_program(*value) {
+            leader[*value as usize - 1] = true;
+        }
+    }
+    for (i, instr) in program.iter().enumerate() {
+        match jump_target(instr) {
+            Some(t) if in_program(t) => leader[t as usize - 1] = true,
+            _ => {}
+        }
+        if (jump_target(instr).is_some() || matches!(instr, Hlt | Ret)) && i + 1 < program.len() {
+            leader[i + 1] = true;
+        }
+    }
+
+    let mut block_of = vec![0; program.len()];
+    let mut blocks: Vec<Block> = vec![];
+    for i in 0..program.len() {
+        if leader[i] {
+            blocks.push(Block {
+                start: i,
+                end: i,
+                edges: vec![],
+            });
+        }
+        block_of[i] = blocks.len() - 1;
+        blocks.last_mut().unwrap().end = i + 1;
+    }
+
+    for block in blocks.iter_mut() {
+        let following = block_of.get(block.end).copied();
+        let target = |t: i32| {
+            if in_program(t) {
+                Some(block_of[t as usize - 1])
+            } else {
+                None
+            }
+        };
+        let edges: Vec<(Option<usize>, &'static str)> = match program[block.end - 1] {
+            Hlt | Ret => vec![],
+            Gto(t) => vec![(target(t), "")],
+            Jmp(t) => vec![(target(t), "taken"), (following, "fallthrough")],
+            Cal(t) => vec![(target(t), "call"), (following, "return")],
+            _ => vec![(following, "")],
+        };
+        block.edges = edges
+            .into_iter()
+            .filter_map(|(b, label)| b.map(|b| (b, label)))
+            .collect();
+    }
+    blocks
+}
+
+/// Escapes a string for a DOT double quoted string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Renders the control-flow graph of a program in the DOT language.
+///
+/// `lines` holds the source line of each instruction, as returned by
+/// `parser::source_lines`. Instructions without a line are shown disassembled.
+pub fn to_dot(
+    program: &[Instruction],
+    labels: &BTreeMap<String, i32>,
+    lines: &[(usize, String)],
+) -> String {
+    let mut dot = String::new();
+    writeln!(dot, "digraph cfg {{").unwrap();
+    writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+    let blocks = blocks(program, labels);
+    for block in &blocks {
+        let mut text = String::new();
+        for (label, _) in labels
+            .iter()
+            .filter(|(_, v)| **v as usize == block.start + 1)
+        {
+            text.push_str(&escape(label));
+            text.push_str("\\l");
+        }
+        for (i, instr) in program.iter().enumerate().take(block.end).skip(block.start) {
+            match lines.get(i) {
+                Some((line, source)) => write!(text, "{}: {}\\l", line, escape(source)),
+                None => write!(text, "{:?}\\l", instr),
+            }
+            .unwrap();
+        }
+        writeln!(dot, "    i{} [label=\"{}\"];", block.start + 1, text).unwrap();
+    }
+    for block in &blocks {
+        for (to, label) in &block.edges {
+            let from = block.start + 1;
+            let to = blocks[*to].start + 1;
+            if label.is_empty() {
+                writeln!(dot, "    i{} -> i{};", from, to).unwrap();
+            } else {
+                writeln!(dot, "    i{} -> i{} [label=\"{}\"];", from, to, label).unwrap();
+            }
+        }
+    }
+
+    writeln!(dot, "}}").unwrap();
+    dot
+}
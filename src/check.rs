@@ -0,0 +1,485 @@
+//! Static analyzer behind `wlvm check`.
+//!
+//! The program is abstractly interpreted: every register holds the interval of
+//! values it may take and the stack is tracked slot by slot. States are kept
+//! per instruction and per stack depth, so that overflows and underflows are
+//! found along each path. Calls are assumed to return with a balanced stack
+//! and to clobber every register and stack slot.
+
+use crate::optimizer::{jump_target, reads};
+use crate::Instruction::*;
+use crate::{reg_name, Instruction, Register, Register::*, STACK_SIZE};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Number of times a state may grow before its intervals are widened, so that
+/// loops are analyzed in bounded time.
+const WIDENING_DELAY: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Human numbered instruction the diagnostic is about.
+    pub instruction: usize,
+    pub message: String,
+}
+
+/// An inclusive interval of `i32` values.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Range {
+    lo: i64,
+    hi: i64,
+}
+
+const FULL: Range = Range {
+    lo: i32::MIN as i64,
+    hi: i32::MAX as i64,
+};
+
+impl Range {
+    fn new(lo: i64, hi: i64) -> Range {
+        if lo < FULL.lo || hi > FULL.hi {
+            FULL
+        } else {
+            Range { lo, hi }
+        }
+    }
+
+    fn exactly(v: i64) -> Range {
+        Range::new(v, v)
+    }
+
+    fn constant(self) -> Option<i64> {
+        if self.lo == self.hi {
+            Some(self.lo)
+        } else {
+            None
+        }
+    }
+
+    fn contains(self, v: i64) -> bool {
+        self.lo <= v && v <= self.hi
+    }
+
+    fn hull(self, other: Range) -> Range {
+        Range::new(self.lo.min(other.lo), self.hi.max(other.hi))
+    }
+
+    /// Pushes the bounds that keep growing to the limits of `i32`.
+    fn widen(self, next: Range) -> Range {
+        Range {
+            lo: if next.lo < self.lo { FULL.lo } else { self.lo },
+            hi: if next.hi > self.hi { FULL.hi } else { self.hi },
+        }
+    }
+
+    fn corners(a: Range, b: Range, f: impl Fn(i64, i64) -> i64) -> Range {
+        let values = [f(a.lo, b.lo), f(a.lo, b.hi), f(a.hi, b.lo), f(a.hi, b.hi)];
+        Range::new(*values.iter().min().unwrap(), *values.iter().max().unwrap())
+    }
+
+    /// Quotient of the division by the non zero part of `divisor`, if any.
+    fn div(self, divisor: Range) -> Option<Range> {
+        let negative = Range::new(divisor.lo, divisor.hi.min(-1));
+        let positive = Range::new(divisor.lo.max(1), divisor.hi);
+        [negative, positive]
+            .iter()
+            .filter(|part| part.lo <= part.hi)
+            .map(|part| Range::corners(self, *part, |x, y| x / y))
+            .fold(None, |acc: Option<Range>, r| {
+                Some(acc.map_or(r, |acc| acc.hull(r)))
+            })
+    }
+
+    /// Outcome of a `t**` instruction: 1 when the comparison always holds, 0
+    /// when it never does and either otherwise.
+    fn compare(instr: Instruction, a: Range, b: Range) -> Range {
+        let (always, never) = match instr {
+            Tee(_, _) => (a.constant().is_some() && a == b, a.hi < b.lo || b.hi < a.lo),
+            Tne(_, _) => (a.hi < b.lo || b.hi < a.lo, a.constant().is_some() && a == b),
+            Tll(_, _) => (a.hi < b.lo, a.lo >= b.hi),
+            Tmm(_, _) => (a.lo > b.hi, a.hi <= b.lo),
+            Tel(_, _) => (a.hi <= b.lo, a.lo > b.hi),
+            Tem(_, _) => (a.lo >= b.hi, a.hi < b.lo),
+            _ => (false, false),
+        };
+        match (always, never) {
+            (true, _) => Range::exactly(1),
+            (_, true) => Range::exactly(0),
+            _ => Range::new(0, 1),
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.constant() {
+            Some(v) => write!(f, "{}", v),
+            None => write!(f, "{}..={}", self.lo, self.hi),
+        }
+    }
+}
+
+fn name(reg: Register) -> String {
+    reg_name(reg as i32).to_lowercase()
+}
+
+/// Abstract machine state before an instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct State {
+    /// Values of the registers, `ip` and `sp` being derived from the position.
+    regs: [Range; 10],
+    /// Whether each register is written along at least one path.
+    written: [bool; 10],
+    /// Values of the slots up to `sp`.
+    stack: Vec<Range>,
+}
+
+impl State {
+    fn entry() -> State {
+        let mut written = [false; 10];
+        written[Ip as usize] = true;
+        written[Sp as usize] = true;
+        State {
+            regs: [Range::exactly(0); 10],
+            written,
+            stack: vec![],
+        }
+    }
+
+    fn get(&self, reg: Register, index: usize) -> Range {
+        match reg {
+            Ip => Range::exactly(index as i64),
+            Sp => Range::exactly(self.stack.len() as i64 - 1),
+            r => self.regs[r as usize],
+        }
+    }
+
+    fn set(&mut self, reg: Register, value: Range) {
+        self.regs[reg as usize] = value;
+        self.written[reg as usize] = true;
+    }
+
+    /// Merges another state at the same depth, telling whether this one grew.
+    fn join(&mut self, other: &State, widen: bool) -> bool {
+        let merge = |old: Range, new: Range| {
+            let joined = old.hull(new);
+            if widen {
+                old.widen(joined)
+            } else {
+                joined
+            }
+        };
+        let before = self.clone();
+        for r in 0..self.regs.len() {
+            self.regs[r] = merge(self.regs[r], other.regs[r]);
+            self.written[r] |= other.written[r];
+        }
+        for (slot, new) in self.stack.iter_mut().zip(&other.stack) {
+            *slot = merge(*slot, *new);
+        }
+        *self != before
+    }
+}
+
+/// Where execution may continue after an instruction, and what can go wrong
+/// running it.
+#[derive(Default)]
+struct Step {
+    next: Vec<(usize, State)>,
+    problems: Vec<String>,
+    /// Set when the instruction jumps somewhere that cannot be determined.
+    lost: bool,
+}
+
+fn step(program: &[Instruction], i: usize, state: &State) -> Step {
+    let mut step = Step::default();
+    let mut s = state.clone();
+    let instr = program[i].clone();
+    let len = s.stack.len();
+    let in_program = |t: i32| t >= 1 && t as usize <= program.len();
+
+    for reg in reads(&instr) {
+        if instr != Dmp && !s.written[reg as usize] {
+            step.problems
+                .push(format!("reads {}, which is never written", name(reg)));
+        }
+    }
+
+    // Index of the instruction run next when not jumping, if any
+    let mut next = Some(i + 1);
+    let mut write = None;
+    match instr {
+        Hlt => next = None,
+        Ret => {
+            if len == 0 {
+                step.problems
+                    .push("stack underflow, no return address on the stack".to_owned());
+            }
+            next = None;
+        }
+        Gto(t) => {
+            if in_program(t) {
+                step.next.push((t as usize - 1, s.clone()));
+            }
+            next = None;
+        }
+        Dmp | Drg(_) => {}
+        Prt(r) => {
+            let value = s.get(r, i);
+            if value.hi < 0 || value.lo > 255 {
+                step.problems.push(format!(
+                    "prt {} prints nothing, its value ({}) is outside 0..256",
+                    name(r),
+                    value
+                ));
+            }
+        }
+        Psh(_) | Cal(_) if len >= STACK_SIZE => {
+            step.problems
+                .push(format!("stack overflow, sp is already {}", STACK_SIZE - 1));
+            next = None;
+        }
+        Psh(v) => {
+            s.stack.push(Range::exactly(v as i64));
+            s.set(St, Range::exactly(v as i64));
+        }
+        Pop if len == 0 => {
+            step.problems
+                .push("stack underflow, the stack is empty".to_owned());
+            next = None;
+        }
+        Pop => {
+            s.stack.pop();
+            let top = s.stack.last().copied().unwrap_or(Range::exactly(0));
+            s.set(St, top);
+        }
+        Cal(t) => {
+            if in_program(t) {
+                let mut callee = s.clone();
+                callee.stack.push(Range::exactly(i as i64));
+                callee.set(St, Range::exactly(i as i64));
+                step.next.push((t as usize - 1, callee));
+            }
+            for reg in &[A, B, C, D, E, F, St, Eq] {
+                s.set(*reg, FULL);
+            }
+            for slot in s.stack.iter_mut() {
+                *slot = FULL;
+            }
+        }
+        Jmp(t) => {
+            let eq = s.get(Eq, i);
+            if eq.contains(1) && in_program(t) {
+                step.next.push((t as usize - 1, s.clone()));
+            }
+            if eq == Range::exactly(1) {
+                next = None;
+            }
+        }
+        Ast(message) => {
+            if !s.get(Eq, i).contains(1) {
+                step.problems
+                    .push(format!("assertion \"{}\" always fails", message));
+                next = None;
+            }
+            // Past the assertion, it held
+            s.set(Eq, Range::exactly(1));
+        }
+        Mov(a, b) => write = Some((a, s.get(b, i))),
+        Add(a, b) => write = Some((a, Range::corners(s.get(a, i), s.get(b, i), |x, y| x + y))),
+        Sub(a, b) => write = Some((a, Range::corners(s.get(a, i), s.get(b, i), |x, y| x - y))),
+        Mul(a, b) => write = Some((a, Range::corners(s.get(a, i), s.get(b, i), |x, y| x * y))),
+        Div(a, b) => {
+            let divisor = s.get(b, i);
+            if divisor.contains(0) {
+                step.problems.push(match divisor.constant() {
+                    Some(_) => format!("division by zero, {} is 0", name(b)),
+                    None => format!("possible division by zero, {} may be 0", name(b)),
+                });
+            }
+            match s.get(a, i).div(divisor) {
+                Some(quotient) => write = Some((a, quotient)),
+                None => next = None,
+            }
+        }
+        Tee(a, b) | Tne(a, b) | Tll(a, b) | Tmm(a, b) | Tel(a, b) | Tem(a, b) => {
+            write = Some((Eq, Range::compare(instr, s.get(a, i), s.get(b, i))));
+        }
+        // Both address the slot in `b`, `a` being the loaded or stored register
+        Lod(a, b) | Sto(b, a) => {
+            let address = s.get(b, i);
+            if address.hi < 0 || address.lo >= STACK_SIZE as i64 {
+                step.problems
+                    .push(format!("invalid stack address {}", address));
+                next = None;
+            } else if let Lod(_, _) = instr {
+                let value = if address.lo >= 0 && address.hi < len as i64 {
+                    s.stack[address.lo as usize..=address.hi as usize]
+                        .iter()
+                        .fold(s.stack[address.lo as usize], |acc, v| acc.hull(*v))
+                } else {
+                    FULL
+                };
+                write = Some((a, value));
+            } else {
+                let value = s.get(a, i);
+                let strong = address.constant().is_some();
+                let first = address.lo.max(0) as usize;
+                let last = (address.hi + 1).min(len as i64).max(0) as usize;
+                for slot in s.stack.iter_mut().take(last).skip(first) {
+                    *slot = if strong { value } else { slot.hull(value) };
+                }
+                if address.contains(len as i64 - 1) {
+                    let top = if strong {
+                        value
+                    } else {
+                        s.get(St, i).hull(value)
+                    };
+                    s.set(St, top);
+                }
+            }
+        }
+    }
+
+    match write {
+        Some((Ip, value)) => match value.constant() {
+            Some(v) if v + 1 >= 0 && ((v + 1) as usize) < program.len() => {
+                next = Some((v + 1) as usize)
+            }
+            Some(v) => {
+                step.problems.push(format!(
+                    "jumps to instruction {}, outside the program",
+                    v + 2
+                ));
+                next = None;
+            }
+            None => {
+                step.problems
+                    .push("jumps to an instruction that cannot be determined".to_owned());
+                step.lost = true;
+                next = None;
+            }
+        },
+        Some((Sp, value)) => match value.constant() {
+            Some(v) if v >= -1 && v < STACK_SIZE as i64 => s.stack.resize((v + 1) as usize, FULL),
+            Some(v) => {
+                step.problems
+                    .push(format!("sets sp to {}, outside the stack", v));
+                next = None;
+            }
+            None => {
+                step.problems
+                    .push("sets sp to a value that cannot be determined".to_owned());
+                next = None;
+            }
+        },
+        Some((reg, value)) => s.set(reg, value),
+        None => {}
+    }
+
+    if let Some(n) = next {
+        if n < program.len() {
+            step.next.push((n, s));
+        }
+    }
+    step
+}
+
+/// Analyzes a program without running it.
+///
+/// Only the first `own` instructions are reported about, the rest being
+/// bundled library code.
+pub fn check(program: &[Instruction], own: usize) -> Vec<Diagnostic> {
+    let own = own.min(program.len());
+    let mut diagnostics = vec![];
+    let mut report = |instruction: usize, message: String| {
+        let diagnostic = Diagnostic {
+            instruction: instruction + 1,
+            message,
+        };
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    };
+
+    for (i, instr) in program.iter().enumerate().take(own) {
+        match jump_target(instr) {
+            Some(t) if t < 0 => report(i, format!("jumps to negative instruction {}", t)),
+            Some(t) if t == 0 || t as usize > program.len() => report(
+                i,
+                format!(
+                    "jumps to instruction {}, outside the program (1..={})",
+                    t,
+                    program.len()
+                ),
+            ),
+            _ => {}
+        }
+    }
+    if program.is_empty() {
+        return diagnostics;
+    }
+
+    // States before each instruction, by stack size, with how many times
+    // they grew
+    let mut states: Vec<BTreeMap<usize, (State, usize)>> = vec![BTreeMap::new(); program.len()];
+    states[0].insert(0, (State::entry(), 0));
+    let mut pending = vec![(0, 0)];
+    while let Some((i, size)) = pending.pop() {
+        let state = states[i][&size].0.clone();
+        for (j, next) in step(program, i, &state).next {
+            let size = next.stack.len();
+            match states[j].get_mut(&size) {
+                Some((old, grown)) => {
+                    if old.join(&next, *grown >= WIDENING_DELAY) {
+                        *grown += 1;
+                        pending.push((j, size));
+                    }
+                }
+                None => {
+                    states[j].insert(size, (next, 0));
+                    pending.push((j, size));
+                }
+            }
+        }
+    }
+
+    let mut lost = false;
+    for (i, by_size) in states.iter().enumerate() {
+        for (state, _) in by_size.values() {
+            let step = step(program, i, state);
+            lost |= step.lost;
+            if i < own {
+                for problem in step.problems {
+                    report(i, problem);
+                }
+            }
+        }
+    }
+
+    // Nothing can be told unreachable once a jump goes who knows where
+    if !lost {
+        let mut i = 0;
+        while i < own {
+            if !states[i].is_empty() {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < own && states[i].is_empty() {
+                i += 1;
+            }
+            report(
+                start,
+                if i - start == 1 {
+                    "unreachable instruction".to_owned()
+                } else {
+                    format!("unreachable instructions, up to {}", i)
+                },
+            );
+        }
+    }
+
+    diagnostics.sort_by_key(|d| d.instruction);
+    diagnostics
+}
@@ -0,0 +1,163 @@
+//! Line and branch coverage, behind `--coverage`.
+//!
+//! The machine counts how many times each instruction ran and, for `jmp`,
+//! how many times it jumped and fell through. Counts are mapped back to the
+//! source lines of the program and written as an lcov tracefile, adding up
+//! with the counts already in the file so that runs can be merged.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Counts gathered while running a program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    /// Number of times each instruction ran.
+    pub hits: Vec<u64>,
+    /// Number of times each instruction jumped and fell through, for `jmp`.
+    pub branches: Vec<(u64, u64)>,
+}
+
+impl Coverage {
+    pub fn new(len: usize) -> Coverage {
+        Coverage {
+            hits: vec![0; len],
+            branches: vec![(0, 0); len],
+        }
+    }
+
+    /// Counts a step, `taken` telling for a `jmp` whether it jumped.
+    pub fn record(&mut self, index: usize, taken: Option<bool>) {
+        self.hits[index] += 1;
+        match taken {
+            Some(true) => self.branches[index].0 += 1,
+            Some(false) => self.branches[index].1 += 1,
+            None => {}
+        }
+    }
+}
+
+/// Coverage of a source file, as stored in a tracefile.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Record {
+    /// Number of times each line ran.
+    pub lines: BTreeMap<usize, u64>,
+    /// Number of times each branch was taken, by line, block and branch, or
+    /// `None` when its line never ran.
+    pub branches: BTreeMap<(usize, usize, usize), Option<u64>>,
+}
+
+impl Record {
+    /// Maps counts to source lines, given the line of each instruction as
+    /// returned by `parser::source_lines` and the conditional jumps. Lines
+    /// past `own` come from included libraries and are left out.
+    pub fn new(
+        coverage: &Coverage,
+        lines: &[(usize, String)],
+        jumps: &[bool],
+        own: usize,
+    ) -> Record {
+        let mut record = Record::default();
+        for (index, (line, _)) in lines.iter().enumerate() {
+            if *line > own {
+                continue;
+            }
+            let hits = coverage.hits[index];
+            *record.lines.entry(*line).or_insert(0) += hits;
+            if jumps[index] {
+                // Branch 0 is the jump, branch 1 falling through
+                let (taken, fallen) = coverage.branches[index];
+                let ran = |count: u64| if hits > 0 { Some(count) } else { None };
+                record.branches.insert((*line, 0, 0), ran(taken));
+                record.branches.insert((*line, 0, 1), ran(fallen));
+            }
+        }
+        record
+    }
+
+    /// Adds the counts of another record.
+    pub fn merge(&mut self, other: &Record) {
+        for (line, hits) in &other.lines {
+            *self.lines.entry(*line).or_insert(0) += hits;
+        }
+        for (branch, taken) in &other.branches {
+            let merged = match (self.branches.get(branch).copied().flatten(), taken) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(*b),
+            };
+            self.branches.insert(*branch, merged);
+        }
+    }
+}
+
+/// Parses an lcov tracefile into records by source file. Only line and
+/// branch counts are read, the summaries being computed again when written.
+pub fn parse(text: &str) -> Result<BTreeMap<String, Record>, String> {
+    let mut records: BTreeMap<String, Record> = BTreeMap::new();
+    let mut current: Option<(String, Record)> = None;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let invalid = || format!("line {}: invalid record {}", n + 1, line);
+        let number = |field: &str| field.parse::<u64>().map_err(|_| invalid());
+        if let Some(path) = line.strip_prefix("SF:") {
+            current = Some((path.to_owned(), Record::default()));
+        } else if line == "end_of_record" {
+            let (path, record) = current.take().ok_or_else(invalid)?;
+            records.entry(path).or_default().merge(&record);
+        } else if let Some(fields) = line.strip_prefix("DA:") {
+            let (_, record) = current.as_mut().ok_or_else(invalid)?;
+            let fields = fields.split(',').collect::<Vec<&str>>();
+            if fields.len() < 2 {
+                return Err(invalid());
+            }
+            let hits = number(fields[1])?;
+            *record.lines.entry(number(fields[0])? as usize).or_insert(0) += hits;
+        } else if let Some(fields) = line.strip_prefix("BRDA:") {
+            let (_, record) = current.as_mut().ok_or_else(invalid)?;
+            let fields = fields.split(',').collect::<Vec<&str>>();
+            if fields.len() != 4 {
+                return Err(invalid());
+            }
+            let branch = (
+                number(fields[0])? as usize,
+                number(fields[1])? as usize,
+                number(fields[2])? as usize,
+            );
+            let taken = match fields[3] {
+                "-" => None,
+                count => Some(number(count)?),
+            };
+            let mut single = Record::default();
+            single.branches.insert(branch, taken);
+            record.merge(&single);
+        }
+    }
+    match current {
+        Some(_) => Err("missing end_of_record".to_owned()),
+        None => Ok(records),
+    }
+}
+
+/// Prints records as an lcov tracefile.
+pub fn to_lcov(records: &BTreeMap<String, Record>) -> String {
+    let mut lcov = String::new();
+    for (path, record) in records {
+        let _ = writeln!(lcov, "TN:\nSF:{}", path);
+        for ((line, block, branch), taken) in &record.branches {
+            let taken = taken.map_or("-".to_owned(), |t| t.to_string());
+            let _ = writeln!(lcov, "BRDA:{},{},{},{}", line, block, branch, taken);
+        }
+        let hit = record
+            .branches
+            .values()
+            .filter(|taken| taken.unwrap_or(0) > 0)
+            .count();
+        let _ = writeln!(lcov, "BRF:{}\nBRH:{}", record.branches.len(), hit);
+        for (line, hits) in &record.lines {
+            let _ = writeln!(lcov, "DA:{},{}", line, hits);
+        }
+        let hit = record.lines.values().filter(|hits| **hits > 0).count();
+        let _ = writeln!(lcov, "LF:{}\nLH:{}", record.lines.len(), hit);
+        lcov.push_str("end_of_record\n");
+    }
+    lcov
+}
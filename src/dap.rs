@@ -0,0 +1,429 @@
+//! Debug Adapter Protocol server, behind `wlvm dap`.
+//!
+//! The adapter speaks DAP over stdio, so the program's own output is captured
+//! and forwarded as `output` events. There is a single thread. Its frames are
+//! the current instruction and then the `cal` instructions of the
+//! subroutines being run. Breakpoints and frames are mapped to source lines
+//! through `parser::source_lines`. Programs without source, such as linked
+//! images, use instruction numbers as line numbers.
+
+use crate::json::{read_message, write_message, Json};
+use crate::parser::{self, parse_code};
+use crate::vm::{Vm, VmError};
+use crate::{object, reg_name, Instruction::*, Register::*};
+use std::convert::TryFrom;
+use std::io;
+
+const REGISTERS_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 2;
+const THREAD: i64 = 1;
+
+/// Why a program is run.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+}
+
+#[derive(Default)]
+pub struct Adapter {
+    vm: Option<Vm>,
+    /// Path of the program, as given to `launch`.
+    path: String,
+    lines: Vec<(usize, String)>,
+    breakpoints: Vec<usize>,
+    stop_on_entry: bool,
+    seq: i64,
+    /// Messages to send, in order.
+    outgoing: Vec<Json>,
+}
+
+impl Adapter {
+    pub fn new() -> Adapter {
+        Adapter::default()
+    }
+
+    /// Takes the messages to send to the client.
+    pub fn messages(&mut self) -> Vec<Json> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    fn send(&mut self, mut members: Vec<(&str, Json)>) {
+        self.seq += 1;
+        members.push(("seq", self.seq.into()));
+        self.outgoing.push(Json::object(members));
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        let mut members = vec![("type", "event".into()), ("event", event.into())];
+        if body != Json::Null {
+            members.push(("body", body));
+        }
+        self.send(members);
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) {
+        let mut members = vec![
+            ("type", "response".into()),
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            (
+                "command",
+                request.get("command").cloned().unwrap_or(Json::Null),
+            ),
+            ("success", result.is_ok().into()),
+        ];
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => members.push(("body", body)),
+            Err(message) => members.push(("message", message.into())),
+        }
+        self.send(members);
+    }
+
+    /// Line shown for an instruction.
+    fn line(&self, index: usize) -> i64 {
+        match self.lines.get(index) {
+            Some((line, _)) => *line as i64,
+            None => index as i64 + 1,
+        }
+    }
+
+    /// Index of the first instruction at or after a line.
+    fn instruction(&self, line: usize) -> Option<usize> {
+        let vm = self.vm.as_ref()?;
+        let index = if self.lines.is_empty() {
+            line.checked_sub(1)
+        } else {
+            self.lines.iter().position(|(l, _)| *l >= line)
+        };
+        index.filter(|i| *i < vm.program.len())
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs a program")?;
+        let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let (program, labels) = if object::is_image(&bytes) {
+            object::read_image(&bytes).map_err(|e| format!("{}: {}", path, e))?
+        } else {
+            let source = String::from_utf8_lossy(&bytes);
+            self.lines = parser::source_lines(&source);
+            parse_code(&source, false)
+        };
+        let mut vm = Vm::new(program, labels);
+        vm.output = Some(vec![]);
+        self.vm = Some(vm);
+        self.path = path.to_owned();
+        self.stop_on_entry = arguments
+            .get("stopOnEntry")
+            .and_then(Json::as_bool)
+            .unwrap_or(false);
+        Ok(Json::Null)
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let requested = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[]);
+        self.breakpoints.clear();
+        let mut breakpoints = vec![];
+        for breakpoint in requested {
+            let line = breakpoint.get("line").and_then(Json::as_i64).unwrap_or(0);
+            let index = usize::try_from(line).ok().and_then(|l| self.instruction(l));
+            breakpoints.push(match index {
+                Some(index) => {
+                    self.breakpoints.push(index);
+                    Json::object(vec![
+                        ("verified", true.into()),
+                        ("line", self.line(index).into()),
+                    ])
+                }
+                None => Json::object(vec![
+                    ("verified", false.into()),
+                    ("line", line.into()),
+                    ("message", "No instruction at or after this line".into()),
+                ]),
+            });
+        }
+        Ok(Json::object(vec![("breakpoints", breakpoints.into())]))
+    }
+
+    fn source(&self) -> Json {
+        let name = std::path::Path::new(&self.path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Json::object(vec![
+            ("name", name.into()),
+            ("path", self.path.as_str().into()),
+        ])
+    }
+
+    fn stack_trace(&self) -> Result<Json, String> {
+        let vm = self.vm.as_ref().ok_or("the program is not launched")?;
+        // Innermost first: the current instruction, then the calls
+        let mut locations = vec![vm.ip()];
+        locations.extend(vm.calls.iter().rev());
+        let frames = locations
+            .iter()
+            .enumerate()
+            .map(|(depth, index)| {
+                let name = match vm.calls.len().checked_sub(depth + 1) {
+                    Some(call) => vm.subroutine(vm.calls[call]),
+                    None => "main".to_owned(),
+                };
+                Json::object(vec![
+                    ("id", (depth as i64).into()),
+                    ("name", name.into()),
+                    ("source", self.source()),
+                    ("line", self.line(*index).into()),
+                    ("column", 1.into()),
+                ])
+            })
+            .collect::<Vec<Json>>();
+        Ok(Json::object(vec![
+            ("totalFrames", (frames.len() as i64).into()),
+            ("stackFrames", frames.into()),
+        ]))
+    }
+
+    fn variables(&self, arguments: &Json) -> Result<Json, String> {
+        let vm = self.vm.as_ref().ok_or("the program is not launched")?;
+        let variable = |name: String, value: i32| {
+            Json::object(vec![
+                ("name", name.into()),
+                ("value", value.to_string().into()),
+                ("variablesReference", 0.into()),
+            ])
+        };
+        let variables = match arguments.get("variablesReference").and_then(Json::as_i64) {
+            Some(REGISTERS_REFERENCE) => vm
+                .registers
+                .iter()
+                .enumerate()
+                .map(|(i, value)| variable(reg_name(i as i32).to_lowercase(), *value))
+                .collect(),
+            Some(STACK_REFERENCE) => {
+                let sp = vm.registers[Sp as usize];
+                vm.stack
+                    .iter()
+                    .take((sp + 1).max(0) as usize)
+                    .enumerate()
+                    .map(|(slot, value)| variable(format!("[{}]", slot), *value))
+                    .collect()
+            }
+            _ => vec![],
+        };
+        Ok(Json::object(vec![("variables", Json::Array(variables))]))
+    }
+
+    /// Sends what the program printed since the last call.
+    fn flush_output(&mut self) {
+        let output = match self.vm.as_mut().and_then(|vm| vm.output.as_mut()) {
+            Some(output) if !output.is_empty() => std::mem::take(output),
+            _ => return,
+        };
+        self.event(
+            "output",
+            Json::object(vec![
+                ("category", "stdout".into()),
+                (
+                    "output",
+                    String::from_utf8_lossy(&output).into_owned().into(),
+                ),
+            ]),
+        );
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(description) = description {
+            body.push(("description", description.clone().into()));
+            body.push(("text", description.into()));
+        }
+        self.event("stopped", Json::object(body));
+    }
+
+    fn exited(&mut self, code: i64) {
+        self.event("exited", Json::object(vec![("exitCode", code.into())]));
+        self.event("terminated", Json::object(vec![]));
+    }
+
+    /// Runs the program, then reports why it stopped.
+    fn resume(&mut self, how: Resume) {
+        let vm = match self.vm.as_mut() {
+            Some(vm) => vm,
+            None => return,
+        };
+        if !vm.running {
+            return self.exited(1);
+        }
+        let depth = vm.calls.len();
+        let over = match vm.current() {
+            Some(Cal(_)) if how == Resume::Next => Some(vm.ip() + 1),
+            _ => None,
+        };
+        let result: Result<&str, VmError> = loop {
+            if let Err(e) = vm.step() {
+                break Err(e);
+            }
+            if !vm.running {
+                break Ok("halted");
+            }
+            let done = match how {
+                Resume::Continue => false,
+                Resume::StepIn => true,
+                Resume::Next => {
+                    over.is_none_or(|after| vm.calls.len() == depth && vm.ip() == after)
+                }
+                Resume::StepOut => vm.calls.len() < depth,
+            };
+            if done {
+                break Ok("step");
+            }
+            if self.breakpoints.contains(&vm.ip()) {
+                break Ok("breakpoint");
+            }
+        };
+        self.flush_output();
+        match result {
+            Ok("halted") => self.exited(0),
+            Ok(reason) => self.stopped(reason, None),
+            Err(e) => self.stopped("exception", Some(e.to_string())),
+        }
+    }
+
+    /// Handles a request, telling whether to keep serving.
+    pub fn handle(&mut self, request: &Json) -> bool {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+        let launched = self.vm.is_some();
+        match command {
+            "initialize" => {
+                let capabilities = Json::object(vec![
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsTerminateRequest", true.into()),
+                ]);
+                self.respond(request, Ok(capabilities));
+                self.event("initialized", Json::Null);
+            }
+            "launch" => {
+                let result = self.launch(&arguments);
+                self.respond(request, result);
+            }
+            "setBreakpoints" => {
+                let result = self.set_breakpoints(&arguments);
+                self.respond(request, result);
+            }
+            "setExceptionBreakpoints" => {
+                self.respond(request, Ok(Json::object(vec![])));
+            }
+            "configurationDone" => {
+                self.respond(request, Ok(Json::Null));
+                let at_breakpoint = match &self.vm {
+                    Some(vm) => self.breakpoints.contains(&vm.ip()),
+                    None => return true,
+                };
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else if at_breakpoint {
+                    self.stopped("breakpoint", None);
+                } else {
+                    self.resume(Resume::Continue);
+                }
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", THREAD.into()), ("name", "main".into())]);
+                self.respond(
+                    request,
+                    Ok(Json::object(vec![("threads", vec![thread].into())])),
+                );
+            }
+            "stackTrace" => {
+                let result = self.stack_trace();
+                self.respond(request, result);
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: i64| {
+                    Json::object(vec![
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", false.into()),
+                    ])
+                };
+                let scopes = vec![
+                    scope("Registers", REGISTERS_REFERENCE),
+                    scope("Stack", STACK_REFERENCE),
+                ];
+                self.respond(request, Ok(Json::object(vec![("scopes", scopes.into())])));
+            }
+            "variables" => {
+                let result = self.variables(&arguments);
+                self.respond(request, result);
+            }
+            "continue" | "next" | "stepIn" | "stepOut" if launched => {
+                let body = match command {
+                    "continue" => Json::object(vec![("allThreadsContinued", true.into())]),
+                    _ => Json::Null,
+                };
+                self.respond(request, Ok(body));
+                self.resume(match command {
+                    "continue" => Resume::Continue,
+                    "next" => Resume::Next,
+                    "stepIn" => Resume::StepIn,
+                    _ => Resume::StepOut,
+                });
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null));
+                if command == "terminate" {
+                    self.event("terminated", Json::object(vec![]));
+                }
+                return false;
+            }
+            _ if !launched && !command.is_empty() => {
+                self.respond(request, Err("the program is not launched".to_owned()))
+            }
+            other => self.respond(request, Err(format!("unsupported request {}", other))),
+        }
+        true
+    }
+}
+
+/// Serves a client over stdio until it disconnects.
+pub fn serve() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let stdout = io::stdout();
+    let mut writer = stdout.lock();
+    let mut adapter = Adapter::new();
+    while let Some(body) = read_message(&mut reader)? {
+        let request = match Json::parse(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("Error: invalid message: {}", e);
+                continue;
+            }
+        };
+        let keep_going = adapter.handle(&request);
+        for message in adapter.messages() {
+            write_message(&mut writer, &message)?;
+        }
+        if !keep_going {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,376 @@
+//! Interactive step debugger, behind `wlvm debug`.
+
+use crate::history::History;
+use crate::parser::register;
+use crate::vm::Vm;
+use crate::watch::{self, Watchpoint};
+use crate::Instruction::*;
+use crate::{reg_name, Instruction};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+break | b <line|:label>     Stops before the instruction of a line or label
+delete | d <line|:label>    Removes a breakpoint
+breakpoints                 Lists the breakpoints
+watch <target> [if <cond>]  Stops when a register or a stack slot ([n]) changes,
+                            optionally only if a condition such as a > 100 holds
+unwatch <target> [if ...]   Removes a watchpoint
+watchpoints                 Lists the watchpoints
+step | s [count]            Runs the next instruction(s), entering subroutines
+next | n                    Runs the next instruction, over subroutine calls
+continue | c                Runs until a breakpoint or the end of the program
+finish                      Runs until the current subroutine returns
+reverse-step | rs [count]   Goes back before the previous instruction(s)
+reverse-continue | rc       Goes back to a breakpoint, a watchpoint hit or as far
+                            as the history goes
+last-write | lw <target>    Tells which instruction last changed a register or a
+                            stack slot ([n])
+regs | r                    Prints the registers
+stack                       Prints the stack
+print | p <register>        Prints a register
+set <register> <value>      Changes the value of a register
+where | w                   Prints the current instruction and the calls
+limit [steps|gas <n>]       Stops the program after n more steps or once it has
+                            used n gas, or prints what is left
+quit | q                    Quits the debugger";
+
+/// Prints the stack up to `sp`, one slot per line.
+pub fn print_stack(vm: &Vm) {
+    match vm.used_stack() {
+        Ok([]) => println!("The stack is empty"),
+        Ok(slots) => {
+            for (slot, value) in slots.iter().enumerate() {
+                println!("[{}] {}", slot, value);
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+pub struct Debugger {
+    vm: Vm,
+    /// Source line of each instruction, as returned by `parser::source_lines`.
+    lines: Vec<(usize, String)>,
+    breakpoints: Vec<usize>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Debugger {
+    pub fn new(
+        program: Vec<Instruction>,
+        labels: BTreeMap<String, i32>,
+        lines: Vec<(usize, String)>,
+    ) -> Debugger {
+        let mut vm = Vm::new(program, labels);
+        vm.history = Some(History::default());
+        Debugger {
+            vm,
+            lines,
+            breakpoints: vec![],
+            watchpoints: vec![],
+        }
+    }
+
+    fn location(&self, index: usize) -> String {
+        match (self.lines.get(index), self.vm.program.get(index)) {
+            (Some((line, source)), _) => format!("line {}: {}", line, source.trim()),
+            (None, Some(instr)) => format!("instruction {}: {:?}", index + 1, instr),
+            (None, None) => format!("instruction {}", index + 1),
+        }
+    }
+
+    /// Resolves a line number, or a label, to an instruction index.
+    ///
+    /// Lines without an instruction stand for the next one that has one.
+    /// Programs without source lines are addressed by instruction number.
+    fn resolve(&self, spec: &str) -> Result<usize, String> {
+        if spec.starts_with(':') {
+            return match self.vm.labels.get(spec) {
+                Some(value) if *value >= 1 => Ok(*value as usize - 1),
+                _ => Err(format!("Unknown label {}", spec)),
+            };
+        }
+        let number = spec
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid line {}", spec))?;
+        let index = if self.lines.is_empty() {
+            number.checked_sub(1)
+        } else {
+            self.lines.iter().position(|(line, _)| *line >= number)
+        };
+        match index {
+            Some(i) if i < self.vm.program.len() => Ok(i),
+            _ => Err(format!("No instruction at or after line {}", spec)),
+        }
+    }
+
+    fn show_current(&self) {
+        if self.vm.running {
+            println!("=> {}", self.location(self.vm.ip()));
+        }
+    }
+
+    /// Runs until `stop` holds, a breakpoint is reached or the program ends.
+    fn resume(&mut self, stop: impl Fn(&Vm) -> bool) {
+        if !self.vm.running {
+            println!("The program is not running");
+            return;
+        }
+        loop {
+            let ip = self.vm.ip();
+            let before = watch::snapshot(&self.watchpoints, &self.vm);
+            if let Err(e) = self.vm.step() {
+                println!("Program stopped: {} at {}", e, self.location(ip));
+                return;
+            }
+            let hits = watch::hits(&self.watchpoints, &before, &self.vm);
+            for hit in &hits {
+                println!(
+                    "Watchpoint {}: {} -> {} at {}",
+                    hit.watchpoint,
+                    hit.old,
+                    hit.new,
+                    self.location(ip)
+                );
+            }
+            let watched = !hits.is_empty();
+            if !self.vm.running {
+                println!("Program halted after {} steps", self.vm.steps);
+                return;
+            }
+            if watched || stop(&self.vm) {
+                break;
+            }
+            if self.breakpoints.contains(&self.vm.ip()) {
+                println!("Breakpoint reached");
+                break;
+            }
+        }
+        self.show_current();
+    }
+
+    /// Goes back until `stop` holds, a breakpoint is reached or the start of
+    /// the history.
+    fn reverse(&mut self, stop: impl Fn(&Vm) -> bool) {
+        loop {
+            let before = watch::snapshot(&self.watchpoints, &self.vm);
+            if !self.vm.step_back() {
+                println!("Reached the start of the recorded history");
+                break;
+            }
+            let hits = watch::hits(&self.watchpoints, &before, &self.vm);
+            for hit in &hits {
+                println!(
+                    "Watchpoint {}: {} -> {} undoing {}",
+                    hit.watchpoint,
+                    hit.old,
+                    hit.new,
+                    self.location(self.vm.ip())
+                );
+            }
+            if !hits.is_empty() || stop(&self.vm) {
+                break;
+            }
+            if self.breakpoints.contains(&self.vm.ip()) {
+                println!("Breakpoint reached");
+                break;
+            }
+        }
+        self.show_current();
+    }
+
+    fn last_write(&self, spec: &str) {
+        let target = match Watchpoint::parse(spec) {
+            Ok(w) => w.target,
+            Err(e) => return println!("{}", e),
+        };
+        let history = self.vm.history.as_ref().unwrap();
+        match history.last_write(target) {
+            Some(write) if write.delta.edit => println!(
+                "{} was last changed from {} to {} by set, after step {}",
+                spec, write.old, write.new, write.delta.step
+            ),
+            Some(write) => println!(
+                "{} was last changed from {} to {} by step {}, {}",
+                spec,
+                write.old,
+                write.new,
+                write.delta.step + 1,
+                self.location(write.delta.index)
+            ),
+            None => println!(
+                "{} was not changed since step {}",
+                spec,
+                history.start().unwrap_or(0) + 1
+            ),
+        }
+    }
+
+    fn print_registers(&self) {
+        for (i, value) in self.vm.registers.iter().enumerate() {
+            println!("{} = {}", reg_name(i as i32).to_lowercase(), value);
+        }
+    }
+
+    fn print_limits(&self) {
+        let limits = [
+            ("steps", self.vm.remaining_steps().map(|n| n.to_string())),
+            ("gas", self.vm.remaining_gas().map(|n| n.to_string())),
+            (
+                "time",
+                self.vm
+                    .remaining_time()
+                    .map(|t| format!("{} ms", t.as_millis())),
+            ),
+        ];
+        if limits.iter().all(|(_, left)| left.is_none()) {
+            println!("No limits");
+        }
+        for (limit, left) in &limits {
+            if let Some(left) = left {
+                println!("{} left: {}", limit, left);
+            }
+        }
+    }
+
+    /// Runs a debugger command, telling whether to keep going.
+    pub fn command(&mut self, line: &str) -> bool {
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        let argument = words.get(1).copied().unwrap_or("");
+        let rest = words.get(1..).unwrap_or(&[]).join(" ");
+        match words.first().copied().unwrap_or("") {
+            "" => {}
+            "break" | "b" => match self.resolve(argument) {
+                Ok(i) if self.breakpoints.contains(&i) => {
+                    println!("Breakpoint already set at {}", self.location(i))
+                }
+                Ok(i) => {
+                    self.breakpoints.push(i);
+                    println!("Breakpoint set at {}", self.location(i));
+                }
+                Err(e) => println!("{}", e),
+            },
+            "delete" | "d" => match self.resolve(argument) {
+                Ok(i) if self.breakpoints.contains(&i) => {
+                    self.breakpoints.retain(|b| *b != i);
+                    println!("Breakpoint removed from {}", self.location(i));
+                }
+                Ok(i) => println!("No breakpoint at {}", self.location(i)),
+                Err(e) => println!("{}", e),
+            },
+            "breakpoints" => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for i in &self.breakpoints {
+                    println!("{}", self.location(*i));
+                }
+            }
+            "watch" => match Watchpoint::parse(&rest) {
+                Ok(w) if self.watchpoints.contains(&w) => {
+                    println!("Watchpoint {} already set", w)
+                }
+                Ok(w) => {
+                    println!("Watchpoint {} set", w);
+                    self.watchpoints.push(w);
+                }
+                Err(e) => println!("{}", e),
+            },
+            "unwatch" => match Watchpoint::parse(&rest) {
+                Ok(w) if self.watchpoints.contains(&w) => {
+                    self.watchpoints.retain(|other| *other != w);
+                    println!("Watchpoint {} removed", w);
+                }
+                Ok(w) => println!("No watchpoint {}", w),
+                Err(e) => println!("{}", e),
+            },
+            "watchpoints" => {
+                if self.watchpoints.is_empty() {
+                    println!("No watchpoints");
+                }
+                for w in &self.watchpoints {
+                    println!("{}", w);
+                }
+            }
+            "step" | "s" => {
+                let count = argument.parse::<u64>().unwrap_or(1);
+                let target = self.vm.steps + count;
+                self.resume(|vm| vm.steps >= target);
+            }
+            "next" | "n" => match self.vm.current() {
+                Some(Cal(_)) if self.vm.running => {
+                    let depth = self.vm.calls.len();
+                    let after = self.vm.ip() + 1;
+                    self.resume(|vm| vm.calls.len() == depth && vm.ip() == after);
+                }
+                _ => self.resume(|_| true),
+            },
+            "continue" | "c" => self.resume(|_| false),
+            "finish" => {
+                let depth = self.vm.calls.len();
+                if depth == 0 {
+                    println!("Not in a subroutine");
+                } else {
+                    self.resume(|vm| vm.calls.len() < depth);
+                }
+            }
+            "reverse-step" | "rs" => {
+                let count = argument.parse::<u64>().unwrap_or(1);
+                let target = self.vm.steps.saturating_sub(count);
+                self.reverse(|vm| vm.steps <= target);
+            }
+            "reverse-continue" | "rc" => self.reverse(|_| false),
+            "last-write" | "lw" => self.last_write(&rest),
+            "regs" | "r" => self.print_registers(),
+            "stack" => print_stack(&self.vm),
+            "print" | "p" => match register(argument) {
+                Some(r) => println!("{} = {}", argument, self.vm.registers[r as usize]),
+                None => println!("Unknown register {}", argument),
+            },
+            "set" => match (register(argument), words.get(2).map(|v| v.parse::<i32>())) {
+                (Some(r), Some(Ok(value))) => self.vm.set_register(r, value),
+                (None, _) => println!("Unknown register {}", argument),
+                _ => println!("Usage: set <register> <value>"),
+            },
+            "where" | "w" => {
+                if self.vm.running {
+                    println!("=> {}", self.location(self.vm.ip()));
+                } else {
+                    println!("The program is not running");
+                }
+                for call in self.vm.calls.iter().rev() {
+                    println!("   called from {}", self.location(*call));
+                }
+            }
+            "limit" => match (argument, words.get(2).map(|v| v.parse::<u64>())) {
+                ("", _) => self.print_limits(),
+                ("steps", Some(Ok(n))) => self.vm.max_steps = Some(self.vm.steps + n),
+                ("gas", Some(Ok(n))) => self.vm.gas = Some(n),
+                _ => println!("Usage: limit [steps|gas <n>]"),
+            },
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return false,
+            other => println!("Unknown command {}, type help for a list", other),
+        }
+        true
+    }
+
+    /// Reads commands from stdin until `quit` or the end of the input.
+    pub fn run(&mut self) {
+        println!(
+            "{} instructions loaded, type help for a list of commands",
+            self.vm.program.len()
+        );
+        self.show_current();
+        let stdin = io::stdin();
+        loop {
+            print!("(wldb) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 || !self.command(&line) {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Differential execution, behind `wlvm diff`.
+//!
+//! Two machines, running two programs or the same program built in two ways,
+//! are stepped in lockstep and compared after every step: whether they are
+//! still running, what they printed, their registers and their stack. The
+//! first difference is reported with the last steps of both traces.
+
+use crate::trace::{Format, Tracer};
+use crate::vm::{Vm, VmError};
+use crate::{reg_name, REGISTERS};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Keeps the last lines written to it, sharing them with its clones.
+#[derive(Clone)]
+struct Recent {
+    lines: Rc<RefCell<VecDeque<String>>>,
+    /// Start of the line being written.
+    partial: Rc<RefCell<String>>,
+    keep: usize,
+}
+
+impl Write for Recent {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut partial = self.partial.borrow_mut();
+        partial.push_str(&String::from_utf8_lossy(buf));
+        while let Some(end) = partial.find('\n') {
+            let mut lines = self.lines.borrow_mut();
+            lines.push_back(partial[..end].to_owned());
+            if lines.len() > self.keep {
+                lines.pop_front();
+            }
+            partial.drain(..=end);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One of the runs being compared.
+pub struct Run {
+    pub vm: Vm,
+    /// The last steps traced.
+    recent: Recent,
+    /// The error the program stopped with.
+    pub error: Option<VmError>,
+}
+
+impl Run {
+    /// Prepares a machine to be compared, keeping the last `context` steps of
+    /// its trace. `lines` are the source lines of its instructions, if known.
+    pub fn new(mut vm: Vm, lines: Vec<(usize, String)>, context: usize) -> Run {
+        let recent = Recent {
+            lines: Rc::new(RefCell::new(VecDeque::new())),
+            partial: Rc::new(RefCell::new(String::new())),
+            keep: context,
+        };
+        vm.trace = Some(Tracer::new(Format::Text, Box::new(recent.clone()), lines));
+        vm.output = Some(vec![]);
+        Run {
+            vm,
+            recent,
+            error: None,
+        }
+    }
+
+    /// The last steps run, as traced.
+    pub fn context(&self) -> Vec<String> {
+        self.recent.lines.borrow().iter().cloned().collect()
+    }
+
+    fn step(&mut self) {
+        if let Err(e) = self.vm.step() {
+            self.error = Some(e);
+            // Limits leave the machine running
+            self.vm.running = false;
+        }
+    }
+
+    fn output(&self) -> &[u8] {
+        self.vm.output.as_deref().unwrap_or_default()
+    }
+}
+
+fn status(run: &Run) -> String {
+    match &run.error {
+        Some(e) => format!("stopped with \"{}\"", e),
+        None if run.vm.running => "still running".to_owned(),
+        None => "halted".to_owned(),
+    }
+}
+
+/// Tells how two runs differ after the same number of steps, if they do.
+pub fn difference(left: &Run, right: &Run) -> Option<String> {
+    if left.vm.running != right.vm.running || left.error != right.error {
+        return Some(format!(
+            "the left program {}, the right one {}",
+            status(left),
+            status(right)
+        ));
+    }
+    let (a, b) = (left.output(), right.output());
+    if a != b {
+        let common = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        return Some(format!(
+            "the left program printed {:?}, the right one {:?}",
+            String::from_utf8_lossy(&a[common..]),
+            String::from_utf8_lossy(&b[common..])
+        ));
+    }
+    let registers = REGISTERS
+        .iter()
+        .filter(|r| left.vm.registers[**r as usize] != right.vm.registers[**r as usize])
+        .map(|r| {
+            format!(
+                "{} is {} on the left, {} on the right",
+                reg_name(*r as i32).to_lowercase(),
+                left.vm.registers[*r as usize],
+                right.vm.registers[*r as usize]
+            )
+        })
+        .collect::<Vec<String>>();
+    if !registers.is_empty() {
+        return Some(registers.join(", "));
+    }
+    let slot = (0..left.vm.stack.len()).find(|s| left.vm.stack[*s] != right.vm.stack[*s])?;
+    Some(format!(
+        "stack slot [{}] is {} on the left, {} on the right",
+        slot, left.vm.stack[slot], right.vm.stack[slot]
+    ))
+}
+
+/// Steps both runs until they differ, returning the step they differ after
+/// and how, or until both stop.
+pub fn compare(left: &mut Run, right: &mut Run) -> Option<(u64, String)> {
+    let mut steps = 0;
+    while left.vm.running || right.vm.running {
+        if left.vm.running {
+            left.step();
+        }
+        if right.vm.running {
+            right.step();
+        }
+        steps += 1;
+        if let Some(difference) = difference(left, right) {
+            return Some((steps, difference));
+        }
+    }
+    None
+}
@@ -0,0 +1,435 @@
+//! Line editor for the REPL.
+//!
+//! The terminal is put in raw mode with `stty` while a line is read, so that
+//! keys come one at a time: the cursor moves with the arrows, Home, End,
+//! Ctrl-A and Ctrl-E, Up and Down walk the history, Ctrl-R searches it and
+//! Tab completes the word before the cursor. Lines are added to a history
+//! file, so that they are remembered across sessions.
+//!
+//! When stdin is not a terminal, or `stty` is not there, lines are read as
+//! they come, without editing nor history.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Lines kept in the history file.
+const HISTORY_SIZE: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    Tab,
+    /// Ctrl-K, removing the end of the line.
+    KillEnd,
+    /// Ctrl-U, removing the start of the line.
+    KillStart,
+    /// Ctrl-R, searching the history.
+    Search,
+    /// Ctrl-C, dropping the line.
+    Interrupt,
+    /// Ctrl-D, ending the input on an empty line.
+    Eof,
+    /// Escape, leaving a search.
+    Escape,
+    Other,
+}
+
+/// Reads a key, decoding escape sequences and UTF-8.
+pub fn read_key(input: &mut impl Read) -> io::Result<Key> {
+    let mut byte = [0];
+    let mut next = |input: &mut dyn Read| -> io::Result<u8> {
+        input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    };
+    Ok(match next(input)? {
+        b'\r' | b'\n' => Key::Enter,
+        127 | 8 => Key::Backspace,
+        b'\t' => Key::Tab,
+        1 => Key::Home,
+        5 => Key::End,
+        2 => Key::Left,
+        6 => Key::Right,
+        16 => Key::Up,
+        14 => Key::Down,
+        11 => Key::KillEnd,
+        21 => Key::KillStart,
+        18 => Key::Search,
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        7 => Key::Escape,
+        27 => match next(input)? {
+            b'[' | b'O' => match next(input)? {
+                b'A' => Key::Up,
+                b'B' => Key::Down,
+                b'C' => Key::Right,
+                b'D' => Key::Left,
+                b'H' => Key::Home,
+                b'F' => Key::End,
+                digit @ b'0'..=b'9' => {
+                    // Sequences such as ESC [ 3 ~ end with a tilde
+                    let mut last = next(input)?;
+                    while last.is_ascii_digit() || last == b';' {
+                        last = next(input)?;
+                    }
+                    match (digit, last) {
+                        (b'1', b'~') | (b'7', b'~') => Key::Home,
+                        (b'4', b'~') | (b'8', b'~') => Key::End,
+                        (b'3', b'~') => Key::Delete,
+                        _ => Key::Other,
+                    }
+                }
+                _ => Key::Other,
+            },
+            _ => Key::Escape,
+        },
+        first if first >= 0x80 => {
+            let len = match first {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                _ => 4,
+            };
+            let mut bytes = vec![first];
+            for _ in 1..len {
+                bytes.push(next(input)?);
+            }
+            match std::str::from_utf8(&bytes) {
+                Ok(s) => Key::Char(s.chars().next().unwrap()),
+                Err(_) => Key::Other,
+            }
+        }
+        c if c >= 0x20 => Key::Char(c as char),
+        _ => Key::Other,
+    })
+}
+
+/// What a key did to the line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    Continue,
+    /// The line was entered.
+    Accept(String),
+    /// The input ended.
+    Eof,
+    /// Completion found several candidates, to be listed.
+    List(Vec<String>),
+}
+
+/// A line being edited.
+#[derive(Clone, Debug, Default)]
+pub struct Edit {
+    pub buffer: Vec<char>,
+    pub cursor: usize,
+    /// Index of the history entry shown, when walking the history.
+    browsing: Option<usize>,
+    /// The line being edited before walking the history.
+    draft: Vec<char>,
+    /// The text searched for and the index of the entry found, when
+    /// searching the history.
+    search: Option<(String, Option<usize>)>,
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix = words[0].clone();
+    for word in &words[1..] {
+        while !word.starts_with(&prefix) {
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+impl Edit {
+    fn line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    fn set(&mut self, line: &str) {
+        self.buffer = line.chars().collect();
+        self.cursor = self.buffer.len();
+    }
+
+    /// Finds the latest history entry containing `query`, before `before`.
+    fn find(history: &[String], query: &str, before: usize) -> Option<usize> {
+        history[..before.min(history.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+
+    /// Handles a key. `complete` returns the words that may follow the text
+    /// before the cursor.
+    pub fn key(
+        &mut self,
+        key: Key,
+        history: &[String],
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> Step {
+        if let Some((query, found)) = self.search.take() {
+            let mut query = query;
+            let found = match key {
+                Key::Char(c) => {
+                    query.push(c);
+                    Edit::find(history, &query, found.map_or(history.len(), |i| i + 1))
+                }
+                Key::Backspace => {
+                    query.pop();
+                    Edit::find(history, &query, history.len())
+                }
+                Key::Search => {
+                    Edit::find(history, &query, found.unwrap_or(history.len())).or(found)
+                }
+                Key::Interrupt | Key::Escape => return Step::Continue,
+                Key::Enter => {
+                    if let Some(i) = found {
+                        self.set(&history[i]);
+                    }
+                    return Step::Accept(self.line());
+                }
+                // Other keys leave the search, keeping what was found
+                _ => {
+                    if let Some(i) = found {
+                        self.set(&history[i]);
+                    }
+                    return Step::Continue;
+                }
+            };
+            self.search = Some((query, found));
+            return Step::Continue;
+        }
+
+        match key {
+            Key::Char(c) => {
+                self.buffer.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter => return Step::Accept(self.line()),
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.buffer.remove(self.cursor);
+            }
+            Key::Eof if self.buffer.is_empty() => return Step::Eof,
+            // Ctrl-D deletes like Delete on a line that is not empty
+            Key::Delete | Key::Eof if self.cursor < self.buffer.len() => {
+                self.buffer.remove(self.cursor);
+            }
+            Key::Left if self.cursor > 0 => self.cursor -= 1,
+            Key::Right if self.cursor < self.buffer.len() => self.cursor += 1,
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.buffer.len(),
+            Key::KillEnd => self.buffer.truncate(self.cursor),
+            Key::KillStart => {
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Up if !history.is_empty() => {
+                let index = match self.browsing {
+                    None => {
+                        self.draft = self.buffer.clone();
+                        history.len() - 1
+                    }
+                    Some(i) => i.saturating_sub(1),
+                };
+                self.browsing = Some(index);
+                self.set(&history[index]);
+            }
+            Key::Down => match self.browsing {
+                Some(i) if i + 1 < history.len() => {
+                    self.browsing = Some(i + 1);
+                    self.set(&history[i + 1]);
+                }
+                Some(_) => {
+                    self.browsing = None;
+                    self.buffer = self.draft.clone();
+                    self.cursor = self.buffer.len();
+                }
+                None => {}
+            },
+            Key::Search => self.search = Some((String::new(), None)),
+            Key::Interrupt => {
+                self.buffer.clear();
+                self.cursor = 0;
+                self.browsing = None;
+            }
+            Key::Tab => return self.complete(complete),
+            _ => {}
+        }
+        Step::Continue
+    }
+
+    fn complete(&mut self, complete: &dyn Fn(&str) -> Vec<String>) -> Step {
+        let before = self.buffer[..self.cursor].iter().collect::<String>();
+        let start = before.rfind(' ').map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let mut candidates = complete(&before)
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .collect::<Vec<String>>();
+        candidates.sort();
+        candidates.dedup();
+        let insert = match candidates.len() {
+            0 => return Step::Continue,
+            1 => format!("{} ", &candidates[0][word.len()..]),
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.len() == word.len() {
+                    return Step::List(candidates);
+                }
+                prefix[word.len()..].to_owned()
+            }
+        };
+        for c in insert.chars() {
+            self.buffer.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+        Step::Continue
+    }
+
+    /// Draws the line over the current one of the terminal.
+    pub fn render(&self, prompt: &str, history: &[String]) -> String {
+        let (shown, line, cursor) = match &self.search {
+            Some((query, found)) => {
+                let line = found.map_or("", |i| history[i].as_str());
+                let shown = format!("(search)`{}': ", query);
+                (shown, line.to_owned(), line.chars().count())
+            }
+            None => (prompt.to_owned(), self.line(), self.cursor),
+        };
+        let mut output = format!("\r{}{}\x1b[K", shown, line);
+        let back = line.chars().count() - cursor;
+        if back > 0 {
+            output.push_str(&format!("\x1b[{}D", back));
+        }
+        output
+    }
+}
+
+/// Puts the terminal in raw mode until dropped.
+struct RawMode {
+    saved: String,
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let saved = stty(&["-g"])?;
+        // Output processing stays on, so that newlines still go to column 0
+        stty(&[
+            "-icanon", "-echo", "-isig", "-ixon", "-iexten", "min", "1", "time", "0",
+        ])?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+pub struct Editor {
+    pub history: Vec<String>,
+    /// Where the history is kept, if anywhere.
+    path: Option<PathBuf>,
+}
+
+impl Editor {
+    /// Starts an editor, loading the history from `path`.
+    pub fn new(path: Option<PathBuf>) -> Editor {
+        let mut history = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(str::to_owned).collect::<Vec<String>>())
+            .unwrap_or_default();
+        if history.len() > HISTORY_SIZE {
+            history.drain(..history.len() - HISTORY_SIZE);
+        }
+        Editor { history, path }
+    }
+
+    /// Adds a line to the history and to its file. Empty lines and repeats
+    /// of the last one are left out.
+    pub fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_owned());
+        if let Some(path) = &self.path {
+            let file = OpenOptions::new().create(true).append(true).open(path);
+            if let Ok(mut file) = file {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+
+    /// Reads a line, returning `None` at the end of the input.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        let raw = if io::stdin().is_terminal() {
+            RawMode::enable().ok()
+        } else {
+            None
+        };
+        let mut stdout = io::stdout();
+        if raw.is_none() {
+            print!("{}", prompt);
+            stdout.flush()?;
+            let mut line = String::new();
+            return match io::stdin().lock().read_line(&mut line)? {
+                0 => Ok(None),
+                _ => Ok(Some(line)),
+            };
+        }
+
+        let mut edit = Edit::default();
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        loop {
+            write!(stdout, "{}", edit.render(prompt, &self.history))?;
+            stdout.flush()?;
+            match edit.key(read_key(&mut input)?, &self.history, complete) {
+                Step::Continue => {}
+                Step::Accept(line) => {
+                    writeln!(stdout, "{}", edit.render(prompt, &self.history))?;
+                    self.remember(&line);
+                    return Ok(Some(line));
+                }
+                Step::Eof => {
+                    writeln!(stdout)?;
+                    return Ok(None);
+                }
+                Step::List(candidates) => writeln!(stdout, "\n{}", candidates.join("  "))?,
+            }
+        }
+    }
+}
+
+/// The history file, in the home directory.
+pub fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".wlvm_history"))
+}
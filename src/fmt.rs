@@ -0,0 +1,179 @@
+//! Source formatter, behind `wlvm fmt`.
+//!
+//! Sources are split into a syntax tree of lines that keeps their text, so
+//! that nothing is lost, comments included. Formatting reprints the tree with
+//! lowercase mnemonics and registers, one space between operands and comments
+//! aligned within each run of instructions.
+//!
+//! The parser ignores whatever follows a label on its line, so such lines are
+//! kept as written: moving that text to a line of its own would make it run.
+//!
+//! The parser splits lines on single spaces, so operands can't be padded:
+//! every mnemonic being three letters long, they line up anyway.
+
+use crate::parser::register;
+
+/// A source line, split into its parts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line<'a> {
+    /// The line as written, without its line break.
+    pub text: &'a str,
+    /// The line break ending the line, empty on the last one.
+    pub newline: &'a str,
+    pub label: Option<&'a str>,
+    /// The mnemonic or directive, then the operands.
+    pub words: Vec<&'a str>,
+    /// The comment ending the line, from its `;`.
+    pub comment: Option<&'a str>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tree<'a> {
+    pub lines: Vec<Line<'a>>,
+}
+
+impl<'a> Line<'a> {
+    fn parse(text: &'a str, newline: &'a str) -> Line<'a> {
+        let mut words = vec![];
+        let mut comment = None;
+        let mut rest = text.trim_start();
+        while !rest.is_empty() {
+            if rest.starts_with(';') {
+                comment = Some(rest.trim_end());
+                break;
+            }
+            // Quoted messages are kept whole, spaces and semicolons included
+            let end = match rest.strip_prefix('"').and_then(|quoted| quoted.find('"')) {
+                Some(end) => end + 2,
+                None => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+            };
+            words.push(&rest[..end]);
+            rest = rest[end..].trim_start();
+        }
+        let label = match words.first() {
+            Some(word) if word.starts_with(':') => Some(words.remove(0)),
+            _ => None,
+        };
+        Line {
+            text,
+            newline,
+            label,
+            words,
+            comment,
+        }
+    }
+
+    /// The instruction or directive, normalized.
+    fn code(&self) -> String {
+        let mut words = self.words.iter();
+        let mut code = match words.next() {
+            Some(first) => first.to_lowercase(),
+            None => return String::new(),
+        };
+        for word in words {
+            code.push(' ');
+            match register(&word.to_lowercase()) {
+                Some(_) => code.push_str(&word.to_lowercase()),
+                None => code.push_str(word),
+            }
+        }
+        code
+    }
+}
+
+impl<'a> Tree<'a> {
+    pub fn parse(source: &'a str) -> Tree<'a> {
+        let mut lines = vec![];
+        let mut rest = source;
+        while !rest.is_empty() {
+            let (line, newline, next) = match rest.find('\n') {
+                Some(end) if rest[..end].ends_with('\r') => {
+                    (&rest[..end - 1], &rest[end - 1..=end], &rest[end + 1..])
+                }
+                Some(end) => (&rest[..end], &rest[end..=end], &rest[end + 1..]),
+                None => (rest, "", ""),
+            };
+            lines.push(Line::parse(line, newline));
+            rest = next;
+        }
+        Tree { lines }
+    }
+
+    /// Prints the tree back as it was written.
+    pub fn source(&self) -> String {
+        self.lines
+            .iter()
+            .flat_map(|line| vec![line.text, line.newline])
+            .collect()
+    }
+
+    /// Prints the tree in the canonical style.
+    pub fn format(&self) -> String {
+        // Lines as (code, comment), labels followed by text being kept whole
+        let mut lines: Vec<(String, Option<&str>)> = vec![];
+        for line in &self.lines {
+            match (line.label, line.words.is_empty()) {
+                (Some(label), true) => lines.push((label.to_owned(), line.comment)),
+                (Some(_), false) => lines.push((line.text.to_owned(), None)),
+                (None, _) => lines.push((line.code(), line.comment)),
+            }
+        }
+
+        let mut output = String::new();
+        let mut blank = true;
+        let mut i = 0;
+        while i < lines.len() {
+            let (code, comment) = &lines[i];
+            if code.is_empty() && comment.is_none() {
+                // Runs of blank lines are collapsed, and removed at the start
+                if !blank {
+                    output.push('\n');
+                }
+                blank = true;
+                i += 1;
+                continue;
+            }
+            blank = false;
+            if code.is_empty() || code.starts_with(':') {
+                output.push_str(code);
+                match comment {
+                    Some(comment) if code.is_empty() => output.push_str(comment),
+                    Some(comment) => {
+                        output.push(' ');
+                        output.push_str(comment);
+                    }
+                    None => {}
+                }
+                output.push('\n');
+                i += 1;
+                continue;
+            }
+
+            // Comments are aligned over runs of instructions
+            let end = lines[i..]
+                .iter()
+                .position(|(code, _)| code.is_empty() || code.starts_with(':'))
+                .map_or(lines.len(), |n| i + n);
+            let width = lines[i..end]
+                .iter()
+                .filter(|(_, comment)| comment.is_some())
+                .map(|(code, _)| code.chars().count())
+                .max()
+                .unwrap_or(0);
+            for (code, comment) in &lines[i..end] {
+                match comment {
+                    Some(comment) => {
+                        output.push_str(&format!("{:width$} {}", code, comment, width = width))
+                    }
+                    None => output.push_str(code),
+                }
+                output.push('\n');
+            }
+            i = end;
+        }
+        while output.ends_with("\n\n") {
+            output.pop();
+        }
+        output
+    }
+}
@@ -0,0 +1,306 @@
+//! Random programs, behind `wlvm gen` and `wlvm fuzz`.
+//!
+//! Generated programs are valid source over the whole instruction set. They
+//! may be asked to terminate, in which case they only jump forward, never
+//! return and never write `ip`, and to only jump to instructions of the
+//! program, in which case their jumps go to labels. Only some of them read
+//! `ip` or use `dmp`, as the optimizers leave such programs untouched.
+//!
+//! The fuzz loop feeds generated programs to the parser, the formatter, the
+//! object format, the static analyzer, the optimizers and the interpreter,
+//! reporting panics and tools that disagree with each other.
+
+use crate::parser::{diagnostics, parse_code};
+use crate::vm::{Vm, VmError};
+use crate::{check, fmt, gas, object, optimizer, ssa, Instruction, Register::*, REGISTERS};
+use std::collections::{BTreeMap, BTreeSet};
+use std::panic;
+
+/// Steps a generated program may run in the fuzz loop.
+const FUZZ_STEPS: u64 = 10_000;
+
+/// Percentage of generated programs reading `ip` or using `dmp`.
+const OBSERVING: u64 = 20;
+
+/// SplitMix64, enough to pick instructions and reproducible from a seed.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `low..=high`.
+    pub fn range(&mut self, low: i64, high: i64) -> i64 {
+        low + (self.next() % (high - low + 1) as u64) as i64
+    }
+
+    /// Tells whether an event of the given percentage happens.
+    pub fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Number of instructions.
+    pub size: usize,
+    /// Only generates programs that stop.
+    pub terminating: bool,
+    /// Only jumps to instructions of the program, through labels.
+    pub in_range: bool,
+}
+
+fn integer(rng: &mut Rng) -> i64 {
+    match rng.range(0, 9) {
+        0 => [i32::MIN, i32::MAX, -1, 0, 254][rng.range(0, 4) as usize] as i64,
+        1 | 2 => rng.range(-1000, 1000),
+        _ => rng.range(-10, 300),
+    }
+}
+
+/// Generates a program from a seed.
+pub fn generate(seed: u64, options: &Options) -> String {
+    let mut rng = Rng::new(seed);
+    let size = options.size;
+    let observes = rng.chance(OBSERVING);
+    let register = |rng: &mut Rng| loop {
+        let r = REGISTERS[rng.range(0, REGISTERS.len() as i64 - 1) as usize];
+        if observes || r != Ip {
+            return crate::reg_name(r as i32).to_lowercase();
+        }
+    };
+    // Registers an instruction writes, `ip` being left out of terminating ones
+    let destination = |rng: &mut Rng| loop {
+        let r = register(rng);
+        if !options.terminating || r != "ip" {
+            return r;
+        }
+    };
+
+    let mut lines: Vec<String> = vec![];
+    // Instructions jumped to through labels, by index
+    let mut targets = BTreeSet::new();
+    for i in 0..size {
+        let mnemonic = loop {
+            let m = gas::MNEMONICS[rng.range(0, gas::MNEMONICS.len() as i64 - 1) as usize];
+            if !(options.terminating && m == "ret" || !observes && m == "dmp") {
+                break m;
+            }
+        };
+        let line = match mnemonic {
+            "psh" => format!("psh {}", integer(&mut rng)),
+            "pop" | "hlt" | "dmp" | "ret" => mnemonic.to_owned(),
+            "drg" | "prt" => format!("{} {}", mnemonic, register(&mut rng)),
+            "gto" | "jmp" | "cal" => {
+                // Jumps are human numbered, the instruction after the last
+                // one being the `hlt` ending every program
+                let low = if options.terminating { i + 1 } else { 0 };
+                if options.in_range {
+                    let target = rng.range(low as i64, size as i64) as usize;
+                    targets.insert(target);
+                    format!("{} :l{}", mnemonic, target + 1)
+                } else {
+                    let target = rng.range(low as i64 - 2, size as i64 + 3) + 1;
+                    format!("{} {}", mnemonic, target)
+                }
+            }
+            "ast" => format!("ast \"check {}\"", i + 1),
+            "sto" => format!("sto {} {}", register(&mut rng), register(&mut rng)),
+            "tee" | "tne" | "tll" | "tmm" | "tel" | "tem" => {
+                format!("{} {} {}", mnemonic, register(&mut rng), register(&mut rng))
+            }
+            _ => format!(
+                "{} {} {}",
+                mnemonic,
+                destination(&mut rng),
+                register(&mut rng)
+            ),
+        };
+        lines.push(line);
+    }
+
+    let mut source = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        if targets.contains(&i) {
+            source.push_str(&format!(":l{}\n", i + 1));
+        }
+        source.push_str(line);
+        if rng.chance(5) {
+            source.push_str(" ; comment");
+        }
+        source.push('\n');
+    }
+    if targets.contains(&size) {
+        source.push_str(&format!(":l{}\n", size + 1));
+    }
+    source
+}
+
+/// What a run ended with, as compared between builds of a program.
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    output: Vec<u8>,
+    error: Option<VmError>,
+    /// Registers but `ip`, when the program halted.
+    registers: Vec<i32>,
+    /// The stack up to `sp`, when the program halted.
+    stack: Vec<i32>,
+}
+
+/// Runs a program, returning what it ended with, unless it ran out of steps.
+fn run(program: Vec<Instruction>, labels: BTreeMap<String, i32>) -> Option<Outcome> {
+    let mut vm = Vm::new(program, labels);
+    vm.output = Some(vec![]);
+    vm.max_steps = Some(FUZZ_STEPS);
+    while vm.running {
+        match vm.step() {
+            Err(e) if e.is_limit() => return None,
+            Err(e) => {
+                return Some(Outcome {
+                    output: vm.output.take().unwrap(),
+                    error: Some(e),
+                    registers: vec![],
+                    stack: vec![],
+                })
+            }
+            Ok(()) => {}
+        }
+    }
+    let sp = vm.registers[Sp as usize];
+    let top = (sp as i64 + 1).clamp(0, vm.stack.len() as i64) as usize;
+    Some(Outcome {
+        output: vm.output.take().unwrap(),
+        error: None,
+        registers: REGISTERS
+            .iter()
+            .filter(|r| **r != Ip)
+            .map(|r| vm.registers[*r as usize])
+            .collect(),
+        stack: vm.stack[..top].to_vec(),
+    })
+}
+
+/// What checking a program found.
+pub struct Checked {
+    pub problems: Vec<String>,
+    /// Whether the program was run and compared with its optimized builds.
+    pub compared: bool,
+}
+
+/// Checks that the tools agree on a program, returning where they do not.
+pub fn check_program(source: &str) -> Checked {
+    let mut problems = vec![];
+    let errors = diagnostics(source);
+    if let Some(e) = errors.first() {
+        return Checked {
+            problems: vec![format!("line {} does not parse: {}", e.line, e.message)],
+            compared: false,
+        };
+    }
+    let (program, labels) = parse_code(source, false);
+
+    // Instructions print back to the source they are parsed from
+    let printed = program[..program.len() - 1]
+        .iter()
+        .map(|instr| format!("{}\n", instr))
+        .collect::<String>();
+    if parse_code(&printed, false).0 != program {
+        problems.push("printing the instructions and parsing them back changes them".to_owned());
+    }
+    let image = object::write_image(&program, &labels);
+    if object::read_image(&image) != Ok((program.clone(), labels.clone())) {
+        problems.push("writing an image and reading it back changes the program".to_owned());
+    }
+    let tree = fmt::Tree::parse(source);
+    if tree.source() != source {
+        problems.push("the formatter loses text".to_owned());
+    }
+    if parse_code(&tree.format(), false) != (program.clone(), labels.clone()) {
+        problems.push("formatting changes the program".to_owned());
+    }
+    check::check(&program, program.len());
+
+    // Builds of a program end the same way
+    let expected = match run(program.clone(), labels.clone()) {
+        Some(outcome) => outcome,
+        None => {
+            return Checked {
+                problems,
+                compared: false,
+            }
+        }
+    };
+    let builds = [
+        ("-O", optimizer::optimize(&program, &labels)),
+        ("--ssa", {
+            let (program, labels) = ssa::optimize(&program, &labels);
+            optimizer::optimize(&program, &labels)
+        }),
+    ];
+    for (flag, (program, labels)) in builds {
+        let outcome = run(program, labels);
+        if outcome.as_ref() != Some(&expected) {
+            problems.push(format!(
+                "the program ends differently with {}: {:?} instead of {:?}",
+                flag, outcome, expected
+            ));
+        }
+    }
+    Checked {
+        problems,
+        compared: true,
+    }
+}
+
+/// What checking generated programs found.
+pub struct Summary {
+    /// Seeds of the programs the tools panicked on or disagreed about, with
+    /// what went wrong.
+    pub failures: Vec<(u64, String)>,
+    /// Number of programs compared with their optimized builds.
+    pub compared: u64,
+}
+
+/// Generates and checks `runs` programs, from seed `seed` on.
+pub fn fuzz(seed: u64, runs: u64, options: &Options) -> Summary {
+    // Panics are reported with the seed instead of printed
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let mut summary = Summary {
+        failures: vec![],
+        compared: 0,
+    };
+    for seed in seed..seed.saturating_add(runs) {
+        let source = generate(seed, options);
+        match panic::catch_unwind(|| check_program(&source)) {
+            Ok(checked) => {
+                summary.compared += checked.compared as u64;
+                let failures = checked.problems.into_iter().map(|p| (seed, p));
+                summary.failures.extend(failures);
+            }
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                summary
+                    .failures
+                    .push((seed, format!("panicked: {}", message)));
+            }
+        }
+    }
+    panic::set_hook(hook);
+    summary
+}
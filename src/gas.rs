@@ -0,0 +1,71 @@
+//! Gas metering, behind `--gas`.
+//!
+//! Every instruction costs some gas, heavier ones more, and a program runs
+//! out of gas when the next instruction costs more than it has left.
+
+use crate::object::opcode;
+use crate::Instruction;
+
+/// Mnemonics, in the order of their opcodes.
+pub const MNEMONICS: [&str; 24] = [
+    "psh", "add", "mul", "div", "sub", "pop", "mov", "hlt", "drg", "dmp", "gto", "prt", "tee",
+    "tne", "tll", "tmm", "tel", "tem", "jmp", "cal", "ret", "lod", "sto", "ast",
+];
+
+/// The gas each instruction costs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Costs {
+    costs: [u64; 24],
+}
+
+impl Default for Costs {
+    fn default() -> Costs {
+        let mut costs = Costs { costs: [1; 24] };
+        for (mnemonic, cost) in &[
+            ("mul", 2),
+            ("div", 4),
+            ("cal", 2),
+            ("ret", 2),
+            ("lod", 2),
+            ("sto", 2),
+            ("dmp", 10),
+        ] {
+            costs.set(mnemonic, *cost).unwrap();
+        }
+        costs
+    }
+}
+
+impl Costs {
+    pub fn cost(&self, instr: &Instruction) -> u64 {
+        self.costs[opcode(instr) as usize]
+    }
+
+    /// Changes the cost of the instructions with the given mnemonic.
+    pub fn set(&mut self, mnemonic: &str, cost: u64) -> Result<(), String> {
+        match MNEMONICS.iter().position(|m| *m == mnemonic) {
+            Some(i) => {
+                self.costs[i] = cost;
+                Ok(())
+            }
+            None => Err(format!("unknown instruction {}", mnemonic)),
+        }
+    }
+
+    /// Changes a cost as given on the command line, as in `div=8`.
+    pub fn parse_assignment(&mut self, assignment: &str) -> Result<(), String> {
+        let (mnemonic, cost) = match assignment.find('=') {
+            Some(i) => (&assignment[..i], &assignment[i + 1..]),
+            None => {
+                return Err(format!(
+                    "expected <instruction>=<cost>, found {}",
+                    assignment
+                ))
+            }
+        };
+        let cost = cost
+            .parse::<u64>()
+            .map_err(|_| format!("invalid cost {}", cost))?;
+        self.set(&mnemonic.to_lowercase(), cost)
+    }
+}
@@ -0,0 +1,339 @@
+//! GDB remote serial protocol stub, behind `wlvm run --gdb <address>`.
+//!
+//! The program counter is `ip`, an instruction index, so breakpoints are set
+//! on instruction indexes (`break *4`). Memory is the stack, every slot being
+//! 4 little endian bytes: slot `n` is at address `4 * n`.
+
+use crate::vm::{Vm, VmError};
+use crate::{reg_name, REGISTERS};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Number of steps run between two checks for an interrupt from GDB.
+const POLL_STEPS: u64 = 4096;
+
+pub struct Stub {
+    vm: Vm,
+    breakpoints: Vec<usize>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// Parses `<address>,<length>`.
+fn range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    Some((number(parts.next()?)?, number(parts.next()?)?))
+}
+
+pub fn target_description() -> String {
+    let registers = REGISTERS
+        .iter()
+        .map(|r| {
+            let kind = if *r == crate::Register::Ip {
+                "code_ptr"
+            } else {
+                "int32"
+            };
+            format!(
+                "    <reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n",
+                reg_name(*r as i32).to_lowercase(),
+                kind,
+                *r as usize
+            )
+        })
+        .collect::<String>();
+    format!(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.wlvm.core\">\n{}  </feature>\n</target>\n",
+        registers
+    )
+}
+
+impl Stub {
+    pub fn new(vm: Vm) -> Stub {
+        Stub {
+            vm,
+            breakpoints: vec![],
+        }
+    }
+
+    fn register_bytes(&self) -> Vec<u8> {
+        self.vm
+            .registers
+            .iter()
+            .flat_map(|r| r.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn stop_reply(&self, result: Result<(), VmError>) -> String {
+        match result {
+            Err(VmError::DivisionByZero) => "X08".to_owned(),
+            Err(_) => "X0b".to_owned(),
+            Ok(()) if !self.vm.running => "W00".to_owned(),
+            Ok(()) => "S05".to_owned(),
+        }
+    }
+
+    /// Runs until a breakpoint, the end of the program or an interrupt
+    /// reported by `interrupted`.
+    fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+        if !self.vm.running {
+            return "W00".to_owned();
+        }
+        loop {
+            if let Err(e) = self.vm.step() {
+                return self.stop_reply(Err(e));
+            }
+            if !self.vm.running || step || self.breakpoints.contains(&self.vm.ip()) {
+                return self.stop_reply(Ok(()));
+            }
+            if self.vm.steps.is_multiple_of(POLL_STEPS) && interrupted() {
+                return "S02".to_owned();
+            }
+        }
+    }
+
+    fn read_memory(&self, address: usize, length: usize) -> Option<String> {
+        let bytes = self
+            .vm
+            .stack
+            .iter()
+            .flat_map(|slot| slot.to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
+        bytes.get(address..address.checked_add(length)?).map(hex)
+    }
+
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> Option<()> {
+        let mut bytes = self
+            .vm
+            .stack
+            .iter()
+            .flat_map(|slot| slot.to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
+        bytes
+            .get_mut(address..address.checked_add(data.len())?)?
+            .copy_from_slice(data);
+        for (slot, chunk) in self.vm.stack.iter_mut().zip(bytes.chunks(4)) {
+            *slot = i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Some(())
+    }
+
+    fn read_features(&self, annex: &str) -> String {
+        // qXfer:features:read:<annex>:<offset>,<length>
+        let mut parts = annex.rsplitn(2, ':');
+        let (range, name) = match (parts.next().and_then(range), parts.next()) {
+            (Some(range), Some(name)) => (range, name),
+            _ => return "E01".to_owned(),
+        };
+        if name != "target.xml" {
+            return "E00".to_owned();
+        }
+        let xml = target_description();
+        let (offset, length) = range;
+        if offset >= xml.len() {
+            return "l".to_owned();
+        }
+        let end = (offset + length).min(xml.len());
+        let more = if end < xml.len() { 'm' } else { 'l' };
+        format!("{}{}", more, &xml[offset..end])
+    }
+
+    /// Answers a packet, or returns `None` when GDB is done with the program.
+    pub fn handle(
+        &mut self,
+        packet: &str,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Option<String> {
+        if packet.is_empty() {
+            return Some(String::new());
+        }
+        let (command, rest) = packet.split_at(1);
+        let reply = match command {
+            "?" => "S05".to_owned(),
+            "g" => hex(&self.register_bytes()),
+            "G" => match unhex(rest) {
+                Some(bytes) if bytes.len() == 4 * REGISTERS.len() => {
+                    for (r, chunk) in self.vm.registers.iter_mut().zip(bytes.chunks(4)) {
+                        *r = i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                    }
+                    "OK".to_owned()
+                }
+                _ => "E01".to_owned(),
+            },
+            "p" => match number(rest).and_then(|n| self.vm.registers.get(n)) {
+                Some(value) => hex(&value.to_le_bytes()),
+                None => "E01".to_owned(),
+            },
+            "P" => {
+                let mut parts = rest.splitn(2, '=');
+                let n = parts.next().and_then(number);
+                let value = parts.next().and_then(unhex);
+                match (n, value) {
+                    (Some(n), Some(bytes)) if n < REGISTERS.len() && bytes.len() == 4 => {
+                        self.vm.registers[n] =
+                            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                        "OK".to_owned()
+                    }
+                    _ => "E01".to_owned(),
+                }
+            }
+            "m" => match range(rest).and_then(|(a, l)| self.read_memory(a, l)) {
+                Some(data) => data,
+                None => "E01".to_owned(),
+            },
+            "M" => {
+                let mut parts = rest.splitn(2, ':');
+                let target = parts.next().and_then(range);
+                let data = parts.next().and_then(unhex);
+                match (target, data) {
+                    (Some((address, length)), Some(data)) if data.len() == length => {
+                        match self.write_memory(address, &data) {
+                            Some(()) => "OK".to_owned(),
+                            None => "E01".to_owned(),
+                        }
+                    }
+                    _ => "E01".to_owned(),
+                }
+            }
+            "s" | "c" => {
+                if let Some(address) = number(rest) {
+                    self.vm.registers[crate::Register::Ip as usize] = address as i32;
+                }
+                self.resume(command == "s", interrupted)
+            }
+            "Z" | "z" => {
+                let fields = rest.split(',').collect::<Vec<&str>>();
+                match (fields.first(), fields.get(1).and_then(|a| number(a))) {
+                    (Some(&"0"), Some(address)) => {
+                        self.breakpoints.retain(|b| *b != address);
+                        if command == "Z" {
+                            self.breakpoints.push(address);
+                        }
+                        "OK".to_owned()
+                    }
+                    // Only software breakpoints are supported
+                    _ => String::new(),
+                }
+            }
+            "H" => "OK".to_owned(),
+            "k" | "D" => return None,
+            "q" => {
+                if rest.starts_with("Supported") {
+                    "PacketSize=4000;qXfer:features:read+".to_owned()
+                } else if let Some(annex) = rest.strip_prefix("Xfer:features:read:") {
+                    self.read_features(annex)
+                } else if rest == "Attached" {
+                    "1".to_owned()
+                } else if rest == "C" {
+                    "QC1".to_owned()
+                } else if rest == "fThreadInfo" {
+                    "m1".to_owned()
+                } else if rest == "sThreadInfo" {
+                    "l".to_owned()
+                } else {
+                    String::new()
+                }
+            }
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+}
+
+/// Reads the next packet, acknowledging it. Returns `None` at the end of the
+/// connection.
+fn read_packet(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        // Acks, nacks and interrupts received while stopped are skipped
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = vec![];
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'#' => break,
+                b'}' => {
+                    reader.read_exact(&mut byte)?;
+                    data.push(byte[0] ^ 0x20);
+                }
+                b => data.push(b),
+            }
+        }
+        let mut checksum = [0; 2];
+        reader.read_exact(&mut checksum)?;
+        let expected = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+        let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if expected == Some(actual) {
+            writer.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+        writer.write_all(b"-")?;
+    }
+}
+
+fn send(writer: &mut impl Write, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(writer, "${}#{:02x}", data, checksum)?;
+    writer.flush()
+}
+
+/// Tells whether GDB sent an interrupt (Ctrl-C) without blocking.
+fn interrupted(stream: &TcpStream) -> bool {
+    let mut byte = [0];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let interrupt = matches!(stream.peek(&mut byte), Ok(1) if byte[0] == 0x03);
+    if interrupt {
+        let _ = (&*stream).read(&mut byte);
+    }
+    let _ = stream.set_nonblocking(false);
+    interrupt
+}
+
+/// Waits for GDB to connect on `address`, and serves it until it detaches.
+pub fn serve(vm: Vm, address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+    let mut reader = stream.try_clone()?;
+    let mut writer = stream.try_clone()?;
+    let mut stub = Stub::new(vm);
+    while let Some(packet) = read_packet(&mut reader, &mut writer)? {
+        match stub.handle(&packet, &mut || interrupted(&stream)) {
+            Some(reply) => send(&mut writer, &reply)?,
+            None => {
+                send(&mut writer, "OK")?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
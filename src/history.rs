@@ -0,0 +1,214 @@
+//! Execution history, letting the debugger step backwards.
+//!
+//! Every step is recorded as a delta holding the values it wrote. Deltas are
+//! grouped in segments starting with a checkpoint of the whole machine: going
+//! back one step restores the checkpoint of the last segment and replays its
+//! deltas but the last one. Only the most recent segments are kept, so memory
+//! use stays bounded however long the program runs.
+
+use crate::vm::Vm;
+use crate::watch::Target;
+use crate::{NumOfRegisters, Register};
+use std::collections::VecDeque;
+
+/// Steps recorded after each checkpoint.
+const SEGMENT_STEPS: usize = 256;
+/// Segments kept, older ones being forgotten.
+const SEGMENTS: usize = 256;
+
+/// The state of a machine, minus its program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub registers: [i32; NumOfRegisters as usize],
+    pub stack: Vec<i32>,
+    pub running: bool,
+    pub calls: Vec<usize>,
+    pub steps: u64,
+}
+
+impl Checkpoint {
+    pub fn capture(vm: &Vm) -> Checkpoint {
+        Checkpoint {
+            registers: vm.registers,
+            stack: vm.stack.clone(),
+            running: vm.running,
+            calls: vm.calls.clone(),
+            steps: vm.steps,
+        }
+    }
+
+    pub fn restore(&self, vm: &mut Vm) {
+        vm.registers = self.registers;
+        vm.stack = self.stack.clone();
+        vm.running = self.running;
+        vm.calls = self.calls.clone();
+        vm.steps = self.steps;
+    }
+}
+
+/// How a step changed the call stack.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallChange {
+    Push(usize),
+    Pop,
+}
+
+/// What a step wrote.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delta {
+    /// Number of the step, counted from 0.
+    pub step: u64,
+    /// Index of the instruction run.
+    pub index: usize,
+    /// New values of the registers that changed, `ip` included.
+    pub registers: Vec<(Register, i32)>,
+    /// Stack slot written, with its new value.
+    pub slot: Option<(usize, i32)>,
+    pub call: Option<CallChange>,
+    pub halted: bool,
+    /// Made by the user between two steps, with `set`, rather than by a step.
+    pub edit: bool,
+}
+
+impl Delta {
+    fn replay(&self, vm: &mut Vm) {
+        for (reg, value) in &self.registers {
+            vm.registers[*reg as usize] = *value;
+        }
+        if self.edit {
+            return;
+        }
+        if let Some((slot, value)) = self.slot {
+            vm.stack[slot] = value;
+        }
+        match self.call {
+            Some(CallChange::Push(index)) => vm.calls.push(index),
+            Some(CallChange::Pop) => {
+                vm.calls.pop();
+            }
+            None => {}
+        }
+        vm.running = !self.halted;
+        vm.steps = self.step + 1;
+    }
+
+    /// Returns the value the step wrote to a target, if it did.
+    pub fn wrote(&self, target: Target) -> Option<i32> {
+        match target {
+            Target::Register(reg) => self
+                .registers
+                .iter()
+                .find(|(r, _)| *r == reg)
+                .map(|(_, value)| *value),
+            Target::Slot(slot) => match self.slot {
+                Some((s, value)) if s == slot => Some(value),
+                _ => None,
+            },
+        }
+    }
+}
+
+struct Segment {
+    checkpoint: Checkpoint,
+    deltas: Vec<Delta>,
+}
+
+/// The last write to a target.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Write<'a> {
+    pub delta: &'a Delta,
+    pub old: i32,
+    pub new: i32,
+}
+
+#[derive(Default)]
+pub struct History {
+    segments: VecDeque<Segment>,
+}
+
+impl History {
+    /// Tells whether the next step starts a new segment, and thus needs a
+    /// checkpoint of the machine before it.
+    pub fn wants_checkpoint(&self) -> bool {
+        self.segments
+            .back()
+            .is_none_or(|s| s.deltas.len() >= SEGMENT_STEPS)
+    }
+
+    pub fn start_segment(&mut self, checkpoint: Checkpoint) {
+        if self.segments.len() >= SEGMENTS {
+            self.segments.pop_front();
+        }
+        self.segments.push_back(Segment {
+            checkpoint,
+            deltas: vec![],
+        });
+    }
+
+    pub fn record(&mut self, delta: Delta) {
+        self.segments
+            .back_mut()
+            .expect("a checkpoint is taken before the first step")
+            .deltas
+            .push(delta);
+    }
+
+    /// Number of the oldest step that can be gone back to.
+    pub fn start(&self) -> Option<u64> {
+        self.segments.front().map(|s| s.checkpoint.steps)
+    }
+
+    /// Puts the machine back in the state it had before its last step,
+    /// telling whether there was one in the history. Edits made after that
+    /// step are undone with it.
+    pub fn undo(&mut self, vm: &mut Vm) -> bool {
+        if self.deltas().all(|d| d.edit) {
+            return false;
+        }
+        loop {
+            let segment = self.segments.back_mut().unwrap();
+            let undone = match segment.deltas.pop() {
+                Some(delta) => delta,
+                None => {
+                    self.segments.pop_back();
+                    continue;
+                }
+            };
+            segment.checkpoint.restore(vm);
+            for delta in &segment.deltas {
+                delta.replay(vm);
+            }
+            if segment.deltas.is_empty() {
+                self.segments.pop_back();
+            }
+            if !undone.edit {
+                return true;
+            }
+        }
+    }
+
+    fn deltas(&self) -> impl DoubleEndedIterator<Item = &Delta> {
+        self.segments.iter().flat_map(|s| s.deltas.iter())
+    }
+
+    /// Returns the last recorded step that wrote to a target, with the value
+    /// the target had before it.
+    pub fn last_write(&self, target: Target) -> Option<Write<'_>> {
+        let mut writes = self
+            .deltas()
+            .rev()
+            .filter_map(|d| d.wrote(target).map(|value| (d, value)));
+        let (delta, new) = writes.next()?;
+        let old = match writes.next() {
+            Some((_, value)) => value,
+            None => {
+                let first = &self.segments.front()?.checkpoint;
+                match target {
+                    Target::Register(reg) => first.registers[reg as usize],
+                    Target::Slot(slot) => first.stack[slot],
+                }
+            }
+        };
+        Some(Write { delta, old, new })
+    }
+}
@@ -0,0 +1,571 @@
+//! Language server for `.vm` source files, behind `wlvm lsp`.
+//!
+//! Documents are synchronized in full on every change, and reparsed to
+//! publish the parser's errors as diagnostics. Labels are found by name in
+//! the document: a line starting with `:label` defines it, any other
+//! occurrence refers to it. Positions are counted in UTF-16 code units, as
+//! the protocol wants.
+
+use crate::json::{read_message, write_message, Json};
+use crate::parser;
+use std::collections::BTreeMap;
+use std::io;
+
+/// Mnemonics and directives, with their syntax and what they do.
+const MNEMONICS: &[(&str, &str, &str)] = &[
+    ("psh", "psh <integer>", "Pushes an integer onto the stack"),
+    ("pop", "pop", "Pops the stack"),
+    (
+        "add",
+        "add <register_a> <register_b>",
+        "Adds the content of register_b to register_a",
+    ),
+    (
+        "sub",
+        "sub <register_a> <register_b>",
+        "Substracts the content of register_b to register_a",
+    ),
+    (
+        "mul",
+        "mul <register_a> <register_b>",
+        "Multiplies the content of register_b to register_a",
+    ),
+    (
+        "div",
+        "div <register_a> <register_b>",
+        "Divides the content of register_a by register_b",
+    ),
+    (
+        "mov",
+        "mov <register_a> <register_b>",
+        "Copies content of register_b in register_a",
+    ),
+    (
+        "jmp",
+        "jmp <label|instruction>",
+        "Jumps to the instruction if the eq register is true",
+    ),
+    ("gto", "gto <label|instruction>", "Jumps to the instruction"),
+    (
+        "lod",
+        "lod <register_a> <register_b>",
+        "Loads the stack slot addressed by register_b in register_a",
+    ),
+    (
+        "sto",
+        "sto <register_a> <register_b>",
+        "Stores register_b in the stack slot addressed by register_a",
+    ),
+    (
+        "cal",
+        "cal <label|instruction>",
+        "Pushes the return address onto the stack and jumps to the instruction",
+    ),
+    (
+        "ret",
+        "ret",
+        "Pops the return address and jumps back after the matching `cal`",
+    ),
+    (
+        "tee",
+        "tee <register_a> <register_b>",
+        "Tests if register_a == register_b",
+    ),
+    (
+        "tne",
+        "tne <register_a> <register_b>",
+        "Tests if register_a != register_b",
+    ),
+    (
+        "tll",
+        "tll <register_a> <register_b>",
+        "Tests if register_a < register_b",
+    ),
+    (
+        "tmm",
+        "tmm <register_a> <register_b>",
+        "Tests if register_a > register_b",
+    ),
+    (
+        "tel",
+        "tel <register_a> <register_b>",
+        "Tests if register_a <= register_b",
+    ),
+    (
+        "tem",
+        "tem <register_a> <register_b>",
+        "Tests if register_a >= register_b",
+    ),
+    ("dmp", "dmp", "Dumps the stack and the registers"),
+    (
+        "drg",
+        "drg <register>",
+        "Prints the content of the specified register",
+    ),
+    (
+        "prt",
+        "prt <register>",
+        "Prints the character corresponding to register value",
+    ),
+    ("hlt", "hlt", "Stops the program"),
+    (
+        "ast",
+        "ast \"<message>\"",
+        "Stops the program with the message if eq is false",
+    ),
+    (
+        ".export",
+        ".export <label>",
+        "Makes the label visible to other modules when linking",
+    ),
+    (
+        ".include",
+        ".include <std/library.vm>",
+        "Makes the routines of a bundled library available",
+    ),
+];
+
+const REGISTERS: &[(&str, &str)] = &[
+    ("a", "Multi purpose register"),
+    ("b", "Multi purpose register"),
+    ("c", "Multi purpose register"),
+    ("d", "Multi purpose register"),
+    ("e", "Multi purpose register"),
+    ("f", "Multi purpose register"),
+    ("ip", "The instruction pointer"),
+    ("sp", "The stack pointer"),
+    ("st", "The stack top value"),
+    ("eq", "The result of the last test performed"),
+];
+
+/// Instructions whose operand is a label.
+const JUMPS: &[&str] = &["jmp", "gto", "cal", ".export"];
+
+// Completion item kinds
+const KEYWORD: i64 = 14;
+const VARIABLE: i64 = 6;
+const REFERENCE: i64 = 18;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// A word of a line, with the columns it spans.
+struct Token<'a> {
+    text: &'a str,
+    start: usize,
+    end: usize,
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Splits a line into words, leaving out comments.
+fn tokens(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut offset = 0;
+    for word in line.trim_end_matches('\r').split(' ') {
+        if word.starts_with(';') {
+            break;
+        }
+        if !word.is_empty() {
+            let start = utf16_len(&line[..offset]);
+            tokens.push(Token {
+                text: word,
+                start,
+                end: start + utf16_len(word),
+            });
+        }
+        offset += word.len() + 1;
+    }
+    tokens
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    let position = |character: usize| {
+        Json::object(vec![
+            ("line", (line as i64).into()),
+            ("character", (character as i64).into()),
+        ])
+    };
+    Json::object(vec![("start", position(start)), ("end", position(end))])
+}
+
+fn location(uri: &str, line: usize, token: &Token<'_>) -> Json {
+    Json::object(vec![
+        ("uri", uri.into()),
+        ("range", range(line, token.start, token.end)),
+    ])
+}
+
+fn markdown(value: String) -> Json {
+    Json::object(vec![(
+        "contents",
+        Json::object(vec![("kind", "markdown".into()), ("value", value.into())]),
+    )])
+}
+
+/// The document and position a request is about.
+fn position(params: &Json) -> Option<(&str, usize, usize)> {
+    let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+    let position = params.get("position")?;
+    let line = position.get("line")?.as_i64()?;
+    let character = position.get("character")?.as_i64()?;
+    Some((uri, line as usize, character as usize))
+}
+
+#[derive(Default)]
+pub struct Server {
+    documents: BTreeMap<String, String>,
+    /// Messages to send, in order.
+    outgoing: Vec<Json>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Takes the messages to send to the client.
+    pub fn messages(&mut self) -> Vec<Json> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    fn notify(&mut self, method: &str, params: Json) {
+        self.outgoing.push(Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]));
+    }
+
+    fn respond(&mut self, id: Json, result: Result<Json, (i64, String)>) {
+        let mut members = vec![("jsonrpc", "2.0".into()), ("id", id)];
+        match result {
+            Ok(result) => members.push(("result", result)),
+            Err((code, message)) => members.push((
+                "error",
+                Json::object(vec![("code", code.into()), ("message", message.into())]),
+            )),
+        }
+        self.outgoing.push(Json::object(members));
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let text = self.documents.get(uri).map(String::as_str).unwrap_or("");
+        let lines = text.split('\n').collect::<Vec<&str>>();
+        let diagnostics = parser::diagnostics(text)
+            .iter()
+            // Errors in included libraries are past the end of the document
+            .filter(|e| e.line >= 1 && e.line <= lines.len())
+            .map(|e| {
+                let line = lines[e.line - 1].trim_end();
+                let start = utf16_len(line) - utf16_len(line.trim_start());
+                Json::object(vec![
+                    ("range", range(e.line - 1, start, utf16_len(line))),
+                    ("severity", 1.into()),
+                    ("source", "wlvm".into()),
+                    ("message", e.message.as_str().into()),
+                ])
+            })
+            .collect::<Vec<Json>>();
+        self.notify(
+            "textDocument/publishDiagnostics",
+            Json::object(vec![
+                ("uri", uri.into()),
+                ("diagnostics", diagnostics.into()),
+            ]),
+        );
+    }
+
+    /// Returns the line and the word under a position.
+    fn word_at(&self, uri: &str, line: usize, character: usize) -> Option<(Vec<Token<'_>>, usize)> {
+        let text = self.documents.get(uri)?.split('\n').nth(line)?;
+        let tokens = tokens(text);
+        let index = tokens
+            .iter()
+            .position(|t| t.start <= character && character <= t.end)?;
+        Some((tokens, index))
+    }
+
+    /// Returns every occurrence of a label, its definition first.
+    fn occurrences(&self, uri: &str, label: &str) -> Vec<(usize, Token<'_>, bool)> {
+        let text = match self.documents.get(uri) {
+            Some(text) => text,
+            None => return vec![],
+        };
+        let mut occurrences = vec![];
+        for (number, line) in text.split('\n').enumerate() {
+            for (i, token) in tokens(line).into_iter().enumerate() {
+                if token.text == label {
+                    let definition = i == 0 && line.starts_with(':');
+                    occurrences.push((number, token, definition));
+                }
+            }
+        }
+        occurrences.sort_by_key(|(_, _, definition)| !definition);
+        occurrences
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let (uri, line, character) = match position(params) {
+            Some(position) => position,
+            None => return Json::Null,
+        };
+        let (tokens, index) = match self.word_at(uri, line, character) {
+            Some(word) => word,
+            None => return Json::Null,
+        };
+        let word = tokens[index].text;
+        if index == 0 {
+            if let Some((_, syntax, doc)) = MNEMONICS.iter().find(|(m, _, _)| *m == word) {
+                return markdown(format!("```\n{}\n```\n{}", syntax, doc));
+            }
+        }
+        if let Some((name, doc)) = REGISTERS.iter().find(|(r, _)| *r == word) {
+            return markdown(format!("Register `{}`: {}", name, doc));
+        }
+        if word.starts_with(':') {
+            return match self.occurrences(uri, word).first() {
+                Some((number, _, true)) => {
+                    markdown(format!("Label `{}`, defined on line {}", word, number + 1))
+                }
+                _ => markdown(format!("Label `{}`, not defined in this file", word)),
+            };
+        }
+        Json::Null
+    }
+
+    /// Returns the label under a position, if any.
+    fn label_at(&self, params: &Json) -> Option<(String, String)> {
+        let (uri, line, character) = position(params)?;
+        let (tokens, index) = self.word_at(uri, line, character)?;
+        let word = tokens[index].text;
+        if word.starts_with(':') {
+            Some((uri.to_owned(), word.to_owned()))
+        } else {
+            None
+        }
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let (uri, label) = match self.label_at(params) {
+            Some(label) => label,
+            None => return Json::Null,
+        };
+        match self.occurrences(&uri, &label).first() {
+            Some((line, token, true)) => location(&uri, *line, token),
+            _ => Json::Null,
+        }
+    }
+
+    fn references(&self, params: &Json) -> Json {
+        let (uri, label) = match self.label_at(params) {
+            Some(label) => label,
+            None => return Json::Null,
+        };
+        let declaration = params
+            .get("context")
+            .and_then(|c| c.get("includeDeclaration"))
+            .and_then(Json::as_bool)
+            .unwrap_or(true);
+        self.occurrences(&uri, &label)
+            .iter()
+            .filter(|(_, _, definition)| declaration || !definition)
+            .map(|(line, token, _)| location(&uri, *line, token))
+            .collect::<Vec<Json>>()
+            .into()
+    }
+
+    fn rename(&self, params: &Json) -> Result<Json, (i64, String)> {
+        let (uri, label) = self
+            .label_at(params)
+            .ok_or((INVALID_PARAMS, "Only labels can be renamed".to_owned()))?;
+        let name = params.get("newName").and_then(Json::as_str).unwrap_or("");
+        let name = if name.starts_with(':') {
+            name.to_owned()
+        } else {
+            format!(":{}", name)
+        };
+        if name.len() < 2 || name.contains(char::is_whitespace) {
+            return Err((INVALID_PARAMS, format!("Invalid label {}", name)));
+        }
+        let edits = self
+            .occurrences(&uri, &label)
+            .iter()
+            .map(|(line, token, _)| {
+                Json::object(vec![
+                    ("range", range(*line, token.start, token.end)),
+                    ("newText", name.as_str().into()),
+                ])
+            })
+            .collect::<Vec<Json>>();
+        let mut changes = BTreeMap::new();
+        changes.insert(uri, Json::Array(edits));
+        Ok(Json::object(vec![("changes", Json::Object(changes))]))
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let (uri, line, character) = match position(params) {
+            Some(position) => position,
+            None => return Json::Null,
+        };
+        let text = self
+            .documents
+            .get(uri)
+            .and_then(|text| text.split('\n').nth(line))
+            .unwrap_or("");
+        let before = text
+            .char_indices()
+            .scan(0, |units, (offset, c)| {
+                *units += c.len_utf16();
+                Some((offset + c.len_utf8(), *units))
+            })
+            .take_while(|(_, units)| *units <= character)
+            .last()
+            .map(|(offset, _)| &text[..offset])
+            .unwrap_or("");
+        let item = |label: &str, kind: i64, detail: &str| {
+            Json::object(vec![
+                ("label", label.into()),
+                ("kind", kind.into()),
+                ("detail", detail.into()),
+            ])
+        };
+        let words = before.split_whitespace().collect::<Vec<&str>>();
+        let typing_first = words.is_empty() || (words.len() == 1 && !before.ends_with(' '));
+        let items = if typing_first {
+            MNEMONICS
+                .iter()
+                .map(|(m, syntax, _)| item(m, KEYWORD, syntax))
+                .collect()
+        } else if JUMPS.contains(&words[0]) {
+            self.documents[uri]
+                .split('\n')
+                .filter(|line| line.starts_with(':'))
+                .filter_map(|line| line.split(' ').next())
+                .map(|label| item(label, REFERENCE, "label"))
+                .collect()
+        } else {
+            REGISTERS
+                .iter()
+                .map(|(r, doc)| item(r, VARIABLE, doc))
+                .collect()
+        };
+        Json::Array(items)
+    }
+
+    /// Handles a message, telling whether to keep serving.
+    pub fn handle(&mut self, message: &Json) -> bool {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or("")
+            .to_owned();
+        let result = match method {
+            "initialize" => Ok(Json::object(vec![
+                (
+                    "capabilities",
+                    Json::object(vec![
+                        ("textDocumentSync", 1.into()),
+                        ("hoverProvider", true.into()),
+                        ("definitionProvider", true.into()),
+                        ("referencesProvider", true.into()),
+                        ("renameProvider", true.into()),
+                        (
+                            "completionProvider",
+                            Json::object(vec![(
+                                "triggerCharacters",
+                                vec![" ".into(), ":".into()].into(),
+                            )]),
+                        ),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object(vec![
+                        ("name", "wlvm".into()),
+                        ("version", env!("CARGO_PKG_VERSION").into()),
+                    ]),
+                ),
+            ])),
+            "textDocument/didOpen" => {
+                let text = params
+                    .get("textDocument")
+                    .and_then(|d| d.get("text"))
+                    .and_then(Json::as_str)
+                    .unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_owned());
+                self.publish_diagnostics(&uri);
+                return true;
+            }
+            "textDocument/didChange" => {
+                // Changes hold the whole document, as asked at initialization
+                let text = params
+                    .get("contentChanges")
+                    .and_then(Json::as_array)
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change.get("text"))
+                    .and_then(Json::as_str);
+                if let Some(text) = text {
+                    self.documents.insert(uri.clone(), text.to_owned());
+                    self.publish_diagnostics(&uri);
+                }
+                return true;
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.notify(
+                    "textDocument/publishDiagnostics",
+                    Json::object(vec![
+                        ("uri", uri.as_str().into()),
+                        ("diagnostics", Json::Array(vec![])),
+                    ]),
+                );
+                return true;
+            }
+            "textDocument/hover" => Ok(self.hover(&params)),
+            "textDocument/definition" => Ok(self.definition(&params)),
+            "textDocument/references" => Ok(self.references(&params)),
+            "textDocument/rename" => self.rename(&params),
+            "textDocument/completion" => Ok(self.completion(&params)),
+            "shutdown" => Ok(Json::Null),
+            "exit" => return false,
+            other => Err((METHOD_NOT_FOUND, format!("unsupported method {}", other))),
+        };
+        // Notifications have no id and get no response
+        if let Some(id) = message.get("id") {
+            self.respond(id.clone(), result);
+        }
+        true
+    }
+}
+
+/// Serves a client over stdio until it exits.
+pub fn serve() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let stdout = io::stdout();
+    let mut writer = stdout.lock();
+    let mut server = Server::new();
+    while let Some(body) = read_message(&mut reader)? {
+        let message = match Json::parse(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Error: invalid message: {}", e);
+                continue;
+            }
+        };
+        let keep_going = server.handle(&message);
+        for message in server.messages() {
+            write_message(&mut writer, &message)?;
+        }
+        if !keep_going {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -3,14 +3,43 @@ use crate::Register::*;
 use std::collections::BTreeMap;
 use std::io;
 use std::io::Write;
+use std::rc::Rc;
 
+mod cfg;
+mod check;
+mod coverage;
+mod dap;
+mod debugger;
+mod diff;
+mod editor;
+mod fmt;
+mod fuzz;
+mod gas;
+mod gdb;
+mod history;
+mod json;
+mod lsp;
+mod object;
+mod optimizer;
 mod parser;
+mod profile;
+mod repl;
+mod replay;
+mod runner;
+mod ssa;
+mod stdlib;
+#[cfg(test)]
 mod tests;
+mod trace;
+mod vm;
+mod watch;
 
 const STACK_SIZE: usize = 255;
+const REGISTERS: [Register; NumOfRegisters as usize] = [A, B, C, D, E, F, Ip, Sp, St, Eq];
 
 use parser::*;
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+use vm::{Vm, VmError};
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Instruction {
     Psh(i32),
     Add(Register, Register),
@@ -31,8 +60,13 @@ pub enum Instruction {
     Tel(Register, Register), // <=
     Tem(Register, Register), // >=
     Jmp(i32),      // Jump to line if Eq is true
+    Cal(i32),      // Pushes the return address and jumps
+    Ret,           // Pops the return address and jumps back to the caller
+    Lod(Register, Register), // Loads the stack slot addressed by register_b in register_a
+    Sto(Register, Register), // Stores register_b in the stack slot addressed by register_a
+    Ast(Rc<str>),            // Halts with the message if Eq is false
 }
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Register {
     A = 0,
     B = 1,
@@ -63,61 +97,57 @@ fn reg_name(reg: i32) -> &'static str {
     }
 }
 
-fn fetch(program: &Vec<Instruction>, ip: usize) -> Instruction {
+#[cfg(test)]
+fn fetch(program: &[Instruction], ip: usize) -> Instruction {
     if ip >= program.len() {
-        panic!("ERR: ATTEMPTED_TO_GO_TO_UNDEFINED_INSTRUCTION");
+        panic!("{}", VmError::UndefinedInstruction.code());
     }
-    program[ip]
+    program[ip].clone()
 }
 
 pub fn dump(
     labels: &BTreeMap<String, i32>,
-    stack: &Vec<i32>,
+    stack: &[i32],
     regs: &[i32; NumOfRegisters as usize],
+    out: &mut dyn Write,
 ) {
-    print!("[");
-    for i in 0..(NumOfRegisters as usize) {
-        print!("{}: {}, ", reg_name(i as i32), regs[i]);
+    let mut text = String::from("[");
+    for (i, value) in regs.iter().enumerate() {
+        text += &format!("{}: {}, ", reg_name(i as i32), value);
     }
-    println!("]");
-    println!();
-    print!("Stack : [{}, ", stack[0]);
+    text += "]\n\n";
+    text += &format!("Stack : [{}, ", stack[0]);
     for i in 1..stack.len() {
         if i == stack.len() - 1 {
-            println!("{}]", stack[i]);
+            text += &format!("{}]\n", stack[i]);
         } else {
-            print!("{}, ", stack[i]);
+            text += &format!("{}, ", stack[i]);
         }
     }
-    println!("\nLabels: ");
+    text += "\nLabels: \n";
     for (label, instr) in labels {
-        println!("{} -> {}", label, instr + 2);
+        text += &format!("{} -> {}\n", label, instr + 2);
     }
+    out.write_all(text.as_bytes()).unwrap();
 }
 
-fn eval(
+/// Runs a single instruction, `prt`, `drg` and `dmp` writing to `out`.
+fn exec(
     labels: &BTreeMap<String, i32>,
     instr: Instruction,
     running: &mut bool,
-    stack: &mut Vec<i32>,
+    stack: &mut [i32],
     regs: &mut [i32; NumOfRegisters as usize],
-    details: bool,
-) {
+    out: &mut dyn Write,
+) -> Result<(), VmError> {
     // Instrucion Pointer : regs[6]
     // Stack Pointer : regs[7]
 
-    if details {
-        print!("{} - ", regs[6]);
-    }
-
     match instr {
-        Dmp => dump(labels, stack, regs),
+        Dmp => dump(labels, stack, regs, out),
         Gto(i) => {
             if i < 0 {
-                panic!("ERR_ATEMPTED_TO_JUMP_TO_NEGATIVE_OPERATION_NUMBER");
-            }
-            if details {
-                println!("Went to {}", i - 1);
+                return Err(VmError::NegativeJump);
             }
             regs[Ip as usize] = i - 2;
 
@@ -128,88 +158,104 @@ fn eval(
         }
         Prt(reg) => {
             if (0..256).contains(&regs[reg as usize]) {
-                print!("{}", regs[reg as usize] as u8 as char);
-                io::stdout().flush().unwrap();
+                write!(out, "{}", regs[reg as usize] as u8 as char).unwrap();
+                out.flush().unwrap();
             }
         }
         Tee(a, b) => {
-            if details {
-                println!("{} == {}", regs[a as usize], regs[b as usize]);
-            }
             regs[Eq as usize] = (regs[a as usize] == regs[b as usize]) as i32;
         }
         Tne(a, b) => {
-            if details {
-                println!("{} != {}", regs[a as usize], regs[b as usize]);
-            }
             regs[Eq as usize] = (regs[a as usize] != regs[b as usize]) as i32;
         }
         Tll(a, b) => {
-            if details {
-                println!("{} < {}", regs[a as usize], regs[b as usize]);
-            }
             regs[Eq as usize] = (regs[a as usize] < regs[b as usize]) as i32;
         }
         Tmm(a, b) => {
-            if details {
-                println!("{} > {}", regs[a as usize], regs[b as usize]);
-            }
             regs[Eq as usize] = (regs[a as usize] > regs[b as usize]) as i32;
         }
         Tel(a, b) => {
-            if details {
-                println!("{}  <= {}", regs[a as usize], regs[b as usize]);
-            }
             regs[Eq as usize] = (regs[a as usize] <= regs[b as usize]) as i32;
         }
         Tem(a, b) => {
-            if details {
-                println!("{}  >= {}", regs[a as usize], regs[b as usize]);
-            }
             regs[Eq as usize] = (regs[a as usize] >= regs[b as usize]) as i32;
         }
         Jmp(i) => {
             if i < 0 {
-                panic!("ERR_ATEMPTED_TO_JUMP_TO_NEGATIVE_OPERATION_NUMBER");
-            }
-            if details {
-                println!("Jumped to {}", i);
+                return Err(VmError::NegativeJump);
             }
             if regs[Eq as usize] == 1 {
-                if details {
-                    println!("Goto {}", i);
-                }
                 regs[Ip as usize] = i - 2;
-            } else {
-                if details {
-                    println!("None");
-                }
             }
         }
-        Hlt => {
-            if details {
-                println!("Quit");
+        Ast(message) => {
+            if regs[Eq as usize] != 1 {
+                return Err(VmError::AssertionFailed(message));
             }
+        }
+        Hlt => {
             *running = false;
         }
         Psh(i) => {
-            if (regs[7] + 1) as usize >= STACK_SIZE {
-                panic!("ERR_STACK_OVERFLOW");
+            if regs[7] < -1 || regs[7] >= STACK_SIZE as i32 - 1 {
+                return Err(VmError::StackOverflow);
             }
             regs[7] += 1;
             stack[regs[7] as usize] = i;
             regs[8] = i;
-            if details {
-                println!("-> {}", i);
+        }
+        Cal(i) => {
+            if i < 0 {
+                return Err(VmError::NegativeJump);
+            }
+            if regs[7] < -1 || regs[7] >= STACK_SIZE as i32 - 1 {
+                return Err(VmError::StackOverflow);
+            }
+            regs[7] += 1;
+            stack[regs[7] as usize] = regs[Ip as usize];
+            regs[8] = regs[Ip as usize];
+            regs[Ip as usize] = i - 2;
+        }
+        Ret => {
+            if regs[7] < 0 {
+                return Err(VmError::StackUnderflow);
+            }
+            if regs[7] >= STACK_SIZE as i32 {
+                return Err(VmError::InvalidStackAddress);
+            }
+            let address = stack[regs[7] as usize];
+            regs[7] -= 1;
+            regs[8] = if regs[7] < 0 {
+                0
+            } else {
+                stack[regs[7] as usize]
+            };
+            regs[Ip as usize] = address;
+        }
+        Lod(a, b) => {
+            let address = regs[b as usize];
+            if !(0..STACK_SIZE as i32).contains(&address) {
+                return Err(VmError::InvalidStackAddress);
+            }
+            regs[a as usize] = stack[address as usize];
+        }
+        Sto(a, b) => {
+            let address = regs[a as usize];
+            if !(0..STACK_SIZE as i32).contains(&address) {
+                return Err(VmError::InvalidStackAddress);
+            }
+            stack[address as usize] = regs[b as usize];
+            if address == regs[7] {
+                regs[8] = regs[b as usize];
             }
         }
         Pop => {
-            if regs[7] - 1 < 0 && regs[7] != 0 {
-                // adding exception for popping the last element
-                panic!("ERR_STACK_UNDERFLOW");
+            if regs[7] < 0 {
+                return Err(VmError::StackUnderflow);
+            }
+            if regs[7] > STACK_SIZE as i32 {
+                return Err(VmError::InvalidStackAddress);
             }
-            let popped = stack[regs[7] as usize];
-
             if regs[7] != 0 {
                 regs[7] -= 1;
                 regs[8] = stack[regs[7] as usize];
@@ -217,51 +263,49 @@ fn eval(
                 regs[7] -= 1;
                 regs[8] = 0;
             }
-
-            if details {
-                println!("<- {}", popped);
-            }
         }
         Add(a, b) => {
-            if details {
-                println!("{} + {}", regs[a as usize], regs[b as usize]);
-            }
-            regs[a as usize] += regs[b as usize];
+            regs[a as usize] = regs[a as usize].wrapping_add(regs[b as usize]);
         }
         Sub(a, b) => {
-            if details {
-                println!("{} - {}", regs[a as usize], regs[b as usize]);
-            }
-            regs[a as usize] -= regs[b as usize];
+            regs[a as usize] = regs[a as usize].wrapping_sub(regs[b as usize]);
         }
         Mul(a, b) => {
-            if details {
-                println!("{} * {}", regs[a as usize], regs[b as usize]);
-            }
-            regs[a as usize] *= regs[b as usize];
+            regs[a as usize] = regs[a as usize].wrapping_mul(regs[b as usize]);
         }
         Div(a, b) => {
-            if details {
-                println!("{} / {}", regs[a as usize], regs[b as usize]);
+            if regs[b as usize] == 0 {
+                return Err(VmError::DivisionByZero);
             }
-            regs[a as usize] /= regs[b as usize];
+            regs[a as usize] = regs[a as usize].wrapping_div(regs[b as usize]);
         }
         Mov(a, b) => {
-            if details {
-                println!("{} <-| {}", reg_name(a as i32), reg_name(b as i32));
-            }
             if a == Ip {
-                regs[a as usize] = regs[b as usize] - 1; // Being the same as jump
+                regs[a as usize] = regs[b as usize].wrapping_sub(1); // Being the same as jump
             }
             regs[a as usize] = regs[b as usize];
         }
         Drg(reg) => {
-            println!("{}", regs[reg as usize]);
+            writeln!(out, "{}", regs[reg as usize]).unwrap();
         }
     }
+    Ok(())
 }
 
-fn help() {
+#[cfg(test)]
+fn eval(
+    labels: &BTreeMap<String, i32>,
+    instr: Instruction,
+    running: &mut bool,
+    stack: &mut [i32],
+    regs: &mut [i32; NumOfRegisters as usize],
+) {
+    if let Err(e) = exec(labels, instr, running, stack, regs, &mut io::stdout()) {
+        panic!("{}", e.code());
+    }
+}
+
+fn help() -> ! {
     println!(
         "wlvm version {} by Wafelack <wafelack@protonmail.com>\n",
         env!("CARGO_PKG_VERSION")
@@ -269,14 +313,44 @@ fn help() {
     println!("usage: wlvm <command> [flags]\n");
     println!("COMMANDS:");
     println!("\trun <filename> : Runs the code file");
+    println!("\tresume <snapshot>: Resumes a program saved by --snapshot-on-exit, taking the same flags as run");
     println!("\tdump <filename>: Runs the program and dumps the memory");
+    println!("\tdebug <filename>: Runs the program in the interactive debugger");
+    println!("\tdap: Serves the Debug Adapter Protocol on stdin and stdout, for editors");
+    println!("\tlsp: Serves the Language Server Protocol on stdin and stdout, for editors");
+    println!("\tasm <filename> [-c] [-o <output>]: Assembles the code file into an executable (or an object file with -c)");
+    println!("\tlink <objects...> -o <output>: Links object files into an executable");
+    println!("\tssa <filename>: Prints the program in SSA form");
+    println!("\tprofile <filename> [--top <n>] [--folded <output>]: Runs the program and reports the instructions, lines and labels it spends the most time in");
+    println!("\tdiff <left> [<right>] [--left <flag>] [--right <flag>]: Runs two programs, or one built in two ways, in lockstep and reports the first step after which they differ");
+    println!("\ttest [paths...] [--jobs <n>]: Runs the programs with expect- annotations found in the files and directories, and reports the ones that fail");
+    println!("\tgen [--seed <n>] [--size <n>] [--terminating] [--in-range]: Prints a random program");
+    println!("\tfuzz [--seed <n>] [--runs <n>] [--size <n>] [--terminating] [--in-range]: Checks the tools against random programs, reporting the seeds they fail on");
+    println!("\tcheck <filename>: Reports likely bugs without running the program");
+    println!("\tfmt <filenames...> [--check]: Formats the code files in place, or lists the ones that need it with --check");
+    println!("\tcfg <filename>: Prints the control-flow graph in the DOT language");
     println!("\nFLAGS:");
     println!("\t--instructions | -d: Shows the instructions run in the program");
-    println!("\t--details | -d     : Shows the details while running code");
+    println!("\t--trace[=json]     : Reports every instruction run on stderr, as text or JSON Lines");
+    println!("\t--trace-file <path>: Writes the trace to a file instead");
+    println!("\t--details | -d     : Same as --trace");
+    println!("\t--optimize | -O    : Optimizes the program before running or assembling it");
+    println!("\t--ssa              : Also runs the SSA dataflow optimizations");
+    println!("\t--strip-assertions : Removes the ast instructions before running, assembling or linking the program");
+    println!("\t--watch <target>   : Reports changes of a register or stack slot ([n]) on stderr");
+    println!("\t--max-steps <n>    : Stops the program after n instructions");
+    println!("\t--gas <n>          : Stops the program once it has used n gas, each instruction costing some");
+    println!("\t--gas-cost <instruction>=<cost>: Changes the gas cost of an instruction");
+    println!("\t--timeout <ms>     : Stops the program after running for ms milliseconds");
+    println!("\t--record <output> : Records the clock reads and the output of the run, to be replayed");
+    println!("\t--replay <recording>: Replays a recording, failing when the run diverges from it");
+    println!("\t--snapshot-on-exit <output>: Saves the state of the machine when the program stops, to be resumed");
+    println!("\t--coverage <output>: Adds the lines and branches run to an lcov tracefile");
+    println!("\t--gdb <address>    : Waits for GDB to connect on address (as 127.0.0.1:1234) to debug the program");
     std::process::exit(0);
 }
 
-fn is_present(args: &Vec<String>, to_search: &str) -> bool {
+fn is_present(args: &[String], to_search: &str) -> bool {
     for arg in args {
         if arg == to_search {
             return true;
@@ -285,50 +359,482 @@ fn is_present(args: &Vec<String>, to_search: &str) -> bool {
     false
 }
 
-fn repl() {
-    let mut program: Vec<Instruction> = vec![];
-    let mut labels: BTreeMap<String, i32> = BTreeMap::new();
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+}
 
-    println!(
-        "wlvm REPL version {} by {}",
-        env!("CARGO_PKG_VERSION"),
-        env!("CARGO_PKG_AUTHORS")
-    );
-    println!("Press q to quit");
+/// Loads a program from either a source file or a linked executable image.
+fn load_program(path: &str) -> (Vec<Instruction>, BTreeMap<String, i32>) {
+    if !std::path::Path::new(path).exists() {
+        eprintln!("Error: no input files");
+        std::process::exit(66);
+    }
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) => panic!("Failed to read file !\nDebug info: {}", e),
+    };
+    if object::is_image(&bytes) {
+        match object::read_image(&bytes) {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("Error: {}: {}", path, e);
+                std::process::exit(65);
+            }
+        }
+    } else {
+        parse_code(&String::from_utf8_lossy(&bytes), true)
+    }
+}
+
+/// Reads back a machine saved by `--snapshot-on-exit`.
+fn load_snapshot(path: &str) -> Vm {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(_) => {
+            eprintln!("Error: no input files");
+            std::process::exit(66);
+        }
+    };
+    match Vm::restore(&bytes) {
+        Ok(vm) => vm,
+        Err(e) => {
+            eprintln!("Error: {}: {}", path, e);
+            std::process::exit(65);
+        }
+    }
+}
+
+/// Returns the source code of a program, unless it is an executable image or
+/// a snapshot.
+fn source_of(path: &str) -> Option<String> {
+    match std::fs::read(path) {
+        Ok(bytes) if !object::is_image(&bytes) && !object::is_snapshot(&bytes) => {
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Reports the problems found by the static analyzer, exiting with an error
+/// if there are any.
+fn check_program(path: &str) -> ! {
+    let (program, _) = load_program(path);
+    // Bundled libraries are appended after the program's own instructions
+    let own = match source_of(path) {
+        Some(source) => source
+            .split('\n')
+            .filter(|line| parser::produces_instruction(line))
+            .count(),
+        None => program.len(),
+    };
+    let diagnostics = check::check(&program, own);
+    for diagnostic in &diagnostics {
+        println!("{}:{}: {}", path, diagnostic.instruction, diagnostic.message);
+    }
+    std::process::exit(if diagnostics.is_empty() { 0 } else { 65 });
+}
+
+/// Applies the optimizations requested on the command line, stripping the
+/// assertions first with `--strip-assertions`.
+fn optimize(
+    args: &[String],
+    program: Vec<Instruction>,
+    labels: BTreeMap<String, i32>,
+) -> (Vec<Instruction>, BTreeMap<String, i32>) {
+    let (program, labels) = if is_present(args, "--strip-assertions") {
+        optimizer::strip_assertions(&program, &labels)
+    } else {
+        (program, labels)
+    };
+    let ssa = is_present(args, "--ssa");
+    let (program, labels) = if ssa {
+        ssa::optimize(&program, &labels)
+    } else {
+        (program, labels)
+    };
+    if ssa || is_present(args, "--optimize") || is_present(args, "-O") {
+        optimizer::optimize(&program, &labels)
+    } else {
+        (program, labels)
+    }
+}
 
-    let (mut stack, mut registers, mut running) = setup_environment();
+fn assemble(args: &[String]) {
+    let relocatable = is_present(args, "-c");
+    if relocatable && is_present(args, "--strip-assertions") {
+        eprintln!("Error: --strip-assertions applies to executables, pass it to wlvm link");
+        std::process::exit(64);
+    }
+    let input = match args.iter().skip(1).find(|a| a.ends_with(".vm")) {
+        Some(i) => i,
+        None => help(),
+    };
+    if !std::path::Path::new(input).exists() {
+        eprintln!("Error: no input files");
+        std::process::exit(66);
+    }
+    let code = match std::fs::read_to_string(input) {
+        Ok(c) => c,
+        Err(e) => panic!("Failed to read file !\nDebug info: {}", e),
+    };
 
-    while running {
-        print!("(wlvm:{}) ", registers[6] + 1);
-        std::io::stdout().flush().unwrap();
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
+    let module = parse_module(&code, true, relocatable);
+    let (bytes, extension) = if relocatable {
+        (module.to_bytes(), "o")
+    } else {
+        match object::link(&[(input.clone(), module)]) {
+            Ok((program, labels)) => {
+                let (program, labels) = optimize(args, program, labels);
+                (object::write_image(&program, &labels), "wlbc")
+            }
+            Err(errors) => link_failed(&errors),
+        }
+    };
+
+    let output = match flag_value(args, "-o") {
+        Some(o) => o.clone(),
+        None => std::path::Path::new(input)
+            .with_extension(extension)
+            .to_string_lossy()
+            .into_owned(),
+    };
+    if let Err(e) = std::fs::write(&output, bytes) {
+        eprintln!("Error: failed to write {}: {}", output, e);
+        std::process::exit(73);
+    }
+}
 
-        if &input.trim() == &"q" || &input.trim() == &"Q" {
-            std::process::exit(0);
+/// Formats source files in place or, with `--check`, reports the ones that
+/// are not formatted and exits with an error.
+fn format_sources(args: &[String]) {
+    let check = is_present(args, "--check");
+    let paths = args[1..]
+        .iter()
+        .filter(|a| *a != "--check")
+        .collect::<Vec<&String>>();
+    if paths.is_empty() {
+        help();
+    }
+    let mut unformatted = 0;
+    for path in paths {
+        let bytes = match std::fs::read(path) {
+            Ok(b) => b,
+            Err(_) => {
+                eprintln!("Error: no input files");
+                std::process::exit(66);
+            }
+        };
+        if object::is_image(&bytes) {
+            eprintln!("Error: {}: cannot format an executable image", path);
+            std::process::exit(65);
+        }
+        let source = String::from_utf8_lossy(&bytes);
+        let tree = fmt::Tree::parse(&source);
+        debug_assert_eq!(tree.source(), source);
+        let formatted = tree.format();
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", path);
+            unformatted += 1;
+        } else if let Err(e) = std::fs::write(path, formatted) {
+            eprintln!("Error: failed to write {}: {}", path, e);
+            std::process::exit(73);
+        }
+    }
+    if unformatted > 0 {
+        std::process::exit(65);
+    }
+}
+
+/// Runs two programs, or the same program built in two ways, in lockstep and
+/// reports the first step after which they differ.
+fn diff_programs(args: &[String]) {
+    // Flags given with --left or --right only apply to that side
+    let mut shared = vec![args[0].clone()];
+    let mut sides = [vec![], vec![]];
+    let mut paths = vec![];
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--left" | "--right" => {
+                let side = (arg == "--right") as usize;
+                match rest.next() {
+                    Some(flag) => sides[side].push(flag.clone()),
+                    None => help(),
+                }
+            }
+            "--max-steps" | "--gas" | "--gas-cost" | "--timeout" | "--context" => {
+                shared.push(arg.clone());
+                shared.extend(rest.next().cloned());
+            }
+            flag if flag.starts_with('-') => shared.push(arg.clone()),
+            path => paths.push(path.to_owned()),
+        }
+    }
+    if paths.is_empty() || paths.len() > 2 {
+        help();
+    }
+    let context = match flag_value(&shared, "--context") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("Error: --context expects a number, found {}", n);
+                std::process::exit(64);
+            }
+        },
+        None => 5,
+    };
+    let runs = [0, 1].map(|side| {
+        let path = &paths[side.min(paths.len() - 1)];
+        let mut flags = shared.clone();
+        flags.extend(sides[side].iter().cloned());
+        let (program, labels) = load_program(path);
+        let (program, labels) = optimize(&flags, program, labels);
+        let built = ["-O", "--optimize", "--ssa", "--strip-assertions"]
+            .iter()
+            .any(|flag| is_present(&flags, flag));
+        // Source lines no longer match once instructions are moved
+        let lines = source_of(path)
+            .filter(|_| !built)
+            .map(|source| parser::source_lines(&source))
+            .unwrap_or_default();
+        let mut vm = Vm::new(program, labels);
+        limit_flags(&flags, &mut vm);
+        diff::Run::new(vm, lines, context)
+    });
+    let [mut left, mut right] = runs;
+    let names = [0, 1].map(|side| {
+        let path = &paths[side.min(paths.len() - 1)];
+        if sides[side].is_empty() {
+            path.clone()
+        } else {
+            format!("{} {}", path, sides[side].join(" "))
+        }
+    });
+    match diff::compare(&mut left, &mut right) {
+        None => {
+            print!("The runs are the same over {} steps", left.vm.steps);
+            match left.error {
+                Some(e) => println!(", both stopping with \"{}\"", e),
+                None => println!(),
+            }
+        }
+        Some((step, difference)) => {
+            println!("The runs differ after step {}: {}", step, difference);
+            for (run, side, name) in [(&left, "Left", &names[0]), (&right, "Right", &names[1])] {
+                println!("\n{}: {}", side, name);
+                for line in run.context() {
+                    println!("  {}", line);
+                }
+            }
+            std::process::exit(70);
+        }
+    }
+}
+
+/// Runs the tests found in the given files and directories, on `--jobs`
+/// threads, and reports the ones that fail.
+fn test_programs(args: &[String]) {
+    let jobs = match flag_value(args, "--jobs") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("Error: --jobs expects a number, found {}", n);
+                std::process::exit(64);
+            }
+        },
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let mut paths = vec![];
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--jobs" {
+            rest.next();
+        } else {
+            paths.push(arg.as_str());
+        }
+    }
+    if paths.is_empty() {
+        paths.push(".");
+    }
+    let files = match runner::discover(&paths) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(66);
         }
+    };
+    let outcomes = runner::run_all(&files, jobs);
+    if outcomes.is_empty() {
+        eprintln!("Error: no tests found");
+        std::process::exit(66);
+    }
+    let mut failed = 0;
+    for outcome in &outcomes {
+        if outcome.failures.is_empty() {
+            println!("PASS {}", outcome.path.display());
+            continue;
+        }
+        failed += 1;
+        println!("FAIL {}", outcome.path.display());
+        for failure in &outcome.failures {
+            println!("    {}", failure);
+        }
+    }
+    println!("\n{} passed, {} failed", outcomes.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(65);
+    }
+}
+
+/// Reads the seed and the shape of the programs to generate.
+fn generation_flags(args: &[String]) -> (u64, fuzz::Options) {
+    let options = fuzz::Options {
+        size: number_value(args, "--size").unwrap_or(20) as usize,
+        terminating: is_present(args, "--terminating"),
+        in_range: is_present(args, "--in-range"),
+    };
+    (number_value(args, "--seed").unwrap_or(0), options)
+}
+
+/// Checks the tools against generated programs, printing how to generate
+/// the ones they fail on.
+fn fuzz_tools(args: &[String]) {
+    let (seed, options) = generation_flags(args);
+    let runs = number_value(args, "--runs").unwrap_or(1000);
+    let summary = fuzz::fuzz(seed, runs, &options);
+    let failures = summary.failures;
+    let mut command = format!("wlvm gen --size {}", options.size);
+    if options.terminating {
+        command += " --terminating";
+    }
+    if options.in_range {
+        command += " --in-range";
+    }
+    for (seed, problem) in &failures {
+        println!("{} --seed {}: {}", command, seed, problem);
+    }
+    let seeds = failures
+        .iter()
+        .map(|(seed, _)| seed)
+        .collect::<std::collections::BTreeSet<_>>();
+    println!(
+        "\n{} programs, {} compared with their optimized builds, {} failed",
+        runs,
+        summary.compared,
+        seeds.len()
+    );
+    if !failures.is_empty() {
+        std::process::exit(70);
+    }
+}
 
-        let (mut current_prog, labs) = parse_code(&input.trim(), false);
-        labels.extend(labs);
-        current_prog.pop();
-        program.extend(&current_prog); // remove hlt instruction
+/// Runs a program while counting the instructions it runs, then reports the
+/// hot spots and, with `--folded`, writes the folded stacks.
+fn profile_program(args: &[String]) {
+    let top = match flag_value(args, "--top") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("Error: --top expects a number, found {}", n);
+                std::process::exit(64);
+            }
+        },
+        None => 10,
+    };
+    let (program, labels) = load_program(&args[1]);
+    let lines = source_of(&args[1])
+        .map(|source| parser::source_lines(&source))
+        .unwrap_or_default();
+    let mut vm = Vm::new(program, labels);
+    limit_flags(args, &mut vm);
+    let mut profile = profile::Profile::new(vm.program.len());
+    let mut error = None;
+    while vm.running {
+        profile.record(&vm);
+        let ip = vm.ip();
+        if let Err(e) = vm.step() {
+            if e.is_limit() {
+                profile.discard(&vm);
+            }
+            error = Some((e, ip));
+            break;
+        }
+    }
+    print!("{}", profile.report(&vm, &lines, top));
+    if let Some(path) = flag_value(args, "--folded") {
+        if let Err(e) = std::fs::write(path, profile.folded(&vm)) {
+            eprintln!("Error: failed to write {}: {}", path, e);
+            std::process::exit(73);
+        }
+    }
+    if let Some((e, ip)) = error {
+        eprintln!("Error: {} at instruction {}", e, ip + 1);
+        std::process::exit(if e.is_limit() { 75 } else { 65 });
+    }
+}
 
-        if *&current_prog.is_empty() {
+fn link_objects(args: &[String]) {
+    let output = match flag_value(args, "-o") {
+        Some(o) => o.clone(),
+        None => help(),
+    };
+    let mut modules = vec![];
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "-o" {
+            i += 2;
+            continue;
+        }
+        if args[i] == "--strip-assertions" {
+            i += 1;
             continue;
         }
+        let bytes = match std::fs::read(&args[i]) {
+            Ok(b) => b,
+            Err(_) => {
+                eprintln!("Error: no input files");
+                std::process::exit(66);
+            }
+        };
+        match object::Object::from_bytes(&bytes) {
+            Ok(o) => modules.push((args[i].clone(), o)),
+            Err(e) => {
+                eprintln!("Error: {}: {}", args[i], e);
+                std::process::exit(65);
+            }
+        }
+        i += 1;
+    }
+    if modules.is_empty() {
+        help();
+    }
 
-        let instr = fetch(&program, registers[6] as usize);
-        eval(
-            &labels,
-            instr,
-            &mut running,
-            &mut stack,
-            &mut registers,
-            false,
-        );
+    let (program, labels) = match object::link(&modules) {
+        Ok(linked) => linked,
+        Err(errors) => link_failed(&errors),
+    };
+    let (program, labels) = if is_present(args, "--strip-assertions") {
+        optimizer::strip_assertions(&program, &labels)
+    } else {
+        (program, labels)
+    };
+    if let Err(e) = std::fs::write(&output, object::write_image(&program, &labels)) {
+        eprintln!("Error: failed to write {}: {}", output, e);
+        std::process::exit(73);
+    }
+}
 
-        registers[6] += 1;
+fn link_failed(errors: &[String]) -> ! {
+    for error in errors {
+        eprintln!("Link error: {}", error);
     }
+    eprintln!("Aborting due to previous errors");
+    std::process::exit(65);
 }
 
 fn main() {
@@ -337,85 +843,402 @@ fn main() {
     let mut program: Vec<Instruction> = vec![];
     let mut labels: BTreeMap<String, i32> = BTreeMap::new();
 
-    let mut details = false;
+    let mut tracer = None;
+    let mut watchpoints = vec![];
+    let mut gdb = None;
+    let mut coverage = None;
+    let mut snapshot = None;
+    let mut resumed = None;
 
-    if args.len() < 1 {
-        repl();
-    } else {
-        if args[0] == "run" {
-            if args.len() < 2 {
-                help();
-            } else {
-                if !std::path::Path::new(&args[1]).exists() {
-                    eprintln!("Error: no input files");
-                    std::process::exit(66);
-                } else {
-                    let code = match std::fs::read_to_string(&args[1]) {
-                        Ok(c) => c,
-                        Err(e) => panic!("Failed to read file !\nDebug info: {}", e),
-                    };
-                    let (tprog, tlabels) = parse_code(&code, true);
-                    /*
-                    Using that because of :
-                                        destructuring assignments are not currently supported
-                    note: for more information, see https://github.com/rust-lang/rfcs/issues/37
-                                        */
-                    program = tprog;
-                    labels = tlabels;
-                }
+    if args.is_empty() {
+        repl::Repl::new().run();
+        return;
+    } else if args[0] == "run" || args[0] == "resume" {
+        if args.len() < 2 {
+            help();
+        } else {
+            if args[0] == "run" {
+                let (tprog, tlabels) = load_program(&args[1]);
+                program = tprog;
+                labels = tlabels;
+                let (tprog, tlabels) = optimize(&args, program, labels);
+                program = tprog;
+                labels = tlabels;
                 if is_present(&args, "--instructions") || is_present(&args, "-i") {
                     println!("{:?}\n==============================", program);
                 }
-                if is_present(&args, "--details") || is_present(&args, "-d") {
-                    details = true;
+            } else {
+                resumed = Some(load_snapshot(&args[1]));
+            }
+            tracer = trace_flags(&args);
+            gdb = flag_value(&args, "--gdb").cloned();
+            snapshot = flag_value(&args, "--snapshot-on-exit").cloned();
+            coverage = flag_value(&args, "--coverage").cloned();
+            if coverage.is_some() {
+                check_coverage_flags(&args);
+            }
+            for pair in args.windows(2).filter(|pair| pair[0] == "--watch") {
+                match watch::Watchpoint::parse(&pair[1]) {
+                    Ok(w) => watchpoints.push(w),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(64);
+                    }
                 }
             }
-        } else if args[0] == "dump" {
-            if args.len() < 2 {
-                help();
-            } else {
-                if !std::path::Path::new(&args[1]).exists() {
+        }
+    } else if args[0] == "dump" {
+        if args.len() < 2 {
+            help();
+        } else {
+            let (tprog, tlab) = load_program(&args[1]);
+            program = tprog;
+            labels = tlab;
+            program.push(Dmp);
+            program.retain(is_valid);
+            program.push(Dmp);
+            program.push(Hlt);
+        }
+    } else if args[0] == "ssa" {
+        if args.len() < 2 {
+            help();
+        }
+        let (program, _) = load_program(&args[1]);
+        match ssa::lift(&program) {
+            Some(function) => print!("{}", function),
+            None => {
+                eprintln!("Error: the stack depth of {} is not statically known", args[1]);
+                std::process::exit(65);
+            }
+        }
+        return;
+    } else if args[0] == "cfg" {
+        if args.len() < 2 {
+            help();
+        }
+        let (program, labels) = load_program(&args[1]);
+        let lines = source_of(&args[1])
+            .map(|source| parser::source_lines(&source))
+            .unwrap_or_default();
+        print!("{}", cfg::to_dot(&program, &labels, &lines));
+        return;
+    } else if args[0] == "debug" {
+        if args.len() < 2 {
+            help();
+        }
+        let (program, labels) = load_program(&args[1]);
+        let lines = source_of(&args[1])
+            .map(|source| parser::source_lines(&source))
+            .unwrap_or_default();
+        debugger::Debugger::new(program, labels, lines).run();
+        return;
+    } else if args[0] == "dap" {
+        if let Err(e) = dap::serve() {
+            eprintln!("Error: {}", e);
+            std::process::exit(74);
+        }
+        return;
+    } else if args[0] == "lsp" {
+        if let Err(e) = lsp::serve() {
+            eprintln!("Error: {}", e);
+            std::process::exit(74);
+        }
+        return;
+    } else if args[0] == "check" {
+        if args.len() < 2 {
+            help();
+        }
+        check_program(&args[1]);
+    } else if args[0] == "profile" {
+        if args.len() < 2 {
+            help();
+        }
+        profile_program(&args);
+        return;
+    } else if args[0] == "diff" {
+        if args.len() < 2 {
+            help();
+        }
+        diff_programs(&args);
+        return;
+    } else if args[0] == "test" {
+        test_programs(&args);
+        return;
+    } else if args[0] == "gen" {
+        let (seed, options) = generation_flags(&args);
+        print!("{}", fuzz::generate(seed, &options));
+        return;
+    } else if args[0] == "fuzz" {
+        fuzz_tools(&args);
+        return;
+    } else if args[0] == "fmt" {
+        format_sources(&args);
+        return;
+    } else if args[0] == "asm" {
+        assemble(&args);
+        return;
+    } else if args[0] == "link" {
+        link_objects(&args);
+        return;
+    } else {
+        help();
+    }
+
+    let mut vm = resumed.unwrap_or_else(|| Vm::new(program, labels));
+    vm.trace = tracer;
+    if coverage.is_some() {
+        vm.coverage = Some(coverage::Coverage::new(vm.program.len()));
+    }
+    if let Some(address) = gdb {
+        if let Err(e) = gdb::serve(vm, &address) {
+            eprintln!("Error: {}: {}", address, e);
+            std::process::exit(74);
+        }
+        return;
+    }
+    limit_flags(&args, &mut vm);
+    let record = session_flags(&args, &mut vm);
+    let result = if watchpoints.is_empty() {
+        vm.run()
+    } else {
+        run_watched(&mut vm, &watchpoints)
+    };
+    let diverged = match &mut vm.session {
+        Some(session) => !session.end(vm.steps),
+        None => false,
+    };
+    if let Some(path) = record {
+        let log = vm.session.as_ref().unwrap().log.to_text();
+        if let Err(e) = std::fs::write(&path, log) {
+            eprintln!("Error: failed to write {}: {}", path, e);
+            std::process::exit(73);
+        }
+    }
+    if let Some(path) = snapshot {
+        if let Err(e) = std::fs::write(&path, vm.snapshot()) {
+            eprintln!("Error: failed to write {}: {}", path, e);
+            std::process::exit(73);
+        }
+    }
+    if let Some(output) = coverage {
+        write_coverage(&args, &output, &vm);
+    }
+    if diverged {
+        let divergence = vm.session.unwrap().divergence.unwrap();
+        eprintln!("Error: execution diverged from the recording: {}", divergence);
+        std::process::exit(70);
+    }
+    match result {
+        Err(e) if e.is_limit() => {
+            eprintln!("Error: {} after {} instructions", e, vm.steps);
+            std::process::exit(75);
+        }
+        Err(e @ VmError::AssertionFailed(_)) => {
+            eprint!("{}", assertion_report(&args, &vm, e));
+            std::process::exit(134);
+        }
+        Err(e) => panic!("{}", e.code()),
+        Ok(()) => {}
+    }
+}
+
+/// Describes a failed assertion: its message, the source line of the `ast`
+/// instruction when the program was run from source, and the state of the
+/// machine.
+fn assertion_report(args: &[String], vm: &Vm, error: VmError) -> String {
+    let ip = vm.ip();
+    let mut report = format!("Error: {}\n  at instruction {}", error, ip + 1);
+    // Optimizations move instructions away from their source lines
+    let optimized = ["-O", "--optimize", "--ssa"]
+        .iter()
+        .any(|flag| is_present(args, flag));
+    if let Some(source) = source_of(&args[1]).filter(|_| !optimized) {
+        if let Some((line, text)) = parser::source_lines(&source).get(ip) {
+            report += &format!(", line {}: {}", line, text.trim());
+        }
+    }
+    let registers = vm
+        .registers
+        .iter()
+        .enumerate()
+        .map(|(i, value)| format!("{} = {}", reg_name(i as i32).to_lowercase(), value))
+        .collect::<Vec<String>>();
+    report += &format!("\n  registers: {}\n  stack:", registers.join(", "));
+    match vm.used_stack() {
+        Ok([]) => report += " empty",
+        Ok(slots) => slots.iter().for_each(|value| report += &format!(" {}", value)),
+        Err(e) => report += &format!(" {}", e),
+    }
+    report.push('\n');
+    report
+}
+
+/// Starts recording a run or replaying a recording, as asked by `--record`
+/// and `--replay`, returning where the recording goes.
+fn session_flags(args: &[String], vm: &mut Vm) -> Option<String> {
+    let record = flag_value(args, "--record");
+    match (record, flag_value(args, "--replay")) {
+        (Some(_), Some(_)) => {
+            eprintln!("Error: --record and --replay cannot be used together");
+            std::process::exit(64);
+        }
+        (Some(_), None) => vm.session = Some(replay::Session::record(&vm.program)),
+        (None, Some(path)) => {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(_) => {
                     eprintln!("Error: no input files");
                     std::process::exit(66);
-                } else {
-                    let code = match std::fs::read_to_string(&args[1]) {
-                        Ok(c) => c,
-                        Err(e) => panic!("Failed to read file !\nDebug info: {}", e),
-                    };
-                    let (tprog, tlab) = parse_code(&code, true);
-                    program = tprog;
-                    labels = tlab;
-                    program.push(Dmp);
-                    program = program
-                        .iter()
-                        .filter(|x| is_valid(**x))
-                        .map(|s| *s)
-                        .collect();
-                    program.push(Dmp);
-                    program.push(Hlt);
+                }
+            };
+            let session = replay::Log::parse(&text)
+                .and_then(|log| replay::Session::replay(log, &vm.program));
+            match session {
+                Ok(session) => vm.session = Some(session),
+                Err(e) => {
+                    eprintln!("Error: {}: {}", path, e);
+                    std::process::exit(65);
                 }
             }
-        } else {
-            help();
         }
+        (None, None) => {}
     }
+    record.cloned()
+}
 
-    let (mut stack, mut registers, mut running) = setup_environment();
+/// Reads the value of a numeric flag, exiting when it isn't a number.
+fn number_value(args: &[String], flag: &str) -> Option<u64> {
+    flag_value(args, flag).map(|value| match value.parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => {
+            eprintln!("Error: {} expects a number, found {}", flag, value);
+            std::process::exit(64);
+        }
+    })
+}
 
-    while running {
-        let instr = fetch(&program, registers[6] as usize);
-        eval(
-            &labels,
-            instr,
-            &mut running,
-            &mut stack,
-            &mut registers,
-            details,
-        );
-        registers[6] += 1;
+/// Puts the limits asked for by `--max-steps`, `--gas`, `--gas-cost` and
+/// `--timeout` on a run.
+fn limit_flags(args: &[String], vm: &mut Vm) {
+    // Resumed programs get as many steps again
+    vm.max_steps = number_value(args, "--max-steps").map(|n| vm.steps + n);
+    vm.gas = number_value(args, "--gas");
+    for pair in args.windows(2).filter(|pair| pair[0] == "--gas-cost") {
+        if let Err(e) = vm.costs.parse_assignment(&pair[1]) {
+            eprintln!("Error: {}", e);
+            std::process::exit(64);
+        }
+    }
+    if let Some(ms) = number_value(args, "--timeout") {
+        vm.set_timeout(std::time::Duration::from_millis(ms));
     }
 }
 
+/// Sets up the tracer asked for by `--trace`, `--trace-file` and `--details`.
+fn trace_flags(args: &[String]) -> Option<trace::Tracer> {
+    let format = if is_present(args, "--trace=json") {
+        trace::Format::Json
+    } else if is_present(args, "--trace")
+        || is_present(args, "--trace=text")
+        || is_present(args, "--details")
+        || is_present(args, "-d")
+    {
+        trace::Format::Text
+    } else if let Some(flag) = args.iter().find(|a| a.starts_with("--trace=")) {
+        eprintln!("Error: unknown trace format {}, expected text or json", &flag[8..]);
+        std::process::exit(64);
+    } else {
+        return None;
+    };
+    let out: Box<dyn Write> = match flag_value(args, "--trace-file") {
+        Some(path) => match std::fs::File::create(path) {
+            Ok(file) => Box::new(io::BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("Error: failed to write {}: {}", path, e);
+                std::process::exit(73);
+            }
+        },
+        None => Box::new(io::stderr()),
+    };
+    // Source lines no longer match instructions once optimized
+    let optimized = ["-O", "--optimize", "--ssa"]
+        .iter()
+        .any(|flag| is_present(args, flag));
+    let lines = match source_of(&args[1]) {
+        Some(source) if !optimized => parser::source_lines(&source),
+        _ => vec![],
+    };
+    Some(trace::Tracer::new(format, out, lines))
+}
+
+/// Exits if coverage can't be measured: it is mapped back to source lines,
+/// which no longer match the instructions once the program is optimized.
+fn check_coverage_flags(args: &[String]) {
+    if source_of(&args[1]).is_none() {
+        eprintln!("Error: coverage needs the source of the program, not an executable image");
+        std::process::exit(65);
+    }
+    for flag in &["-O", "--optimize", "--ssa", "--strip-assertions", "--gdb"] {
+        if is_present(args, flag) {
+            eprintln!("Error: --coverage cannot be used with {}", flag);
+            std::process::exit(64);
+        }
+    }
+}
+
+/// Adds the coverage of a run to the lcov tracefile at `output`.
+fn write_coverage(args: &[String], output: &str, vm: &Vm) {
+    let source = source_of(&args[1]).unwrap();
+    let jumps = vm
+        .program
+        .iter()
+        .map(|instr| matches!(instr, Jmp(_)))
+        .collect::<Vec<bool>>();
+    let record = coverage::Record::new(
+        vm.coverage.as_ref().unwrap(),
+        &parser::source_lines(&source),
+        &jumps,
+        source.split('\n').count(),
+    );
+    let mut records = match std::fs::read_to_string(output) {
+        Ok(text) => match coverage::parse(&text) {
+            Ok(records) => records,
+            Err(e) => {
+                eprintln!("Error: {}: {}", output, e);
+                std::process::exit(65);
+            }
+        },
+        Err(_) => BTreeMap::new(),
+    };
+    let path = std::fs::canonicalize(&args[1])
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| args[1].clone());
+    records.entry(path).or_default().merge(&record);
+    if let Err(e) = std::fs::write(output, coverage::to_lcov(&records)) {
+        eprintln!("Error: failed to write {}: {}", output, e);
+        std::process::exit(73);
+    }
+}
+
+/// Runs a program, reporting on stderr every change of a watched value.
+fn run_watched(vm: &mut Vm, watchpoints: &[watch::Watchpoint]) -> Result<(), VmError> {
+    while vm.running {
+        let ip = vm.ip();
+        let before = watch::snapshot(watchpoints, vm);
+        vm.step()?;
+        for hit in watch::hits(watchpoints, &before, vm) {
+            eprintln!(
+                "watch {}: instruction {}: {} -> {}",
+                hit.watchpoint,
+                ip + 1,
+                hit.old,
+                hit.new
+            );
+        }
+    }
+    Ok(())
+}
+
 fn setup_environment() -> (Vec<i32>, [i32; NumOfRegisters as usize], bool) {
     let stack = vec![0; STACK_SIZE];
     let mut registers = [0; NumOfRegisters as usize];
@@ -425,12 +1248,6 @@ fn setup_environment() -> (Vec<i32>, [i32; NumOfRegisters as usize], bool) {
     (stack, registers, true)
 }
 
-fn is_valid(instr: Instruction) -> bool {
-    match instr {
-        Prt(_) => false,
-        Drg(_) => false,
-        Dmp => false,
-        Hlt => false,
-        _ => true,
-    }
+fn is_valid(instr: &Instruction) -> bool {
+    !matches!(instr, Prt(_) | Drg(_) | Dmp | Hlt)
 }
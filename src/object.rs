@@ -0,0 +1,490 @@
+use crate::vm::Vm;
+use crate::Instruction::*;
+use crate::{Instruction, NumOfRegisters, Register, Register::*, STACK_SIZE};
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+const OBJECT_MAGIC: &[u8; 4] = b"WLOB";
+const IMAGE_MAGIC: &[u8; 4] = b"WLBC";
+const SNAPSHOT_MAGIC: &[u8; 4] = b"WLSS";
+const FORMAT_VERSION: u8 = 1;
+
+/// A linked program and its symbol table.
+pub type Image = (Vec<Instruction>, BTreeMap<String, i32>);
+
+/// What the operand of a jump instruction refers to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// An instruction of the same module, relocated by the module's base.
+    Local,
+    /// A label exported by another module.
+    Symbol(String),
+}
+
+/// A jump operand that has to be patched at link time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: usize,
+    pub target: Target,
+}
+
+/// A relocatable module, as produced by `wlvm asm -c`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Object {
+    pub code: Vec<Instruction>,
+    pub labels: BTreeMap<String, i32>,
+    pub exports: Vec<String>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+pub fn is_image(bytes: &[u8]) -> bool {
+    bytes.starts_with(IMAGE_MAGIC)
+}
+
+pub fn is_snapshot(bytes: &[u8]) -> bool {
+    bytes.starts_with(SNAPSHOT_MAGIC)
+}
+
+pub fn opcode(instr: &Instruction) -> u8 {
+    match instr {
+        Psh(_) => 0,
+        Add(_, _) => 1,
+        Mul(_, _) => 2,
+        Div(_, _) => 3,
+        Sub(_, _) => 4,
+        Pop => 5,
+        Mov(_, _) => 6,
+        Hlt => 7,
+        Drg(_) => 8,
+        Dmp => 9,
+        Gto(_) => 10,
+        Prt(_) => 11,
+        Tee(_, _) => 12,
+        Tne(_, _) => 13,
+        Tll(_, _) => 14,
+        Tmm(_, _) => 15,
+        Tel(_, _) => 16,
+        Tem(_, _) => 17,
+        Jmp(_) => 18,
+        Cal(_) => 19,
+        Ret => 20,
+        Lod(_, _) => 21,
+        Sto(_, _) => 22,
+        Ast(_) => 23,
+    }
+}
+
+fn register(byte: u8) -> Result<Register, String> {
+    Ok(match byte {
+        0 => A,
+        1 => B,
+        2 => C,
+        3 => D,
+        4 => E,
+        5 => F,
+        6 => Ip,
+        7 => Sp,
+        8 => St,
+        9 => Eq,
+        x => return Err(format!("invalid register {}", x)),
+    })
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+    fn u32(&mut self, v: u32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    fn i32(&mut self, v: i32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+    fn instruction(&mut self, instr: &Instruction) {
+        self.u8(opcode(instr));
+        match *instr {
+            Psh(i) | Gto(i) | Jmp(i) | Cal(i) => self.i32(i),
+            Drg(r) | Prt(r) => self.u8(r as u8),
+            Add(a, b)
+            | Mul(a, b)
+            | Div(a, b)
+            | Sub(a, b)
+            | Mov(a, b)
+            | Tee(a, b)
+            | Tne(a, b)
+            | Tll(a, b)
+            | Tmm(a, b)
+            | Tel(a, b)
+            | Tem(a, b)
+            | Lod(a, b)
+            | Sto(a, b) => {
+                self.u8(a as u8);
+                self.u8(b as u8);
+            }
+            Ast(ref message) => self.str(message),
+            Pop | Hlt | Dmp | Ret => {}
+        }
+    }
+    fn code(&mut self, code: &[Instruction]) {
+        self.u32(code.len() as u32);
+        for instr in code {
+            self.instruction(instr);
+        }
+    }
+    fn symbols(&mut self, symbols: &BTreeMap<String, i32>) {
+        self.u32(symbols.len() as u32);
+        for (name, value) in symbols {
+            self.str(name);
+            self.i32(*value);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Messages of the assertions read so far.
+    messages: BTreeSet<Rc<str>>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.bytes.len() {
+            return Err("unexpected end of file".to_owned());
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+    fn i32(&mut self) -> Result<i32, String> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(i32::from_le_bytes(buf))
+    }
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "invalid symbol name".to_owned())
+    }
+    fn header(&mut self, magic: &[u8; 4]) -> Result<(), String> {
+        if self.take(4)? != magic {
+            return Err("bad magic number".to_owned());
+        }
+        let version = self.u8()?;
+        if version != FORMAT_VERSION {
+            return Err(format!("unsupported format version {}", version));
+        }
+        Ok(())
+    }
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        let op = self.u8()?;
+        Ok(match op {
+            0 => Psh(self.i32()?),
+            1 => Add(register(self.u8()?)?, register(self.u8()?)?),
+            2 => Mul(register(self.u8()?)?, register(self.u8()?)?),
+            3 => Div(register(self.u8()?)?, register(self.u8()?)?),
+            4 => Sub(register(self.u8()?)?, register(self.u8()?)?),
+            5 => Pop,
+            6 => Mov(register(self.u8()?)?, register(self.u8()?)?),
+            7 => Hlt,
+            8 => Drg(register(self.u8()?)?),
+            9 => Dmp,
+            10 => Gto(self.i32()?),
+            11 => Prt(register(self.u8()?)?),
+            12 => Tee(register(self.u8()?)?, register(self.u8()?)?),
+            13 => Tne(register(self.u8()?)?, register(self.u8()?)?),
+            14 => Tll(register(self.u8()?)?, register(self.u8()?)?),
+            15 => Tmm(register(self.u8()?)?, register(self.u8()?)?),
+            16 => Tel(register(self.u8()?)?, register(self.u8()?)?),
+            17 => Tem(register(self.u8()?)?, register(self.u8()?)?),
+            18 => Jmp(self.i32()?),
+            19 => Cal(self.i32()?),
+            20 => Ret,
+            21 => Lod(register(self.u8()?)?, register(self.u8()?)?),
+            22 => Sto(register(self.u8()?)?, register(self.u8()?)?),
+            23 => {
+                let message = self.str()?;
+                Ast(crate::parser::intern(&mut self.messages, &message))
+            }
+            x => return Err(format!("invalid opcode {}", x)),
+        })
+    }
+    fn code(&mut self) -> Result<Vec<Instruction>, String> {
+        let len = self.u32()?;
+        (0..len).map(|_| self.instruction()).collect()
+    }
+    fn symbols(&mut self) -> Result<BTreeMap<String, i32>, String> {
+        let len = self.u32()?;
+        let mut symbols = BTreeMap::new();
+        for _ in 0..len {
+            let name = self.str()?;
+            symbols.insert(name, self.i32()?);
+        }
+        Ok(symbols)
+    }
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer { bytes: vec![] };
+        w.bytes.extend_from_slice(OBJECT_MAGIC);
+        w.u8(FORMAT_VERSION);
+        w.code(&self.code);
+
+        let exports = self
+            .exports
+            .iter()
+            .map(|name| (name.clone(), self.labels[name]))
+            .collect::<BTreeMap<String, i32>>();
+        w.symbols(&exports);
+
+        w.u32(self.imports.len() as u32);
+        for name in &self.imports {
+            w.str(name);
+        }
+
+        w.u32(self.relocations.len() as u32);
+        for reloc in &self.relocations {
+            w.u32(reloc.offset as u32);
+            match &reloc.target {
+                Target::Local => w.u8(0),
+                Target::Symbol(name) => {
+                    w.u8(1);
+                    w.str(name);
+                }
+            }
+        }
+        w.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, String> {
+        let mut r = Reader {
+            bytes,
+            pos: 0,
+            messages: BTreeSet::new(),
+        };
+        r.header(OBJECT_MAGIC)?;
+        let code = r.code()?;
+        let labels = r.symbols()?;
+        let exports = labels.keys().cloned().collect();
+
+        let mut imports = vec![];
+        for _ in 0..r.u32()? {
+            imports.push(r.str()?);
+        }
+
+        let mut relocations = vec![];
+        for _ in 0..r.u32()? {
+            let offset = r.u32()? as usize;
+            let target = match r.u8()? {
+                0 => Target::Local,
+                1 => Target::Symbol(r.str()?),
+                x => return Err(format!("invalid relocation kind {}", x)),
+            };
+            if offset >= code.len() {
+                return Err(format!("relocation offset {} out of range", offset));
+            }
+            relocations.push(Relocation { offset, target });
+        }
+
+        Ok(Object {
+            code,
+            labels,
+            exports,
+            imports,
+            relocations,
+        })
+    }
+}
+
+/// Serializes a linked program into an executable image.
+pub fn write_image(program: &[Instruction], labels: &BTreeMap<String, i32>) -> Vec<u8> {
+    let mut w = Writer { bytes: vec![] };
+    w.bytes.extend_from_slice(IMAGE_MAGIC);
+    w.u8(FORMAT_VERSION);
+    w.code(program);
+    w.symbols(labels);
+    w.bytes
+}
+
+pub fn read_image(bytes: &[u8]) -> Result<Image, String> {
+    let mut r = Reader {
+        bytes,
+        pos: 0,
+        messages: BTreeSet::new(),
+    };
+    r.header(IMAGE_MAGIC)?;
+    let code = r.code()?;
+    let labels = r.symbols()?;
+    Ok((code, labels))
+}
+
+/// Serializes the state of a machine, its program included, so that it can
+/// be resumed later, possibly elsewhere.
+pub fn write_snapshot(vm: &Vm) -> Vec<u8> {
+    let mut w = Writer { bytes: vec![] };
+    w.bytes.extend_from_slice(SNAPSHOT_MAGIC);
+    w.u8(FORMAT_VERSION);
+    w.code(&vm.program);
+    w.symbols(&vm.labels);
+    w.u8(vm.registers.len() as u8);
+    for value in &vm.registers {
+        w.i32(*value);
+    }
+    w.u32(vm.stack.len() as u32);
+    for value in &vm.stack {
+        w.i32(*value);
+    }
+    w.u8(vm.running as u8);
+    w.u32(vm.calls.len() as u32);
+    for call in &vm.calls {
+        w.u32(*call as u32);
+    }
+    w.u64(vm.steps);
+    w.bytes
+}
+
+pub fn read_snapshot(bytes: &[u8]) -> Result<Vm, String> {
+    let mut r = Reader {
+        bytes,
+        pos: 0,
+        messages: BTreeSet::new(),
+    };
+    r.header(SNAPSHOT_MAGIC)?;
+    let code = r.code()?;
+    let labels = r.symbols()?;
+    let mut vm = Vm::new(code, labels);
+    let registers = r.u8()?;
+    if registers != NumOfRegisters as u8 {
+        return Err(format!(
+            "expected {} registers, found {}",
+            NumOfRegisters as u8, registers
+        ));
+    }
+    for value in vm.registers.iter_mut() {
+        *value = r.i32()?;
+    }
+    let slots = r.u32()? as usize;
+    if slots != STACK_SIZE {
+        return Err(format!(
+            "expected {} stack slots, found {}",
+            STACK_SIZE, slots
+        ));
+    }
+    for value in vm.stack.iter_mut() {
+        *value = r.i32()?;
+    }
+    vm.running = r.u8()? != 0;
+    for _ in 0..r.u32()? {
+        let call = r.u32()? as usize;
+        if call >= vm.program.len() {
+            return Err(format!("call from instruction {} out of range", call));
+        }
+        vm.calls.push(call);
+    }
+    vm.steps = r.u64()?;
+    Ok(vm)
+}
+
+/// Links modules together, in order, into a single program.
+///
+/// The first module is the entry point. Every error found is reported, not
+/// only the first one.
+pub fn link(modules: &[(String, Object)]) -> Result<Image, Vec<String>> {
+    let mut errors = vec![];
+    let mut symbols: BTreeMap<String, (i32, &str)> = BTreeMap::new();
+    let mut bases = vec![];
+    let mut base = 0;
+
+    for (name, object) in modules {
+        bases.push(base);
+        for export in &object.exports {
+            let value = object.labels[export] + base;
+            if let Some((_, other)) = symbols.get(export) {
+                errors.push(format!(
+                    "duplicate symbol {} (defined in {} and {})",
+                    export, other, name
+                ));
+            } else {
+                symbols.insert(export.clone(), (value, name));
+            }
+        }
+        base += object.code.len() as i32;
+    }
+
+    let mut program = vec![];
+    for ((name, object), base) in modules.iter().zip(bases) {
+        let mut code = object.code.clone();
+        // Local jumps outside the module are left as written, rather than
+        // moved to what could be an instruction of another module
+        let len = code.len() as i32;
+        let relocate = |i: i32| {
+            if (1..=len).contains(&i) {
+                i + base
+            } else {
+                i
+            }
+        };
+        for reloc in &object.relocations {
+            let value = match &reloc.target {
+                Target::Local => None,
+                Target::Symbol(symbol) => match symbols.get(symbol) {
+                    Some((value, _)) => Some(*value),
+                    None => {
+                        let error = format!("undefined symbol {} (used in {})", symbol, name);
+                        if !errors.contains(&error) {
+                            errors.push(error);
+                        }
+                        continue;
+                    }
+                },
+            };
+            code[reloc.offset] = match code[reloc.offset].clone() {
+                Gto(i) => Gto(value.unwrap_or_else(|| relocate(i))),
+                Jmp(i) => Jmp(value.unwrap_or_else(|| relocate(i))),
+                Cal(i) => Cal(value.unwrap_or_else(|| relocate(i))),
+                other => {
+                    errors.push(format!(
+                        "invalid relocation at instruction {} of {} ({:?})",
+                        reloc.offset + 1,
+                        name,
+                        other
+                    ));
+                    continue;
+                }
+            };
+        }
+        program.extend(code);
+    }
+
+    if errors.is_empty() {
+        let labels = symbols.into_iter().map(|(k, (v, _))| (k, v)).collect();
+        Ok((program, labels))
+    } else {
+        Err(errors)
+    }
+}
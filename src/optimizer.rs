@@ -0,0 +1,254 @@
+//! Peephole optimizer, run between parsing and execution with `--optimize`.
+//!
+//! Programs that observe the instruction pointer are left untouched, as
+//! removing instructions would change what they print. They do it with an
+//! `ip` operand, by dumping the whole machine state with `dmp`, or through
+//! the return addresses `cal` pushes, which any stack read can see and any
+//! `ret` jumps to, pushed by `cal` or not.
+
+use crate::Instruction::*;
+use crate::{Instruction, Register, Register::*};
+use std::collections::BTreeMap;
+
+const GENERAL_REGISTERS: [Register; 6] = [A, B, C, D, E, F];
+
+/// Returns the (human numbered) instruction a jump goes to.
+pub fn jump_target(instr: &Instruction) -> Option<i32> {
+    match *instr {
+        Gto(i) | Jmp(i) | Cal(i) => Some(i),
+        _ => None,
+    }
+}
+
+pub fn with_target(instr: Instruction, target: i32) -> Instruction {
+    match instr {
+        Gto(_) => Gto(target),
+        Jmp(_) => Jmp(target),
+        Cal(_) => Cal(target),
+        other => other,
+    }
+}
+
+/// Returns the registers an instruction reads.
+pub fn reads(instr: &Instruction) -> Vec<Register> {
+    match *instr {
+        Mov(_, b) | Lod(_, b) => vec![b],
+        Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) | Sto(a, b) | Tee(a, b) | Tne(a, b)
+        | Tll(a, b) | Tmm(a, b) | Tel(a, b) | Tem(a, b) => vec![a, b],
+        Drg(r) | Prt(r) => vec![r],
+        Jmp(_) | Ast(_) => vec![Eq],
+        Pop | Ret => vec![Sp],
+        Cal(_) => vec![Sp, Ip],
+        Psh(_) => vec![Sp],
+        Dmp => vec![A, B, C, D, E, F, Ip, Sp, St, Eq],
+        Hlt | Gto(_) => vec![],
+    }
+}
+
+/// Returns the register an instruction explicitly writes.
+pub fn destination(instr: &Instruction) -> Option<Register> {
+    match *instr {
+        Mov(a, _) | Lod(a, _) | Add(a, _) | Sub(a, _) | Mul(a, _) | Div(a, _) => Some(a),
+        Tee(_, _) | Tne(_, _) | Tll(_, _) | Tmm(_, _) | Tel(_, _) | Tem(_, _) => Some(Eq),
+        _ => None,
+    }
+}
+
+pub fn mentions_ip(instr: &Instruction) -> bool {
+    let registers = match *instr {
+        Mov(a, b) | Lod(a, b) | Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) | Sto(a, b)
+        | Tee(a, b) | Tne(a, b) | Tll(a, b) | Tmm(a, b) | Tel(a, b) | Tem(a, b) => vec![a, b],
+        Drg(r) | Prt(r) => vec![r],
+        _ => vec![],
+    };
+    registers.contains(&Ip)
+}
+
+fn is_optimizable(program: &[Instruction]) -> bool {
+    program.iter().all(|instr| {
+        !matches!(instr, Dmp | Cal(_) | Ret)
+            && !mentions_ip(instr)
+            && jump_target(instr).is_none_or(|t| t >= 1 && t as usize <= program.len())
+    })
+}
+
+fn jump_targets(program: &[Instruction]) -> Vec<bool> {
+    let mut targeted = vec![false; program.len()];
+    for instr in program {
+        if let Some(t) = jump_target(instr) {
+            targeted[t as usize - 1] = true;
+        }
+    }
+    targeted
+}
+
+/// Returns which instructions can be reached from the first one.
+fn reachable(program: &[Instruction]) -> Vec<bool> {
+    let mut seen = vec![false; program.len()];
+    let mut pending = vec![0];
+
+    while let Some(i) = pending.pop() {
+        if i >= program.len() || seen[i] {
+            continue;
+        }
+        seen[i] = true;
+        match program[i] {
+            Hlt | Ret => {}
+            Gto(t) => pending.push(t as usize - 1),
+            Jmp(t) | Cal(t) => {
+                pending.push(t as usize - 1);
+                pending.push(i + 1);
+            }
+            _ => pending.push(i + 1),
+        }
+    }
+    seen
+}
+
+/// Retargets jumps landing on an unconditional `gto`, or conditional jumps
+/// landing on another `jmp`.
+fn thread_jumps(program: &mut [Instruction]) {
+    for i in 0..program.len() {
+        let mut target = match jump_target(&program[i]) {
+            Some(t) => t,
+            None => continue,
+        };
+        for _ in 0..program.len() {
+            match (&program[i], &program[target as usize - 1]) {
+                (_, Gto(next)) | (Jmp(_), Jmp(next)) if *next != target => target = *next,
+                _ => break,
+            }
+        }
+        program[i] = with_target(program[i].clone(), target);
+    }
+}
+
+/// Tells whether the value a `mov` writes to a general purpose register is
+/// overwritten before being read.
+fn is_dead_store(program: &[Instruction], targeted: &[bool], i: usize) -> bool {
+    let reg = match program[i] {
+        Mov(a, b) if a != b && GENERAL_REGISTERS.contains(&a) => a,
+        _ => return false,
+    };
+    for j in i + 1..program.len() {
+        let instr = program[j].clone();
+        if targeted[j] || jump_target(&instr).is_some() || instr == Hlt || instr == Ret {
+            return false;
+        }
+        if reads(&instr).contains(&reg) {
+            return false;
+        }
+        if destination(&instr) == Some(reg) {
+            return true;
+        }
+    }
+    false
+}
+
+fn removable(program: &[Instruction]) -> Vec<bool> {
+    let targeted = jump_targets(program);
+    let reachable = reachable(program);
+    let stack_is_raw = program
+        .iter()
+        .any(|instr| matches!(instr, Lod(_, _)) || matches!(destination(instr), Some(Sp) | Some(St)));
+
+    let mut remove = vec![false; program.len()];
+    for i in 0..program.len() {
+        if remove[i] {
+            continue;
+        }
+        remove[i] = match program[i] {
+            _ if !reachable[i] => true,
+            Mov(a, b) if a == b => true,
+            Gto(t) | Jmp(t) if t as usize == i + 2 => true,
+            Psh(_) if !stack_is_raw && program.get(i + 1) == Some(&Pop) && !targeted[i + 1] => {
+                remove[i + 1] = true;
+                true
+            }
+            _ => is_dead_store(program, &targeted, i),
+        };
+    }
+    remove
+}
+
+/// Removes the flagged instructions, remapping jumps and labels that pointed
+/// to them to the next kept instruction.
+pub fn compact(
+    program: &[Instruction],
+    labels: &BTreeMap<String, i32>,
+    remove: &[bool],
+) -> (Vec<Instruction>, BTreeMap<String, i32>) {
+    // Index, in the compacted program, of the first kept instruction
+    // starting from each instruction of the current one
+    let len = program.len();
+    let mut index = vec![0; len + 1];
+    let mut kept = 0;
+    for (i, removed) in remove.iter().enumerate() {
+        index[i] = kept;
+        if !removed {
+            kept += 1;
+        }
+    }
+    index[len] = kept;
+    let remap = |t: i32| index[(t as usize - 1).min(len)] as i32 + 1;
+
+    let program = program
+        .iter()
+        .zip(remove)
+        .filter(|(_, r)| !**r)
+        .map(|(instr, _)| match jump_target(instr) {
+            Some(t) => with_target(instr.clone(), remap(t)),
+            None => instr.clone(),
+        })
+        .collect();
+    let mut labels = labels.clone();
+    for value in labels.values_mut() {
+        if *value >= 1 {
+            *value = remap(*value);
+        }
+    }
+    (program, labels)
+}
+
+/// Rewrites known patterns, removes no-ops and unreachable instructions, and
+/// remaps jumps and labels accordingly.
+pub fn optimize(
+    program: &[Instruction],
+    labels: &BTreeMap<String, i32>,
+) -> (Vec<Instruction>, BTreeMap<String, i32>) {
+    let mut program = program.to_vec();
+    let mut labels = labels.clone();
+    if !is_optimizable(&program) {
+        return (program, labels);
+    }
+
+    loop {
+        thread_jumps(&mut program);
+        let remove = removable(&program);
+        if !remove.contains(&true) {
+            break;
+        }
+        let (compacted, remapped) = compact(&program, &labels, &remove);
+        program = compacted;
+        labels = remapped;
+    }
+
+    (program, labels)
+}
+
+/// Replaces the `ast` instructions with no-ops, for release builds. The
+/// other instructions keep their numbers, which programs can see through
+/// `ip` and return addresses.
+pub fn strip_assertions(
+    program: &[Instruction],
+    labels: &BTreeMap<String, i32>,
+) -> (Vec<Instruction>, BTreeMap<String, i32>) {
+    let stripped = program
+        .iter()
+        .map(|instr| match instr {
+            Ast(_) => Mov(A, A),
+            other => other.clone(),
+        })
+        .collect();
+    (stripped, labels.clone())
+}
@@ -1,41 +1,239 @@
-use crate::{Instruction, Instruction::*, Register::*};
-use std::collections::BTreeMap;
-use std::fs;
-
-fn error(line: usize, whr: &str, message: &str) {
-  eprintln!("{} | {}", line, whr);
-  eprintln!("^^^^^^^^^^^^^^^^^^^^");
-  eprintln!("{}\n", message);
+use crate::object::{Object, Relocation, Target};
+use crate::stdlib;
+use crate::{Instruction, Instruction::*, Register, Register::*};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::rc::Rc;
+
+/// A problem found while parsing a line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+  /// Number of the line, counted from 1.
+  pub line: usize,
+  pub text: String,
+  pub message: String,
+}
+
+fn error(errors: &mut Vec<ParseError>, line: usize, whr: &str, message: &str) {
+  errors.push(ParseError {
+    line,
+    text: whr.to_owned(),
+    message: message.to_owned(),
+  });
+}
+
+pub fn register(raw: &str) -> Option<Register> {
+  Some(match raw {
+    "a" => A,
+    "b" => B,
+    "c" => C,
+    "d" => D,
+    "e" => E,
+    "f" => F,
+    "ip" => Ip,
+    "sp" => Sp,
+    "st" => St,
+    "eq" => Eq,
+    _ => return None,
+  })
+}
+
+/// Returns the message of the table equal to `text`, adding it when there is
+/// none, so that the assertions of a program share their equal messages.
+pub fn intern(messages: &mut BTreeSet<Rc<str>>, text: &str) -> Rc<str> {
+  match messages.get(text) {
+    Some(message) => Rc::clone(message),
+    None => {
+      let message: Rc<str> = Rc::from(text);
+      messages.insert(Rc::clone(&message));
+      message
+    }
+  }
+}
+
+/// Returns the message of an `ast` instruction, between the double quotes
+/// following the mnemonic.
+pub fn assertion_message(line: &str) -> Option<&str> {
+  let rest = line.strip_prefix("ast")?.trim_start().strip_prefix('"')?;
+  rest.find('"').map(|end| &rest[..end])
+}
+
+/// Prints instructions in the syntax they are parsed from, jumps and calls
+/// with their (human numbered) target instruction.
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = |r: &Register| crate::reg_name(*r as i32).to_lowercase();
+    match self {
+      Psh(i) => write!(f, "psh {}", i),
+      Gto(i) => write!(f, "gto {}", i),
+      Jmp(i) => write!(f, "jmp {}", i),
+      Cal(i) => write!(f, "cal {}", i),
+      Pop => f.write_str("pop"),
+      Ret => f.write_str("ret"),
+      Hlt => f.write_str("hlt"),
+      Dmp => f.write_str("dmp"),
+      Drg(r) => write!(f, "drg {}", name(r)),
+      Prt(r) => write!(f, "prt {}", name(r)),
+      Add(a, b) => write!(f, "add {} {}", name(a), name(b)),
+      Sub(a, b) => write!(f, "sub {} {}", name(a), name(b)),
+      Mul(a, b) => write!(f, "mul {} {}", name(a), name(b)),
+      Div(a, b) => write!(f, "div {} {}", name(a), name(b)),
+      Mov(a, b) => write!(f, "mov {} {}", name(a), name(b)),
+      Tee(a, b) => write!(f, "tee {} {}", name(a), name(b)),
+      Tne(a, b) => write!(f, "tne {} {}", name(a), name(b)),
+      Tll(a, b) => write!(f, "tll {} {}", name(a), name(b)),
+      Tmm(a, b) => write!(f, "tmm {} {}", name(a), name(b)),
+      Tel(a, b) => write!(f, "tel {} {}", name(a), name(b)),
+      Tem(a, b) => write!(f, "tem {} {}", name(a), name(b)),
+      Lod(a, b) => write!(f, "lod {} {}", name(a), name(b)),
+      Sto(a, b) => write!(f, "sto {} {}", name(a), name(b)),
+      Ast(message) => write!(f, "ast \"{}\"", message),
+    }
+  }
+}
+
+/// Appends the bundled libraries pulled by `.include` directives after the
+/// program, behind a `hlt` so that they only run when called. Also returns
+/// the name of each library with the number of the line before its first one.
+fn with_includes(code: &str) -> (String, Vec<(usize, String)>) {
+  let mut included: Vec<&str> = vec![];
+  let mut pending = vec![code];
+  let mut libraries = String::new();
+  let mut starts = vec![];
+  let hlt = code.matches('\n').count() + 2;
+
+  while let Some(source) = pending.pop() {
+    for line in source.split('\n') {
+      let splited = line.split(' ').collect::<Vec<&str>>();
+      if splited[0] != ".include" || splited.len() < 2 || included.contains(&splited[1]) {
+        continue;
+      }
+      if let Some(library) = stdlib::lookup(splited[1]) {
+        included.push(splited[1]);
+        starts.push((hlt + libraries.matches('\n').count(), splited[1].to_owned()));
+        libraries.push('\n');
+        libraries.push_str(library);
+        pending.push(library);
+      }
+    }
+  }
+
+  if included.is_empty() {
+    (code.to_owned(), starts)
+  } else {
+    (format!("{}\nhlt{}", code, libraries), starts)
+  }
+}
+
+/// Describes where a line of the code with its includes expanded comes from.
+fn location(ln: usize, libraries: &[(usize, String)]) -> String {
+  match libraries.iter().rev().find(|(before, _)| *before < ln) {
+    Some((before, name)) => format!("line {} of {}", ln - before, name),
+    None => format!("line {}", ln),
+  }
+}
+
+/// Tells whether a source line assembles to an instruction.
+pub fn produces_instruction(line: &str) -> bool {
+  !(line.is_empty() || line.starts_with(';') || line.starts_with(':') || line.starts_with('.'))
+}
+
+/// Returns the number, counted in the code with its includes expanded, and
+/// the text of the line each instruction comes from. The `hlt` ending every
+/// program has no line.
+pub fn source_lines(code: &str) -> Vec<(usize, String)> {
+  with_includes(code)
+    .0
+    .split('\n')
+    .enumerate()
+    .filter(|(_, line)| produces_instruction(line))
+    .map(|(i, line)| (i + 1, line.trim_end().to_owned()))
+    .collect()
 }
 
 pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String, i32>) {
+  let object = parse_module(code, quit, false);
+  (object.code, object.labels)
+}
+
+/// Parses a source file into a module.
+///
+/// When `relocatable` is set, references to labels that are not defined in the
+/// file are recorded as imports instead of being reported as errors.
+pub fn parse_module(code: &str, quit: bool, relocatable: bool) -> Object {
+  let (object, errors) = parse(code, relocatable);
+  for e in &errors {
+    eprintln!("{} | {}", e.line, e.text);
+    eprintln!("^^^^^^^^^^^^^^^^^^^^");
+    eprintln!("{}\n", e.message);
+  }
+  if !errors.is_empty() {
+    eprintln!("Aborting due to previous errors");
+    if quit {
+      std::process::exit(-7);
+    }
+  }
+  object
+}
+
+/// Returns the problems found in a source file, without reporting them.
+pub fn diagnostics(code: &str) -> Vec<ParseError> {
+  parse(code, false).1
+}
+
+fn parse(code: &str, relocatable: bool) -> (Object, Vec<ParseError>) {
   let mut instrs: Vec<Instruction> = vec![];
   let mut labels: BTreeMap<String, i32> = BTreeMap::new();
-  let mut had_error = false;
+  let mut exports: Vec<String> = vec![];
+  let mut imports: Vec<String> = vec![];
+  let mut relocations: Vec<Relocation> = vec![];
+  let mut errors: Vec<ParseError> = vec![];
+  let mut messages: BTreeSet<Rc<str>> = BTreeSet::new();
 
+  let (code, libraries) = with_includes(code);
   let lines = code.split('\n').collect::<Vec<&str>>();
   let mut ln = 0usize;
+  let mut count = 0;
+  let mut defined: BTreeMap<&str, usize> = BTreeMap::new();
 
-  for line in &lines {
-    ln += 1;
+  for (i, line) in lines.iter().enumerate() {
     let splited = line.split(' ').collect::<Vec<&str>>();
-    if line.starts_with(":") {
-      // Labels
-      labels.insert(splited[0].to_owned(), (ln) as i32); // Minus two because of human notation and Instruction launching (see main.rs)
+    if line.starts_with(':') {
+      if let Some(&first) = defined.get(splited[0]) {
+        // Reported on the program's own line when a library clashes with it
+        let in_library = libraries.iter().any(|(before, _)| *before < i + 1);
+        let at = if in_library { first } else { i + 1 };
+        error(
+          &mut errors,
+          at,
+          lines[at - 1],
+          &format!(
+            "Symbol error: label {} is defined twice, on {} and {}",
+            splited[0],
+            location(first, &libraries),
+            location(i + 1, &libraries)
+          ),
+        );
+        continue;
+      }
+      defined.insert(splited[0], i + 1);
+      // Labels point to the (human numbered) instruction that follows them
+      labels.insert(splited[0].to_owned(), count + 1);
       continue;
     }
+    if produces_instruction(line) {
+      count += 1;
+    }
   }
 
-  ln = 0;
-
   for line in lines {
     ln += 1;
     let splited = line.split(' ').collect::<Vec<&str>>();
 
-    if line.starts_with(";") {
+    if line.starts_with(';') {
       continue;
     }
-    if line.starts_with(":") {
+    if line.starts_with(':') {
       continue;
     }
 
@@ -43,16 +241,53 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       continue;
     }
 
+    if line.starts_with('.') {
+      match splited[0] {
+        ".export" => {
+          if splited.len() < 2 {
+            error(&mut errors, ln, line, "Syntax error: valid syntax: `.export <label>`");
+            continue;
+          }
+          if !labels.contains_key(splited[1]) {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Symbol error: cannot export undefined label {}", splited[1]),
+            );
+            continue;
+          }
+          exports.push(splited[1].to_owned());
+        }
+        ".include" => {
+          if splited.len() < 2 {
+            error(&mut errors, ln, line, "Syntax error: valid syntax: `.include <std/library.vm>`");
+          } else if stdlib::lookup(splited[1]).is_none() {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Include error: no bundled library named {}", splited[1]),
+            );
+          }
+        }
+        x => {
+          error(&mut errors, ln, line, &format!("Error: Unknown directive: {}", x));
+        }
+      }
+      continue;
+    }
+
     match splited[0] {
       "dmp" => instrs.push(Dmp),
       "gto" => {
         if splited.len() < 2 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `gto <label|instruction>``",
           );
-          had_error = true;
           continue;
         }
 
@@ -61,58 +296,101 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
         let num = match raw.parse::<i32>() {
           Ok(n) => n,
           Err(_) => {
-            println!("{:?}", labels);
             if labels.contains_key(raw) {
               labels[raw]
+            } else if relocatable && raw.starts_with(':') {
+              if !imports.iter().any(|i| i == raw) {
+                imports.push(raw.to_owned());
+              }
+              relocations.push(Relocation {
+                offset: instrs.len(),
+                target: Target::Symbol(raw.to_owned()),
+              });
+              instrs.push(Gto(0));
+              continue;
             } else {
               error(
+                &mut errors,
                 ln,
                 line,
                 "Type error: gto has to take a valid integer or label !",
               );
-              had_error = true;
               continue;
             }
           }
         };
 
+        relocations.push(Relocation {
+          offset: instrs.len(),
+          target: Target::Local,
+        });
         instrs.push(Gto(num));
       }
-      "prt" => {
+      "cal" => {
         if splited.len() < 2 {
-          error(ln, line, "Syntax error: valid syntax: `prt <register>`");
-          had_error = true;
+          error(
+            &mut errors,
+            ln,
+            line,
+            "Syntax error: valid syntax: `cal <label|instruction>`",
+          );
           continue;
         }
 
         let raw = splited[1];
 
-        let reg = if raw == "a" {
-          A
-        } else if raw == "b" {
-          B
-        } else if raw == "c" {
-          C
-        } else if raw == "e" {
-          D
-        } else if raw == "d" {
-          E
-        } else if raw == "f" {
-          F
-        } else if raw == "ip" {
-          Ip
-        } else if raw == "sp" {
-          Sp
-        } else if raw == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw),
-          );
-          had_error = true;
+        let num = match raw.parse::<i32>() {
+          Ok(n) => n,
+          Err(_) => {
+            if labels.contains_key(raw) {
+              labels[raw]
+            } else if relocatable && raw.starts_with(':') {
+              if !imports.iter().any(|i| i == raw) {
+                imports.push(raw.to_owned());
+              }
+              relocations.push(Relocation {
+                offset: instrs.len(),
+                target: Target::Symbol(raw.to_owned()),
+              });
+              instrs.push(Cal(0));
+              continue;
+            } else {
+              error(
+                &mut errors,
+                ln,
+                line,
+                "Type error: cal has to take a valid integer or label !",
+              );
+              continue;
+            }
+          }
+        };
+
+        relocations.push(Relocation {
+          offset: instrs.len(),
+          target: Target::Local,
+        });
+        instrs.push(Cal(num));
+      }
+      "prt" => {
+        if splited.len() < 2 {
+          error(&mut errors, ln, line, "Syntax error: valid syntax: `prt <register>`");
           continue;
+        }
+
+        let raw = splited[1];
+
+        let reg = match register(raw) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw),
+            );
+            continue;
+          }
         };
 
         instrs.push(Prt(reg))
@@ -120,71 +398,41 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "tee" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `tee <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Tee(reg_a, reg_b));
@@ -192,71 +440,41 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "tne" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `tne <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Tne(reg_a, reg_b));
@@ -264,71 +482,41 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "tll" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `tll <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Tll(reg_a, reg_b));
@@ -336,71 +524,41 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "tmm" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `tmm <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Tmm(reg_a, reg_b));
@@ -408,71 +566,41 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "tel" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `tel <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Tel(reg_a, reg_b));
@@ -480,106 +608,87 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "tem" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `tem <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Tem(reg_a, reg_b));
       }
       "jmp" => {
         if splited.len() < 2 {
-          error(ln, line, "Syntax error: valid syntax: `jmp <instruction>`");
-          had_error = true;
+          error(&mut errors, ln, line, "Syntax error: valid syntax: `jmp <instruction>`");
           continue;
         }
 
         let instruction = match splited[1].parse::<i32>() {
           Ok(i) => i,
           Err(_e) => {
-            println!("{:?}", labels);
             if labels.contains_key(splited[1]) {
               labels[splited[1]]
+            } else if relocatable && splited[1].starts_with(':') {
+              if !imports.iter().any(|i| i == splited[1]) {
+                imports.push(splited[1].to_owned());
+              }
+              relocations.push(Relocation {
+                offset: instrs.len(),
+                target: Target::Symbol(splited[1].to_owned()),
+              });
+              instrs.push(Jmp(0));
+              continue;
             } else {
               error(
+                &mut errors,
                 ln,
                 line,
                 "Type error: gto has to take a valid integer or label !",
               );
-              had_error = true;
               continue;
             }
           }
         };
 
+        relocations.push(Relocation {
+          offset: instrs.len(),
+          target: Target::Local,
+        });
         instrs.push(Jmp(instruction));
       }
       "psh" => {
         if splited.len() < 2 {
-          error(ln, line, "Syntax error: valid syntax: `psh <integer>`");
-          had_error = true;
+          error(&mut errors, ln, line, "Syntax error: valid syntax: `psh <integer>`");
           continue;
         }
 
@@ -587,11 +696,11 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
           Ok(i) => i,
           Err(_e) => {
             error(
+              &mut errors,
               ln,
               line,
               &format!("Type error : {} is not a valid integer", splited[1]),
             );
-            had_error = true;
             continue;
           }
         };
@@ -601,144 +710,170 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "mov" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `mov <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
+        };
+
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
+        };
+
+        instrs.push(Mov(reg_a, reg_b));
+      }
+
+      "lod" => {
+        if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_a),
+            "Syntax error: valid syntax: `lod <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
+        }
+
+        let raw_a = splited[1];
+        let raw_b = splited[2];
+
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
+        };
+
+        instrs.push(Lod(reg_a, reg_b));
+      }
+
+      "sto" => {
+        if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_b),
+            "Syntax error: valid syntax: `sto <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
+        }
+
+        let raw_a = splited[1];
+        let raw_b = splited[2];
+
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        instrs.push(Mov(reg_a, reg_b));
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
+        };
+
+        instrs.push(Sto(reg_a, reg_b));
       }
 
       "add" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `mov <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Add(reg_a, reg_b));
@@ -746,71 +881,41 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "sub" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `mov <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Sub(reg_a, reg_b));
@@ -818,71 +923,41 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "mul" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `mov <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Mul(reg_a, reg_b));
@@ -890,135 +965,92 @@ pub fn parse_code(code: &str, quit: bool) -> (Vec<Instruction>, BTreeMap<String,
       "div" => {
         if splited.len() < 3 {
           error(
+            &mut errors,
             ln,
             line,
             "Syntax error: valid syntax: `mov <register_a> <register_b>`",
           );
-          had_error = true;
           continue;
         }
 
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            continue;
+          }
         };
 
         instrs.push(Div(reg_a, reg_b));
       }
       "pop" => instrs.push(Pop),
+      "ret" => instrs.push(Ret),
       "drg" => {
         if splited.len() < 2 {
-          error(ln, line, "Syntax error: valid syntax: `drg <register>`");
-          had_error = true;
+          error(&mut errors, ln, line, "Syntax error: valid syntax: `drg <register>`");
           continue;
         }
 
         let raw = splited[1];
 
-        let reg = if raw == "a" {
-          A
-        } else if raw == "b" {
-          B
-        } else if raw == "c" {
-          C
-        } else if raw == "e" {
-          D
-        } else if raw == "d" {
-          E
-        } else if raw == "f" {
-          F
-        } else if raw == "ip" {
-          Ip
-        } else if raw == "sp" {
-          Sp
-        } else if raw == "st" {
-          St
-        } else if raw == "eq" {
-          Eq
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw),
-          );
-          had_error = true;
-          continue;
+        let reg = match register(raw) {
+          Some(r) => r,
+          None => {
+            error(
+              &mut errors,
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw),
+            );
+            continue;
+          }
         };
 
         instrs.push(Drg(reg));
       }
-      "hlt" => {
-        instrs.push(Hlt);
-        return (instrs, labels);
-      }
+      "hlt" => instrs.push(Hlt),
+      "ast" => match assertion_message(line) {
+        Some(message) => instrs.push(Ast(intern(&mut messages, message))),
+        None => {
+          error(&mut errors, ln, line, "Syntax error: valid syntax: `ast \"<message>\"`");
+          continue;
+        }
+      },
 
       x => {
-        error(ln, line, &format!("Error: Unexpected token: {}", x));
-        had_error = true;
+        error(&mut errors, ln, line, &format!("Error: Unexpected token: {}", x));
         continue;
       }
     }
   }
-  if had_error {
-    eprintln!("Aborting due to previous errors");
-    if quit {
-      std::process::exit(-7);
-    }
-  }
   instrs.push(Hlt);
-  (instrs, labels)
+  let object = Object {
+    code: instrs,
+    labels,
+    exports,
+    imports,
+    relocations,
+  };
+  (object, errors)
 }
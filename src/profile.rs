@@ -0,0 +1,220 @@
+//! Instruction-level profiler, behind `wlvm profile`.
+//!
+//! Every step is counted against the instruction about to run and against the
+//! call stack it runs under. Counts are then added up by source line and by
+//! region, a region going from a label to the next one.
+//!
+//! Folded stacks, as read by flamegraph tools, follow the calls when the
+//! program has subroutines, and the regions otherwise.
+
+use crate::vm::Vm;
+use crate::Instruction::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub struct Profile {
+    /// Number of times each instruction ran.
+    pub counts: Vec<u64>,
+    /// Steps run under each call stack, as the `cal` instructions entered.
+    pub stacks: BTreeMap<Vec<usize>, u64>,
+}
+
+/// Instructions from a label up to the next one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    /// The label, or `main` for the instructions before the first one.
+    pub name: String,
+    /// Index of the first instruction.
+    pub start: usize,
+    /// Index past the last instruction.
+    pub end: usize,
+}
+
+/// Splits a program into regions, at its labels.
+pub fn regions(vm: &Vm) -> Vec<Region> {
+    let mut starts: BTreeMap<usize, &str> = BTreeMap::new();
+    for (label, value) in &vm.labels {
+        if *value >= 1 && (*value as usize) <= vm.program.len() {
+            starts.entry(*value as usize - 1).or_insert(label);
+        }
+    }
+    let mut regions = vec![];
+    if starts.keys().next() != Some(&0) && !vm.program.is_empty() {
+        regions.push(Region {
+            name: "main".to_owned(),
+            start: 0,
+            end: 0,
+        });
+    }
+    for (start, label) in starts {
+        if let Some(last) = regions.last_mut() {
+            last.end = start;
+        }
+        regions.push(Region {
+            name: label.to_owned(),
+            start,
+            end: 0,
+        });
+    }
+    if let Some(last) = regions.last_mut() {
+        last.end = vm.program.len();
+    }
+    regions
+}
+
+fn share(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+/// Sorts counts from the hottest, dropping the ones that never ran.
+fn hottest<T>(mut counts: Vec<(T, u64)>, top: usize) -> Vec<(T, u64)> {
+    counts.retain(|(_, count)| *count > 0);
+    // The sort is stable, so ties stay in program order
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    counts.truncate(top);
+    counts
+}
+
+impl Profile {
+    pub fn new(len: usize) -> Profile {
+        Profile {
+            counts: vec![0; len],
+            stacks: BTreeMap::new(),
+        }
+    }
+
+    /// Counts the instruction the machine is about to run.
+    pub fn record(&mut self, vm: &Vm) {
+        if vm.current().is_none() {
+            return;
+        }
+        self.counts[vm.ip()] += 1;
+        match self.stacks.get_mut(&vm.calls) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(vm.calls.clone(), 1);
+            }
+        }
+    }
+
+    /// Takes back the count of an instruction that did not run after all,
+    /// the machine having been stopped by a limit.
+    pub fn discard(&mut self, vm: &Vm) {
+        if vm.current().is_none() {
+            return;
+        }
+        self.counts[vm.ip()] -= 1;
+        if let Some(count) = self.stacks.get_mut(&vm.calls) {
+            *count -= 1;
+        }
+    }
+
+    /// Number of instructions run.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Counts by source line, given the line of each instruction as returned
+    /// by `parser::source_lines`.
+    pub fn by_line(&self, lines: &[(usize, String)]) -> Vec<(usize, u64)> {
+        let mut counts: BTreeMap<usize, u64> = BTreeMap::new();
+        for (count, (line, _)) in self.counts.iter().zip(lines) {
+            *counts.entry(*line).or_insert(0) += count;
+        }
+        counts.into_iter().collect()
+    }
+
+    /// Counts by region.
+    pub fn by_region(&self, vm: &Vm) -> Vec<(Region, u64)> {
+        regions(vm)
+            .into_iter()
+            .map(|region| {
+                let count = self.counts[region.start..region.end].iter().sum();
+                (region, count)
+            })
+            .collect()
+    }
+
+    /// Prints the `top` hottest instructions, lines and regions.
+    pub fn report(&self, vm: &Vm, lines: &[(usize, String)], top: usize) -> String {
+        let total = self.total();
+        let mut report = format!("{} instructions run\n", total);
+
+        report.push_str("\nHot instructions:\n");
+        let counts = self.counts.iter().copied().enumerate().collect();
+        for (index, count) in hottest(counts, top) {
+            let _ = write!(
+                report,
+                "{:>10} {:>5.1}%  instruction {}",
+                count,
+                share(count, total),
+                index + 1
+            );
+            if let Some((line, _)) = lines.get(index) {
+                let _ = write!(report, " (line {})", line);
+            }
+            let _ = writeln!(report, ": {}", vm.program[index]);
+        }
+
+        if !lines.is_empty() {
+            report.push_str("\nHot lines:\n");
+            let text: BTreeMap<usize, &str> = lines
+                .iter()
+                .map(|(line, text)| (*line, text.trim()))
+                .collect();
+            for (line, count) in hottest(self.by_line(lines), top) {
+                let _ = writeln!(
+                    report,
+                    "{:>10} {:>5.1}%  line {}: {}",
+                    count,
+                    share(count, total),
+                    line,
+                    text[&line]
+                );
+            }
+        }
+
+        report.push_str("\nHot regions:\n");
+        for (region, count) in hottest(self.by_region(vm), top) {
+            let _ = writeln!(
+                report,
+                "{:>10} {:>5.1}%  {} (instructions {}-{})",
+                count,
+                share(count, total),
+                region.name,
+                region.start + 1,
+                region.end
+            );
+        }
+        report
+    }
+
+    /// Prints the profile as folded stacks, one `frame;frame count` per line.
+    pub fn folded(&self, vm: &Vm) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        if vm.program.iter().any(|instr| matches!(instr, Cal(_))) {
+            for (calls, count) in &self.stacks {
+                let mut frames = vec!["main".to_owned()];
+                frames.extend(calls.iter().map(|call| vm.subroutine(*call)));
+                *stacks.entry(frames.join(";")).or_insert(0) += count;
+            }
+        } else {
+            for (region, count) in self.by_region(vm) {
+                let stack = match region.name.as_str() {
+                    "main" => "main".to_owned(),
+                    name => format!("main;{}", name),
+                };
+                *stacks.entry(stack).or_insert(0) += count;
+            }
+        }
+        let mut folded = String::new();
+        for (stack, count) in stacks.into_iter().filter(|(_, count)| *count > 0) {
+            let _ = writeln!(folded, "{} {}", stack, count);
+        }
+        folded
+    }
+}
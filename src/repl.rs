@@ -0,0 +1,238 @@
+//! Interactive REPL, started by `wlvm` without arguments.
+//!
+//! Snippets are added to the source of the session, which is parsed again as
+//! a whole so that labels and jumps work across snippets. The machine then
+//! runs from where it stopped until it reaches the end of the code entered so
+//! far. A snippet that does not parse, or fails while running, is undone.
+//!
+//! Lines are read with the line editor of `editor.rs`, completing mnemonics,
+//! registers, labels and meta-commands.
+
+use crate::editor::{history_path, Editor};
+use crate::history::Checkpoint;
+use crate::parser::{diagnostics, parse_code, produces_instruction};
+use crate::vm::Vm;
+use crate::{debugger, gas, object, reg_name, REGISTERS};
+
+/// Steps a snippet may run, so that an endless loop gives the prompt back.
+const SNIPPET_STEPS: u64 = 10_000_000;
+
+const HELP: &str = "\
+<instructions>      Runs the instructions, labels starting a block that ends
+                    at an empty line
+:regs               Prints the registers
+:stack              Prints the stack
+:load <file>        Runs the code of a file
+:save <file>        Saves the code entered so far to a file
+:undo               Takes back the last snippet and what it did
+:reset              Starts over with an empty program
+:help               Prints this message
+:quit | q           Quits the REPL";
+
+const COMMANDS: &[&str] = &[
+    "regs", "stack", "load", "save", "undo", "reset", "help", "quit",
+];
+
+pub struct Repl {
+    /// Code of the snippets entered so far, one per line.
+    source: String,
+    vm: Vm,
+    /// Length of the source and state of the machine before each snippet.
+    undo: Vec<(usize, Checkpoint)>,
+    /// Lines of the block being entered.
+    block: Vec<String>,
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        let (program, labels) = parse_code("", false);
+        Repl {
+            source: String::new(),
+            vm: Vm::new(program, labels),
+            undo: vec![],
+            block: vec![],
+        }
+    }
+
+    /// Number of instructions entered, included libraries coming after them.
+    fn own(&self) -> usize {
+        self.source
+            .split('\n')
+            .filter(|line| produces_instruction(line))
+            .count()
+    }
+
+    /// Loads the program of the current source, keeping the state of the
+    /// machine as given.
+    fn reload(&mut self, state: &Checkpoint) {
+        let (program, labels) = parse_code(&self.source, false);
+        self.vm = Vm::new(program, labels);
+        state.restore(&mut self.vm);
+    }
+
+    /// Adds a snippet to the program and runs it.
+    pub fn eval(&mut self, snippet: &str) -> Result<(), String> {
+        let before = self.source.split('\n').count() - 1;
+        let mut source = self.source.clone();
+        for line in snippet.lines() {
+            source.push_str(line.trim());
+            source.push('\n');
+        }
+        let errors = diagnostics(&source);
+        if !errors.is_empty() {
+            let errors = errors
+                .iter()
+                .map(|e| format!("{} (line {}: {})", e.message, e.line - before, e.text))
+                .collect::<Vec<String>>();
+            return Err(errors.join("\n"));
+        }
+
+        let state = Checkpoint::capture(&self.vm);
+        self.undo.push((self.source.len(), state.clone()));
+        self.source = source;
+        self.reload(&state);
+        let own = self.own();
+        self.vm.running = true;
+        self.vm.max_steps = Some(self.vm.steps + SNIPPET_STEPS);
+        while self.vm.running && self.vm.ip() != own {
+            let ip = self.vm.ip();
+            if let Err(e) = self.vm.step() {
+                self.take_back();
+                return Err(format!(
+                    "Error: {} at instruction {}, the snippet was undone",
+                    e,
+                    ip + 1
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes back the last snippet, telling whether there was one.
+    fn take_back(&mut self) -> bool {
+        match self.undo.pop() {
+            Some((len, state)) => {
+                self.source.truncate(len);
+                self.reload(&state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn print_registers(&self) {
+        for (i, value) in self.vm.registers.iter().enumerate() {
+            println!("{} = {}", reg_name(i as i32).to_lowercase(), value);
+        }
+    }
+
+    fn report(result: Result<(), String>) {
+        if let Err(e) = result {
+            println!("{}", e);
+        }
+    }
+
+    /// Runs a meta-command, returning false to quit.
+    fn meta(&mut self, command: &str, argument: &str) -> bool {
+        match command {
+            "regs" => self.print_registers(),
+            "stack" => debugger::print_stack(&self.vm),
+            "load" => match std::fs::read(argument) {
+                Ok(bytes) if object::is_image(&bytes) || object::is_snapshot(&bytes) => {
+                    println!("Error: {} is not a source file", argument)
+                }
+                Ok(bytes) => Repl::report(self.eval(&String::from_utf8_lossy(&bytes))),
+                Err(e) => println!("Error: {}: {}", argument, e),
+            },
+            "save" => match std::fs::write(argument, &self.source) {
+                Ok(()) => println!(
+                    "Saved {} lines to {}",
+                    self.source.lines().count(),
+                    argument
+                ),
+                Err(e) => println!("Error: failed to write {}: {}", argument, e),
+            },
+            "undo" => {
+                if !self.take_back() {
+                    println!("Nothing to undo");
+                }
+            }
+            "reset" => *self = Repl::new(),
+            "help" => println!("{}", HELP),
+            "quit" => return false,
+            _ => unreachable!(),
+        }
+        true
+    }
+
+    /// Handles a line of input, returning false to quit.
+    pub fn command(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        if !self.block.is_empty() {
+            if line.is_empty() {
+                let block = self.block.join("\n");
+                self.block.clear();
+                Repl::report(self.eval(&block));
+            } else {
+                self.block.push(line.to_owned());
+            }
+            return true;
+        }
+        let mut words = line.splitn(2, ' ');
+        let first = words.next().unwrap_or("");
+        let argument = words.next().unwrap_or("").trim();
+        if line == "q" || line == "Q" {
+            return false;
+        }
+        match first.strip_prefix(':') {
+            Some(command) if COMMANDS.contains(&command) => return self.meta(command, argument),
+            // A label starts a block
+            Some(_) => self.block.push(line.to_owned()),
+            None if line.is_empty() => {}
+            None => Repl::report(self.eval(line)),
+        }
+        true
+    }
+
+    /// Words that may follow `before` on a line.
+    fn completions(&self, before: &str) -> Vec<String> {
+        let words = before.split_whitespace().collect::<Vec<&str>>();
+        let labels = self.vm.labels.keys().cloned();
+        if words.is_empty() || (words.len() == 1 && !before.ends_with(' ')) {
+            let commands = COMMANDS.iter().map(|c| format!(":{}", c));
+            let mnemonics = gas::MNEMONICS.iter().map(|m| m.to_string());
+            return mnemonics.chain(commands).chain(labels).collect();
+        }
+        match words[0] {
+            "jmp" | "gto" | "cal" => labels.collect(),
+            _ if words[0].starts_with(':') => vec![],
+            _ => REGISTERS
+                .iter()
+                .map(|r| reg_name(*r as i32).to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Reads lines until `:quit` or the end of the input.
+    pub fn run(&mut self) {
+        println!(
+            "wlvm REPL version {} by {}",
+            env!("CARGO_PKG_VERSION"),
+            env!("CARGO_PKG_AUTHORS")
+        );
+        println!("Type :help for a list of commands, :quit to quit");
+        let mut editor = Editor::new(history_path());
+        loop {
+            let prompt = if self.block.is_empty() {
+                format!("(wlvm:{}) ", self.vm.ip() + 1)
+            } else {
+                "...       ".to_owned()
+            };
+            let line = editor.read_line(&prompt, &|before| self.completions(before));
+            match line {
+                Ok(Some(line)) if self.command(&line) => {}
+                _ => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Recording and replay of runs, behind `--record` and `--replay`.
+//!
+//! No instruction reads input bytes, the clock or random numbers: there is no
+//! input instruction and `std/random.vm` is seeded by the program. The only
+//! thing a run depends on besides its program is thus the clock, read by the
+//! interpreter when running with a time limit. Recording notes the step the time
+//! limit was hit at, and replay stops the program at that same step whatever
+//! the clock says. What the program prints is recorded too, with the step
+//! printing it, and replay checks that it prints the same things at the same
+//! steps to tell when it diverges from the recording.
+
+use std::fmt::Write;
+
+const HEADER: &str = "wlvm-record 1";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The program printed something.
+    Output { step: u64, bytes: Vec<u8> },
+    /// The time limit was hit before running a step.
+    Timeout { step: u64 },
+    /// The program stopped after running `steps` instructions.
+    End { steps: u64 },
+}
+
+impl Event {
+    fn describe(&self) -> String {
+        match self {
+            Event::Output { step, bytes } => {
+                format!(
+                    "{:?} printed at step {}",
+                    String::from_utf8_lossy(bytes),
+                    step + 1
+                )
+            }
+            Event::Timeout { step } => format!("time limit hit before step {}", step + 1),
+            Event::End { steps } => format!("program stopped after {} steps", steps),
+        }
+    }
+}
+
+/// Identifies a program, so that replaying another one is refused.
+pub fn fingerprint(program: &[crate::Instruction]) -> u64 {
+    // FNV-1a over the instructions, in their source syntax
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for instr in program {
+        for byte in format!("{}\n", instr).bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Log {
+    pub program: u64,
+    pub events: Vec<Event>,
+}
+
+impl Log {
+    pub fn parse(text: &str) -> Result<Log, String> {
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err("not a wlvm recording".to_owned());
+        }
+        let mut program = None;
+        let mut events = vec![];
+        for (n, line) in lines.enumerate() {
+            let invalid = || format!("line {}: invalid event {}", n + 2, line);
+            let words = line.split(' ').collect::<Vec<&str>>();
+            let number = |i: usize| -> Result<u64, String> {
+                words
+                    .get(i)
+                    .and_then(|w| w.parse().ok())
+                    .ok_or_else(invalid)
+            };
+            match words[0] {
+                "program" => {
+                    let hash = words.get(1).ok_or_else(invalid)?;
+                    program = Some(u64::from_str_radix(hash, 16).map_err(|_| invalid())?);
+                }
+                "output" => {
+                    let hex = words.get(2).ok_or_else(invalid)?;
+                    if hex.len() % 2 != 0 {
+                        return Err(invalid());
+                    }
+                    let bytes = (0..hex.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                        .collect::<Result<Vec<u8>, _>>()
+                        .map_err(|_| invalid())?;
+                    events.push(Event::Output {
+                        step: number(1)?,
+                        bytes,
+                    });
+                }
+                "timeout" => events.push(Event::Timeout { step: number(1)? }),
+                "end" => events.push(Event::End { steps: number(1)? }),
+                "" => {}
+                _ => return Err(invalid()),
+            }
+        }
+        let program = program.ok_or("missing program fingerprint")?;
+        Ok(Log { program, events })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\nprogram {:016x}\n", HEADER, self.program);
+        for event in &self.events {
+            let _ = match event {
+                Event::Output { step, bytes } => {
+                    let hex = bytes
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>();
+                    writeln!(text, "output {} {}", step, hex)
+                }
+                Event::Timeout { step } => writeln!(text, "timeout {}", step),
+                Event::End { steps } => writeln!(text, "end {}", steps),
+            };
+        }
+        text
+    }
+}
+
+/// A run being recorded or replayed.
+pub struct Session {
+    pub log: Log,
+    replaying: bool,
+    /// Index of the next event to replay.
+    next: usize,
+    /// Why the run diverged from the recording, once it has.
+    pub divergence: Option<String>,
+}
+
+impl Session {
+    pub fn record(program: &[crate::Instruction]) -> Session {
+        Session {
+            log: Log {
+                program: fingerprint(program),
+                events: vec![],
+            },
+            replaying: false,
+            next: 0,
+            divergence: None,
+        }
+    }
+
+    /// Replays a recording, telling whether it was made with this program.
+    pub fn replay(log: Log, program: &[crate::Instruction]) -> Result<Session, String> {
+        if log.program != fingerprint(program) {
+            return Err("the recording was made with another program".to_owned());
+        }
+        Ok(Session {
+            log,
+            replaying: true,
+            next: 0,
+            divergence: None,
+        })
+    }
+
+    /// Records an event, or checks that it is the next one recorded. Returns
+    /// false when the run diverges.
+    fn event(&mut self, event: Event) -> bool {
+        if !self.replaying {
+            self.log.events.push(event);
+            return true;
+        }
+        let expected = self.log.events.get(self.next);
+        if expected == Some(&event) {
+            self.next += 1;
+            return true;
+        }
+        self.divergence = Some(format!(
+            "{}, but the recording has {}",
+            event.describe(),
+            expected.map_or("nothing more".to_owned(), Event::describe)
+        ));
+        false
+    }
+
+    /// Tells whether the time limit is hit before `step`, given what the clock
+    /// says. When replaying, the recording answers instead.
+    pub fn timeout(&mut self, step: u64, clock: bool) -> bool {
+        if !self.replaying {
+            if clock {
+                self.event(Event::Timeout { step });
+            }
+            return clock;
+        }
+        match self.log.events.get(self.next) {
+            Some(Event::Timeout { step: s }) if *s == step => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn output(&mut self, step: u64, bytes: &[u8]) -> bool {
+        bytes.is_empty()
+            || self.event(Event::Output {
+                step,
+                bytes: bytes.to_vec(),
+            })
+    }
+
+    /// Records the end of the run, or checks it ended as recorded.
+    pub fn end(&mut self, steps: u64) -> bool {
+        self.divergence.is_none() && self.event(Event::End { steps })
+    }
+}
@@ -0,0 +1,240 @@
+//! Test runner, behind `wlvm test`.
+//!
+//! A test is a program with annotations in its comments, telling what it
+//! should print, the value registers should end with, or the error it should
+//! stop with:
+//!
+//! ```text
+//! ; expect-stdout: 11
+//! ; expect-reg a = 11
+//! ; expect-error: stack overflow
+//! ```
+//!
+//! Every `expect-stdout` annotation is a line of the output, in order. Files
+//! without annotations are not tests, so that libraries can sit next to
+//! their tests.
+
+use crate::parser::{diagnostics, parse_code, register};
+use crate::vm::Vm;
+use crate::{reg_name, Register};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Steps a test may run, so that an endless loop fails instead of hanging.
+pub const TEST_STEPS: u64 = 10_000_000;
+
+/// What a test expects from its program.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Expectations {
+    /// Lines of the output, when checked.
+    pub stdout: Option<Vec<String>>,
+    /// Values of registers once the program stops.
+    pub registers: Vec<(Register, i32)>,
+    /// The error the program stops with.
+    pub error: Option<String>,
+}
+
+impl Expectations {
+    /// Reads the annotations of a source file, or `None` when it has none.
+    pub fn parse(source: &str) -> Result<Option<Expectations>, String> {
+        let mut expectations = Expectations::default();
+        let mut annotated = false;
+        for (n, line) in source.lines().enumerate() {
+            let annotation = match line.trim().strip_prefix(';') {
+                Some(comment) => match comment.trim_start().strip_prefix("expect-") {
+                    Some(annotation) => annotation,
+                    None => continue,
+                },
+                None => continue,
+            };
+            annotated = true;
+            let invalid = || format!("line {}: invalid annotation {}", n + 1, line.trim());
+            if let Some(text) = annotation.strip_prefix("stdout:") {
+                let text = text.strip_prefix(' ').unwrap_or(text);
+                expectations
+                    .stdout
+                    .get_or_insert_with(Vec::new)
+                    .push(text.to_owned());
+            } else if let Some(text) = annotation.strip_prefix("error:") {
+                expectations.error = Some(text.trim().to_owned());
+            } else if let Some(assignment) = annotation.strip_prefix("reg ") {
+                let (name, value) = assignment.split_once('=').ok_or_else(invalid)?;
+                let reg = register(name.trim()).ok_or_else(invalid)?;
+                let value = value.trim().parse().map_err(|_| invalid())?;
+                expectations.registers.push((reg, value));
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(if annotated { Some(expectations) } else { None })
+    }
+}
+
+/// Compares lines, returning them prefixed with `-` when only expected,
+/// `+` when only found, and a space when in both.
+pub fn diff(expected: &[String], actual: &[&str]) -> Vec<String> {
+    // Longest common subsequence, from the ends of the lines
+    let (n, m) = (expected.len(), actual.len());
+    let mut common = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            lines.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Runs a test, returning why it failed, or nothing when it passed.
+pub fn run(source: &str, expectations: &Expectations) -> Vec<String> {
+    let errors = diagnostics(source);
+    if !errors.is_empty() {
+        return errors
+            .iter()
+            .map(|e| format!("line {}: {} ({})", e.line, e.message, e.text.trim()))
+            .collect();
+    }
+    let (program, labels) = parse_code(source, false);
+    let mut vm = Vm::new(program, labels);
+    vm.output = Some(vec![]);
+    vm.max_steps = Some(TEST_STEPS);
+    let mut error = None;
+    while vm.running {
+        let ip = vm.ip();
+        if let Err(e) = vm.step() {
+            error = Some((e, ip));
+            break;
+        }
+    }
+
+    let mut failures = vec![];
+    match (&expectations.error, error) {
+        (None, None) => {}
+        (None, Some((e, ip))) => failures.push(format!(
+            "the program failed: {} at instruction {}",
+            e,
+            ip + 1
+        )),
+        (Some(expected), None) => failures.push(format!(
+            "expected the error \"{}\", but the program ended normally",
+            expected
+        )),
+        (Some(expected), Some((e, ip))) if *expected != e.to_string() => failures.push(format!(
+            "expected the error \"{}\", found \"{}\" at instruction {}",
+            expected,
+            e,
+            ip + 1
+        )),
+        (Some(_), Some(_)) => {}
+    }
+    for (reg, expected) in &expectations.registers {
+        let value = vm.registers[*reg as usize];
+        if value != *expected {
+            failures.push(format!(
+                "register {} is {}, expected {}",
+                reg_name(*reg as i32).to_lowercase(),
+                value,
+                expected
+            ));
+        }
+    }
+    if let Some(expected) = &expectations.stdout {
+        let output = String::from_utf8_lossy(vm.output.as_ref().unwrap()).into_owned();
+        let actual = output.lines().collect::<Vec<&str>>();
+        if actual != *expected {
+            failures.push("the output differs (- expected, + found):".to_owned());
+            failures.extend(diff(expected, &actual));
+        }
+    }
+    failures
+}
+
+/// Finds the `.vm` files of the given files and directories, in order.
+pub fn discover(paths: &[&str]) -> Result<Vec<PathBuf>, String> {
+    fn walk(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                let entry = entry?.path();
+                if entry.is_dir() || entry.extension().is_some_and(|e| e == "vm") {
+                    walk(&entry, files)?;
+                }
+            }
+        } else {
+            fs::metadata(path)?;
+            files.push(path.to_owned());
+        }
+        Ok(())
+    }
+    let mut files = vec![];
+    for path in paths {
+        walk(Path::new(path), &mut files).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Outcome of a test file.
+pub struct Outcome {
+    pub path: PathBuf,
+    /// Why it failed, empty when it passed.
+    pub failures: Vec<String>,
+}
+
+/// Runs the tests among `files` on `jobs` threads, leaving out the files that
+/// are not tests. Outcomes come in the order of the files.
+pub fn run_all(files: &[PathBuf], jobs: usize) -> Vec<Outcome> {
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(vec![]);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let path = match files.get(index) {
+                    Some(path) => path,
+                    None => break,
+                };
+                let failures = match fs::read(path) {
+                    Ok(bytes) => {
+                        let source = String::from_utf8_lossy(&bytes);
+                        match Expectations::parse(&source) {
+                            Ok(Some(expectations)) => run(&source, &expectations),
+                            Ok(None) => continue,
+                            Err(e) => vec![e],
+                        }
+                    }
+                    Err(e) => vec![e.to_string()],
+                };
+                let outcome = Outcome {
+                    path: path.clone(),
+                    failures,
+                };
+                outcomes.lock().unwrap().push((index, outcome));
+            });
+        }
+    });
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(index, _)| *index);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
@@ -0,0 +1,772 @@
+//! SSA form of programs over registers and stack slots, and the dataflow
+//! optimizations run with `--ssa`.
+//!
+//! Every value lives in the location (register or stack slot) it was defined
+//! in, so phis never need to be lowered to copies: lowering back to
+//! instructions only removes or rewrites the original ones.
+//!
+//! Only programs whose stack depth is known at every instruction can be
+//! lifted, which excludes the ones using `cal`, `ret`, `lod`, `sto`, `dmp`,
+//! writing `sp` or mentioning `ip`.
+
+use crate::optimizer::{compact, jump_target, mentions_ip};
+use crate::Instruction::*;
+use crate::{Instruction, Register, Register::*, STACK_SIZE};
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub type ValueId = usize;
+
+/// Registers tracked as SSA locations, `sp` being known statically.
+const TRACKED: [Register; 8] = [A, B, C, D, E, F, St, Eq];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Loc {
+    Reg(Register),
+    Slot(i32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl BinOp {
+    fn of(instr: &Instruction) -> Option<BinOp> {
+        Some(match instr {
+            Add(_, _) => BinOp::Add,
+            Sub(_, _) => BinOp::Sub,
+            Mul(_, _) => BinOp::Mul,
+            Div(_, _) => BinOp::Div,
+            Tee(_, _) => BinOp::Eq,
+            Tne(_, _) => BinOp::Ne,
+            Tll(_, _) => BinOp::Lt,
+            Tmm(_, _) => BinOp::Gt,
+            Tel(_, _) => BinOp::Le,
+            Tem(_, _) => BinOp::Ge,
+            _ => return None,
+        })
+    }
+
+    /// Folds the operation, if it cannot fail at runtime.
+    fn fold(self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            BinOp::Add => a.checked_add(b),
+            BinOp::Sub => a.checked_sub(b),
+            BinOp::Mul => a.checked_mul(b),
+            BinOp::Div => a.checked_div(b),
+            BinOp::Eq => Some((a == b) as i32),
+            BinOp::Ne => Some((a != b) as i32),
+            BinOp::Lt => Some((a < b) as i32),
+            BinOp::Gt => Some((a > b) as i32),
+            BinOp::Le => Some((a <= b) as i32),
+            BinOp::Ge => Some((a >= b) as i32),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Const(i32),
+    Copy(ValueId),
+    Binary(BinOp, ValueId, ValueId),
+    /// One operand per predecessor, in the order of `Block::preds`
+    Phi(Vec<ValueId>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Stmt {
+    pub index: usize,
+    pub instr: Instruction,
+    /// Registers read by the instruction and the values they hold
+    pub uses: Vec<(Register, ValueId)>,
+    pub defs: Vec<(Loc, ValueId)>,
+    /// Values held by every location before the instruction
+    pub env: BTreeMap<Loc, ValueId>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Block {
+    /// Range of the block in the program, the entry block being empty
+    pub start: usize,
+    pub end: usize,
+    /// Stack pointer when entering the block, `None` if it is unreachable
+    pub depth: Option<i32>,
+    pub jump: Option<usize>,
+    pub next: Option<usize>,
+    pub preds: Vec<usize>,
+    pub phis: Vec<(Loc, ValueId)>,
+    pub stmts: Vec<Stmt>,
+    pub exit: BTreeMap<Loc, ValueId>,
+}
+
+impl Block {
+    pub fn succs(&self) -> Vec<usize> {
+        let mut succs = self.jump.into_iter().chain(self.next).collect::<Vec<usize>>();
+        succs.dedup();
+        succs
+    }
+
+    fn terminator(&self) -> Option<Instruction> {
+        self.stmts.last().map(|s| s.instr.clone())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Lattice {
+    Unknown,
+    Const(i32),
+    Varying,
+}
+
+impl Lattice {
+    fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Unknown, x) | (x, Lattice::Unknown) => x,
+            (Lattice::Const(a), Lattice::Const(b)) if a == b => Lattice::Const(a),
+            _ => Lattice::Varying,
+        }
+    }
+}
+
+/// A program in SSA form. Block 0 is a synthetic entry block defining the
+/// initial value of every register.
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub blocks: Vec<Block>,
+    pub values: Vec<Op>,
+}
+
+fn liftable(instr: &Instruction, len: usize) -> bool {
+    !matches!(instr, Dmp | Cal(_) | Ret | Lod(_, _) | Sto(_, _))
+        && !mentions_ip(instr)
+        && crate::optimizer::destination(instr) != Some(Sp)
+        && jump_target(instr).is_none_or(|t| t >= 1 && t as usize <= len)
+}
+
+fn stack_effect(instr: &Instruction, depth: i32) -> Option<i32> {
+    match instr {
+        Psh(_) if (depth + 1) as usize >= STACK_SIZE => None,
+        Psh(_) => Some(depth + 1),
+        Pop if depth < 0 => None,
+        Pop => Some(depth - 1),
+        _ => Some(depth),
+    }
+}
+
+/// Lifts a program into SSA form, if its stack depth is statically known.
+pub fn lift(program: &[Instruction]) -> Option<Function> {
+    if !program.iter().all(|instr| liftable(instr, program.len())) {
+        return None;
+    }
+
+    let mut leader = vec![false; program.len()];
+    leader[0] = true;
+    for (i, instr) in program.iter().enumerate() {
+        if let Some(t) = jump_target(instr) {
+            leader[t as usize - 1] = true;
+        }
+        if matches!(instr, Gto(_) | Jmp(_) | Hlt) && i + 1 < program.len() {
+            leader[i + 1] = true;
+        }
+    }
+
+    let mut block_of = vec![0; program.len()];
+    let mut blocks = vec![Block {
+        start: 0,
+        end: 0,
+        depth: Some(-1),
+        jump: None,
+        next: Some(1),
+        preds: vec![],
+        phis: vec![],
+        stmts: vec![],
+        exit: BTreeMap::new(),
+    }];
+    for i in 0..program.len() {
+        if leader[i] {
+            blocks.push(Block {
+                start: i,
+                end: i,
+                depth: None,
+                jump: None,
+                next: None,
+                preds: vec![],
+                phis: vec![],
+                stmts: vec![],
+                exit: BTreeMap::new(),
+            });
+        }
+        block_of[i] = blocks.len() - 1;
+        blocks.last_mut().unwrap().end = i + 1;
+    }
+    for block in blocks.iter_mut().skip(1) {
+        let following = block_of.get(block.end).copied();
+        match program[block.end - 1] {
+            Gto(t) => block.jump = Some(block_of[t as usize - 1]),
+            Jmp(t) => {
+                block.jump = Some(block_of[t as usize - 1]);
+                block.next = following;
+            }
+            Hlt => {}
+            _ => block.next = following,
+        }
+    }
+
+    // Stack depth at the entry of every reachable block
+    let mut pending = vec![0];
+    while let Some(b) = pending.pop() {
+        let mut depth = blocks[b].depth.unwrap();
+        for instr in &program[blocks[b].start..blocks[b].end] {
+            depth = stack_effect(instr, depth)?;
+        }
+        for s in blocks[b].succs() {
+            match blocks[s].depth {
+                None => {
+                    blocks[s].depth = Some(depth);
+                    pending.push(s);
+                }
+                Some(d) if d != depth => return None,
+                Some(_) => {}
+            }
+        }
+    }
+    for b in 0..blocks.len() {
+        if blocks[b].depth.is_some() {
+            for s in blocks[b].succs() {
+                blocks[s].preds.push(b);
+            }
+        }
+    }
+
+    let mut values = vec![];
+    for reg in &TRACKED {
+        values.push(Op::Const(0));
+        blocks[0].exit.insert(Loc::Reg(*reg), values.len() - 1);
+    }
+
+    for block in blocks.iter_mut().skip(1) {
+        let depth = match block.depth {
+            Some(d) => d,
+            None => continue,
+        };
+        let mut env = BTreeMap::new();
+        let locs = TRACKED
+            .iter()
+            .map(|r| Loc::Reg(*r))
+            .chain((0..=depth).map(Loc::Slot));
+        for loc in locs {
+            values.push(Op::Phi(vec![]));
+            block.phis.push((loc, values.len() - 1));
+            env.insert(loc, values.len() - 1);
+        }
+
+        let mut depth = depth;
+        for (index, instr) in program.iter().enumerate().take(block.end).skip(block.start) {
+            let instr = instr.clone();
+            let mut uses = vec![];
+            let mut defs = vec![];
+            let before = env.clone();
+            let mut read = |reg: Register, values: &mut Vec<Op>| -> ValueId {
+                let value = if reg == Sp {
+                    values.push(Op::Const(depth));
+                    values.len() - 1
+                } else {
+                    before[&Loc::Reg(reg)]
+                };
+                uses.push((reg, value));
+                value
+            };
+
+            match instr {
+                Psh(i) => {
+                    values.push(Op::Const(i));
+                    defs.push((Loc::Slot(depth + 1), values.len() - 1));
+                    defs.push((Loc::Reg(St), values.len() - 1));
+                }
+                Pop => {
+                    values.push(match before.get(&Loc::Slot(depth - 1)) {
+                        Some(v) => Op::Copy(*v),
+                        None => Op::Const(0),
+                    });
+                    defs.push((Loc::Reg(St), values.len() - 1));
+                }
+                Mov(a, b) => {
+                    let v = read(b, &mut values);
+                    values.push(Op::Copy(v));
+                    defs.push((Loc::Reg(a), values.len() - 1));
+                }
+                Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) | Tee(a, b) | Tne(a, b)
+                | Tll(a, b) | Tmm(a, b) | Tel(a, b) | Tem(a, b) => {
+                    let x = read(a, &mut values);
+                    let y = read(b, &mut values);
+                    values.push(Op::Binary(BinOp::of(&instr).unwrap(), x, y));
+                    let dest = if matches!(instr, Add(..) | Sub(..) | Mul(..) | Div(..)) {
+                        a
+                    } else {
+                        Eq
+                    };
+                    defs.push((Loc::Reg(dest), values.len() - 1));
+                }
+                Drg(r) | Prt(r) => {
+                    read(r, &mut values);
+                }
+                Jmp(_) | Ast(_) => {
+                    read(Eq, &mut values);
+                }
+                _ => {}
+            }
+
+            if instr == Pop {
+                env.remove(&Loc::Slot(depth));
+            }
+            depth = stack_effect(&instr, depth).unwrap();
+            for (loc, value) in &defs {
+                env.insert(*loc, *value);
+            }
+            block.stmts.push(Stmt {
+                index,
+                instr,
+                uses,
+                defs,
+                env: before,
+            });
+        }
+        block.exit = env;
+    }
+
+    for b in 1..blocks.len() {
+        let phis = blocks[b].phis.clone();
+        for (loc, phi) in phis {
+            let operands = blocks[b]
+                .preds
+                .iter()
+                .map(|p| blocks[*p].exit[&loc])
+                .collect();
+            values[phi] = Op::Phi(operands);
+        }
+    }
+
+    let mut function = Function { blocks, values };
+    function.simplify_phis();
+    Some(function)
+}
+
+impl Function {
+    /// Replaces the phis merging a single value by that value.
+    fn simplify_phis(&mut self) {
+        let mut alias: Vec<ValueId> = (0..self.values.len()).collect();
+        fn resolve(alias: &mut [ValueId], v: ValueId) -> ValueId {
+            let mut root = v;
+            while alias[root] != root {
+                root = alias[root];
+            }
+            alias[v] = root;
+            root
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for phi in 0..self.values.len() {
+                if alias[phi] != phi {
+                    continue;
+                }
+                if let Op::Phi(operands) = &self.values[phi] {
+                    let mut unique = vec![];
+                    for op in operands.clone() {
+                        let op = resolve(&mut alias, op);
+                        if op != phi && !unique.contains(&op) {
+                            unique.push(op);
+                        }
+                    }
+                    if unique.len() == 1 {
+                        alias[phi] = unique[0];
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        for v in 0..self.values.len() {
+            resolve(&mut alias, v);
+        }
+        let alias = |v: &mut ValueId| *v = alias[*v];
+        for op in self.values.iter_mut() {
+            match op {
+                Op::Copy(x) => alias(x),
+                Op::Binary(_, x, y) => {
+                    alias(x);
+                    alias(y);
+                }
+                Op::Phi(operands) => operands.iter_mut().for_each(alias),
+                Op::Const(_) => {}
+            }
+        }
+        for block in self.blocks.iter_mut() {
+            block.phis.retain(|(_, v)| {
+                let mut resolved = *v;
+                alias(&mut resolved);
+                resolved == *v
+            });
+            block.exit.values_mut().for_each(alias);
+            for stmt in block.stmts.iter_mut() {
+                stmt.uses.iter_mut().for_each(|(_, v)| alias(v));
+                stmt.defs.iter_mut().for_each(|(_, v)| alias(v));
+                stmt.env.values_mut().for_each(alias);
+            }
+        }
+    }
+
+    fn evaluate(&self, value: ValueId, lattice: &[Lattice], block: &Block, edges: &[(usize, usize)]) -> Lattice {
+        match &self.values[value] {
+            Op::Const(i) => Lattice::Const(*i),
+            Op::Copy(x) => lattice[*x],
+            Op::Binary(op, x, y) => match (lattice[*x], lattice[*y]) {
+                (Lattice::Unknown, _) | (_, Lattice::Unknown) => Lattice::Unknown,
+                (Lattice::Const(a), Lattice::Const(b)) => match op.fold(a, b) {
+                    Some(r) => Lattice::Const(r),
+                    None => Lattice::Varying,
+                },
+                _ => Lattice::Varying,
+            },
+            Op::Phi(operands) => operands
+                .iter()
+                .zip(&block.preds)
+                .filter(|(_, p)| edges.iter().any(|(from, _)| from == *p))
+                .fold(Lattice::Unknown, |acc, (v, _)| acc.meet(lattice[*v])),
+        }
+    }
+
+    /// Sparse conditional constant propagation. Returns the lattice value of
+    /// every value and which blocks can be executed.
+    pub fn propagate(&self) -> (Vec<Lattice>, Vec<bool>) {
+        let mut lattice = vec![Lattice::Unknown; self.values.len()];
+        let mut executable = vec![false; self.blocks.len()];
+        executable[0] = true;
+        for (v, op) in self.values.iter().enumerate() {
+            if let Op::Const(i) = op {
+                lattice[v] = Lattice::Const(*i);
+            }
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (b, block) in self.blocks.iter().enumerate().skip(1) {
+                if !executable[b] {
+                    continue;
+                }
+                let edges = block
+                    .preds
+                    .iter()
+                    .filter(|p| executable[**p] && self.is_taken(**p, b, &lattice))
+                    .map(|p| (*p, b))
+                    .collect::<Vec<(usize, usize)>>();
+                let defined = block
+                    .phis
+                    .iter()
+                    .map(|(_, v)| *v)
+                    .chain(block.stmts.iter().flat_map(|s| s.defs.iter().map(|(_, v)| *v)));
+                for v in defined {
+                    let new = lattice[v].meet(self.evaluate(v, &lattice, block, &edges));
+                    if new != lattice[v] {
+                        lattice[v] = new;
+                        changed = true;
+                    }
+                }
+            }
+            for b in 0..self.blocks.len() {
+                if !executable[b] {
+                    continue;
+                }
+                for s in self.blocks[b].succs() {
+                    if !executable[s] && self.is_taken(b, s, &lattice) {
+                        executable[s] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        (lattice, executable)
+    }
+
+    /// Tells whether the edge between two blocks can be taken.
+    fn is_taken(&self, from: usize, to: usize, lattice: &[Lattice]) -> bool {
+        let block = &self.blocks[from];
+        match block.terminator() {
+            Some(Jmp(_)) => {
+                let eq = block.stmts.last().unwrap().uses[0].1;
+                match lattice[eq] {
+                    Lattice::Unknown => false,
+                    Lattice::Const(1) => block.jump == Some(to),
+                    Lattice::Const(_) => block.next == Some(to),
+                    Lattice::Varying => true,
+                }
+            }
+            _ => block.succs().contains(&to),
+        }
+    }
+
+    fn defining_stmt(&self) -> BTreeMap<ValueId, (usize, usize)> {
+        let mut def = BTreeMap::new();
+        for (b, block) in self.blocks.iter().enumerate() {
+            for (s, stmt) in block.stmts.iter().enumerate() {
+                for (_, v) in &stmt.defs {
+                    def.insert(*v, (b, s));
+                }
+            }
+        }
+        def
+    }
+}
+
+/// Rewrites the registers an instruction only reads.
+fn rewrite_reads(instr: Instruction, f: impl Fn(Register) -> Register) -> Instruction {
+    match instr {
+        Mov(a, b) => Mov(a, f(b)),
+        Add(a, b) => Add(a, f(b)),
+        Sub(a, b) => Sub(a, f(b)),
+        Mul(a, b) => Mul(a, f(b)),
+        Div(a, b) => Div(a, f(b)),
+        Tee(a, b) => Tee(f(a), f(b)),
+        Tne(a, b) => Tne(f(a), f(b)),
+        Tll(a, b) => Tll(f(a), f(b)),
+        Tmm(a, b) => Tmm(f(a), f(b)),
+        Tel(a, b) => Tel(f(a), f(b)),
+        Tem(a, b) => Tem(f(a), f(b)),
+        Drg(r) => Drg(f(r)),
+        Prt(r) => Prt(f(r)),
+        other => other,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Key {
+    Const(i32),
+    Root(ValueId),
+}
+
+/// Runs constant propagation, copy propagation and dead code elimination,
+/// then lowers the result back to instructions. Programs that cannot be
+/// lifted are returned unchanged.
+pub fn optimize(
+    program: &[Instruction],
+    labels: &BTreeMap<String, i32>,
+) -> (Vec<Instruction>, BTreeMap<String, i32>) {
+    let mut function = match lift(program) {
+        Some(f) => f,
+        None => return (program.to_vec(), labels.clone()),
+    };
+    let (lattice, executable) = function.propagate();
+    let mut program = program.to_vec();
+    let mut remove = vec![false; program.len()];
+
+    let keys = (0..function.values.len())
+        .map(|v| {
+            if let Lattice::Const(i) = lattice[v] {
+                return Key::Const(i);
+            }
+            let mut root = v;
+            while let Op::Copy(x) = function.values[root] {
+                root = x;
+            }
+            Key::Root(root)
+        })
+        .collect::<Vec<Key>>();
+    let is_copy = function
+        .values
+        .iter()
+        .map(|op| matches!(op, Op::Copy(_)))
+        .collect::<Vec<bool>>();
+
+    // Constant branches and copy propagation
+    for (b, block) in function.blocks.iter_mut().enumerate() {
+        if !executable[b] {
+            remove[block.start..block.end].fill(true);
+            continue;
+        }
+        for stmt in block.stmts.iter_mut() {
+            if let Jmp(t) = stmt.instr {
+                match lattice[stmt.uses[0].1] {
+                    Lattice::Const(1) => {
+                        stmt.instr = Gto(t);
+                        stmt.uses.clear();
+                    }
+                    Lattice::Const(_) => {
+                        remove[stmt.index] = true;
+                        stmt.uses.clear();
+                    }
+                    _ => {}
+                }
+                program[stmt.index] = stmt.instr.clone();
+                continue;
+            }
+
+            // Reads are redirected to the register holding the original
+            // value rather than a copy of it
+            let mut replacement = BTreeMap::new();
+            for (reg, v) in &stmt.uses {
+                if *reg == Sp {
+                    continue;
+                }
+                let best = TRACKED
+                    .iter()
+                    .filter(|r| keys[stmt.env[&Loc::Reg(**r)]] == keys[*v])
+                    .min_by_key(|r| (is_copy[stmt.env[&Loc::Reg(**r)]], **r != *reg));
+                if let Some(best) = best {
+                    replacement.insert(*reg, *best);
+                }
+            }
+            let rewritten = rewrite_reads(stmt.instr.clone(), |r| *replacement.get(&r).unwrap_or(&r));
+            if rewritten != stmt.instr {
+                let sp = stmt.uses.iter().find(|(r, _)| *r == Sp).map(|(_, v)| *v);
+                stmt.uses = crate::optimizer::reads(&rewritten)
+                    .into_iter()
+                    .map(|r| match r {
+                        Sp => (r, sp.unwrap()),
+                        _ => (r, stmt.env[&Loc::Reg(r)]),
+                    })
+                    .collect();
+                program[stmt.index] = rewritten.clone();
+                stmt.instr = rewritten;
+            }
+        }
+    }
+
+    // Liveness, the whole final state being observable when halting
+    let defining = function.defining_stmt();
+    let mut live = vec![false; function.values.len()];
+    let mut pending: Vec<ValueId> = vec![];
+    for (b, block) in function.blocks.iter().enumerate() {
+        if !executable[b] {
+            continue;
+        }
+        for stmt in &block.stmts {
+            if remove[stmt.index] {
+                continue;
+            }
+            let effectful = match stmt.instr {
+                Prt(_) | Drg(_) | Jmp(_) | Ast(_) => true,
+                Div(_, _) => lattice[stmt.uses[1].1] == Lattice::Const(0)
+                    || !matches!(lattice[stmt.uses[1].1], Lattice::Const(_)),
+                _ => false,
+            };
+            if effectful {
+                pending.extend(stmt.uses.iter().map(|(_, v)| *v));
+            }
+            if stmt.instr == Hlt {
+                pending.extend(stmt.env.values());
+            }
+        }
+    }
+    while let Some(v) = pending.pop() {
+        if live[v] {
+            continue;
+        }
+        live[v] = true;
+        if let Some((b, s)) = defining.get(&v) {
+            pending.extend(function.blocks[*b].stmts[*s].uses.iter().map(|(_, v)| *v));
+        }
+        match &function.values[v] {
+            Op::Phi(operands) => pending.extend(operands),
+            Op::Copy(x) if !defining.contains_key(&v) => pending.push(*x),
+            _ => {}
+        }
+    }
+
+    for (b, block) in function.blocks.iter().enumerate() {
+        if !executable[b] {
+            continue;
+        }
+        for stmt in &block.stmts {
+            let pure = match stmt.instr {
+                Mov(a, b) if a == b => {
+                    remove[stmt.index] = true;
+                    continue;
+                }
+                Div(_, _) => matches!(lattice[stmt.uses[1].1], Lattice::Const(d) if d != 0),
+                Mov(_, _) | Add(_, _) | Sub(_, _) | Mul(_, _) => true,
+                Tee(_, _) | Tne(_, _) | Tll(_, _) | Tmm(_, _) | Tel(_, _) | Tem(_, _) => true,
+                _ => false,
+            };
+            if pure && stmt.defs.iter().all(|(_, v)| !live[*v]) {
+                remove[stmt.index] = true;
+            }
+        }
+    }
+
+    compact(&program, labels, &remove)
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Loc::Reg(r) => write!(f, "{}", format!("{:?}", r).to_lowercase()),
+            Loc::Slot(i) => write!(f, "[{}]", i),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |v: ValueId| -> String {
+            match &self.values[v] {
+                Op::Const(i) => format!("v{} = {}", v, i),
+                Op::Copy(x) => format!("v{} = v{}", v, x),
+                Op::Binary(op, x, y) => format!("v{} = {:?} v{} v{}", v, op, x, y),
+                Op::Phi(operands) => format!(
+                    "v{} = phi({})",
+                    v,
+                    operands
+                        .iter()
+                        .map(|o| format!("v{}", o))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+            }
+        };
+        for (b, block) in self.blocks.iter().enumerate() {
+            match block.depth {
+                None => writeln!(f, "b{}: unreachable", b)?,
+                Some(d) if b == 0 => writeln!(f, "b0: entry, sp = {}", d)?,
+                Some(d) => writeln!(
+                    f,
+                    "b{}: instructions {}..{}, sp = {}, preds {:?}",
+                    b,
+                    block.start + 1,
+                    block.end,
+                    d,
+                    block.preds
+                )?,
+            }
+            for (loc, v) in &block.phis {
+                writeln!(f, "    {} <- {}", loc, show(*v))?;
+            }
+            for stmt in &block.stmts {
+                let defs = stmt
+                    .defs
+                    .iter()
+                    .map(|(loc, v)| format!("{} <- {}", loc, show(*v)))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                writeln!(f, "  {:>4} {:?}  {}", stmt.index + 1, stmt.instr, defs)?;
+            }
+            if b == 0 {
+                for (loc, v) in &block.exit {
+                    writeln!(f, "    {} <- {}", loc, show(*v))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,17 @@
+//! Libraries shipped inside the binary, pulled with `.include <std/...>`.
+
+const LIBRARIES: &[(&str, &str)] = &[
+    ("<std/io.vm>", include_str!("../std/io.vm")),
+    ("<std/math.vm>", include_str!("../std/math.vm")),
+    ("<std/mem.vm>", include_str!("../std/mem.vm")),
+    ("<std/random.vm>", include_str!("../std/random.vm")),
+    ("<std/sort.vm>", include_str!("../std/sort.vm")),
+    ("<std/string.vm>", include_str!("../std/string.vm")),
+];
+
+pub fn lookup(name: &str) -> Option<&'static str> {
+    LIBRARIES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, source)| *source)
+}
@@ -1,4 +1,4 @@
-use crate::{Instruction::*, Register::*, *};
+use crate::*;
 
 #[cfg(test)]
 mod test {
@@ -6,10 +6,10 @@ mod test {
 
   #[test]
   fn validation() {
-    assert!(!is_valid(Prt(A)));
-    assert!(!is_valid(Drg(A)));
-    assert!(!is_valid(Dmp));
-    assert!(!is_valid(Hlt));
+    assert!(!is_valid(&Prt(A)));
+    assert!(!is_valid(&Drg(A)));
+    assert!(!is_valid(&Dmp));
+    assert!(!is_valid(&Hlt));
   }
 
   #[test]
@@ -23,7 +23,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(stack[0], 5);
     eval(
@@ -32,7 +31,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(stack[1], 8);
     eval(
@@ -41,7 +39,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -49,7 +46,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -57,7 +53,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(stack[0], 14);
   }
@@ -74,7 +69,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -82,7 +76,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(registers[A as usize], 5);
     eval(
@@ -91,7 +84,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(registers[B as usize], 5);
   }
@@ -108,7 +100,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -116,7 +107,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -124,7 +114,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -132,7 +121,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -140,7 +128,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(registers[A as usize], 11);
   }
@@ -157,7 +144,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -165,7 +151,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -173,7 +158,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -181,7 +165,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -189,7 +172,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(registers[A as usize], -1);
   }
@@ -206,7 +188,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -214,7 +195,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -222,7 +202,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -230,7 +209,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -238,7 +216,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(registers[A as usize], 30);
   }
@@ -255,7 +232,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -263,7 +239,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -271,7 +246,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -279,7 +253,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -287,7 +260,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(registers[A as usize], 2);
   }
@@ -304,7 +276,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -312,7 +283,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -320,7 +290,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -328,7 +297,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -336,7 +304,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -345,7 +312,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     assert!(!running);
@@ -363,7 +329,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -371,7 +336,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -379,7 +343,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -387,7 +350,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -396,7 +358,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     assert_eq!(registers[Eq as usize], 1);
@@ -413,7 +374,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -421,7 +381,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -429,7 +388,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -437,7 +395,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -446,7 +403,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     assert_eq!(registers[Eq as usize], 1);
@@ -464,7 +420,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -472,7 +427,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -480,7 +434,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -488,7 +441,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -497,7 +449,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     assert_eq!(registers[Eq as usize], 1);
@@ -515,7 +466,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -523,7 +473,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -531,7 +480,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -539,7 +487,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -548,7 +495,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     assert_eq!(registers[Eq as usize], 1);
@@ -565,7 +511,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -573,7 +518,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -581,7 +525,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -589,7 +532,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -598,7 +540,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     assert_eq!(registers[Eq as usize], 1);
@@ -616,7 +557,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -624,7 +564,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -632,7 +571,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -640,7 +578,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -649,7 +586,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     assert_eq!(registers[Eq as usize], 1);
@@ -667,7 +603,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -675,7 +610,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -683,7 +617,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     eval(
       &labels,
@@ -691,7 +624,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -700,7 +632,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
 
     eval(
@@ -709,7 +640,6 @@ mod test {
       &mut running,
       &mut stack,
       &mut registers,
-      false,
     );
     assert_eq!(registers[Ip as usize], 2);
   }
@@ -730,7 +660,6 @@ mod test {
         &mut running,
         &mut stack,
         &mut registers,
-        false,
       );
 
       registers[6] += 1;
@@ -738,4 +667,1010 @@ mod test {
 
     assert_eq!(registers[A as usize], 4);
   }
+
+  #[test]
+  fn object_round_trip() {
+    let object = parse_module(".export :f\n:f\npsh 3\ngto :g\njmp 1", true, true);
+    assert_eq!(object.imports, vec![":g".to_owned()]);
+    assert_eq!(object::Object::from_bytes(&object.to_bytes()), Ok(object));
+  }
+
+  #[test]
+  fn linking() {
+    let main = parse_module(
+      ".export :back\npsh 4\nmov a st\ngto :double\n:back\nhlt",
+      true,
+      true,
+    );
+    let lib = parse_module(".export :double\n:double\nadd a a\ngto :back", true, true);
+    let (program, labels) =
+      object::link(&[("main.o".to_owned(), main), ("lib.o".to_owned(), lib)]).unwrap();
+    assert_eq!(labels[":double"], 6);
+    assert_eq!(program[5], Add(A, A));
+    assert_eq!(program[6], Gto(4));
+
+    let (mut stack, mut registers, mut running) = setup_environment();
+    while running {
+      let instr = fetch(&program, registers[6] as usize);
+      eval(
+        &labels,
+        instr,
+        &mut running,
+        &mut stack,
+        &mut registers,
+      );
+      registers[6] += 1;
+    }
+    assert_eq!(registers[A as usize], 8);
+  }
+
+  #[test]
+  fn link_errors() {
+    let a = parse_module(".export :f\n:f\ngto :missing", true, true);
+    let b = parse_module(".export :f\n:f\nhlt", true, true);
+    let errors = object::link(&[("a.o".to_owned(), a), ("b.o".to_owned(), b)]).unwrap_err();
+    assert_eq!(
+      errors,
+      vec![
+        "duplicate symbol :f (defined in a.o and b.o)".to_owned(),
+        "undefined symbol :missing (used in a.o)".to_owned(),
+      ]
+    );
+  }
+
+  #[test]
+  fn call_and_return() {
+    let (mut stack, mut registers, mut running) = setup_environment();
+
+    let (program, labels) = parse_code(
+      "cal :double\ncal :double\nhlt\n:double\npsh 1\nmov b st\npop\nadd a b\nadd a a\nret",
+      true,
+    );
+
+    while running {
+      let instr = fetch(&program, registers[6] as usize);
+      eval(
+        &labels,
+        instr,
+        &mut running,
+        &mut stack,
+        &mut registers,
+      );
+
+      registers[6] += 1;
+    }
+
+    assert_eq!(registers[A as usize], 6);
+    assert_eq!(registers[Sp as usize], -1);
+  }
+
+  #[test]
+  fn stack_addressing() {
+    let (mut stack, mut registers, mut running) = setup_environment();
+
+    let labels: BTreeMap<String, i32> = BTreeMap::new();
+
+    for instr in [Psh(3), Psh(0), Mov(A, St), Psh(7), Mov(B, St), Sto(A, B), Lod(C, A)] {
+      eval(
+        &labels,
+        instr,
+        &mut running,
+        &mut stack,
+        &mut registers,
+      );
+    }
+    assert_eq!(stack[0], 7);
+    assert_eq!(registers[C as usize], 7);
+  }
+
+  #[test]
+  fn register_names() {
+    assert_eq!(parser::register("d"), Some(D));
+    assert_eq!(parser::register("e"), Some(E));
+    assert_eq!(
+      parse_code("mov d a\nmov e b\nadd eq c\ndrg eq", false).0,
+      vec![Mov(D, A), Mov(E, B), Add(Eq, C), Drg(Eq), Hlt]
+    );
+    assert_eq!(reg_name(D as i32), "D");
+    assert_eq!(reg_name(E as i32), "E");
+  }
+
+  #[test]
+  fn subroutine_images() {
+    let program = vec![Cal(3), Hlt, Lod(A, B), Sto(C, Sp), Ret, Hlt];
+    let labels = BTreeMap::new();
+    let image = object::write_image(&program, &labels);
+    assert_eq!(object::read_image(&image), Ok((program, labels)));
+  }
+
+  fn execute(program: &[Instruction], labels: &BTreeMap<String, i32>) -> ([i32; 10], Vec<i32>) {
+    let (mut stack, mut registers, mut running) = setup_environment();
+    while running {
+      let instr = fetch(program, registers[6] as usize);
+      eval(
+        labels,
+        instr,
+        &mut running,
+        &mut stack,
+        &mut registers,
+      );
+      registers[6] += 1;
+    }
+    registers[Ip as usize] = 0;
+    stack.truncate((registers[Sp as usize] + 1) as usize);
+    (registers, stack)
+  }
+
+  #[test]
+  fn peephole() {
+    let (program, labels) = parse_code(
+      "psh 3\nmov a st\npop\npsh 4\nmov a st\nmov a a\ngto :next\n:next\npsh 9\npop\ngto :end\ndrg a\n:end\nhlt",
+      true,
+    );
+    let (optimized, optimized_labels) = optimizer::optimize(&program, &labels);
+    assert_eq!(optimized, vec![Psh(4), Mov(A, St), Hlt]);
+    assert_eq!(optimized_labels[":next"], 3);
+    assert_eq!(optimized_labels[":end"], 3);
+    assert_eq!(execute(&program, &labels), execute(&optimized, &optimized_labels));
+  }
+
+  #[test]
+  fn optimized_loop() {
+    let (program, labels) = parse_code(
+      "psh 0\nmov a st\nmov b st\npsh 1\nmov c st\npop\npsh 10\nmov d st\npop\n:loop\nadd b c\nadd a b\ntne b d\njmp :continue\ngto :end\n:continue\ngto :loop\n:end\nmov e e\nhlt",
+      true,
+    );
+    let (optimized, optimized_labels) = optimizer::optimize(&program, &labels);
+    assert_eq!(optimized.len(), 14);
+    assert_eq!(optimized[12], Jmp(10));
+    let (registers, _) = execute(&optimized, &optimized_labels);
+    assert_eq!(registers[A as usize], 55);
+    assert_eq!(execute(&program, &labels), (registers, vec![0]));
+  }
+
+  #[test]
+  fn unoptimizable() {
+    let (program, labels) = parse_code("psh 1\npop\nmov a ip\nhlt", true);
+    assert_eq!(optimizer::optimize(&program, &labels), (program, labels));
+    let (program, labels) = parse_code("psh 1\npop\ndmp", true);
+    assert_eq!(optimizer::optimize(&program, &labels), (program, labels));
+    // Return addresses are instruction numbers, read from the stack or
+    // jumped to by `ret` even without a `cal`
+    let (program, labels) = parse_code("mov a a\ncal :f\nhlt\n:f\ndrg st\nret", true);
+    assert_eq!(optimizer::optimize(&program, &labels), (program, labels));
+    let (program, labels) = parse_code("mov a a\npsh 4\nret\nhlt\npsh 66\nmov a st\nprt a", true);
+    assert_eq!(optimizer::optimize(&program, &labels), (program, labels));
+  }
+
+  #[test]
+  fn ssa_constant_branch() {
+    let (program, labels) = parse_code(
+      "psh 2\nmov a st\npsh 3\nmov b st\nmov c b\ntll a b\njmp :small\ndrg c\n:small\nmov d a\nadd d d\ndrg d\nhlt",
+      true,
+    );
+    let (optimized, optimized_labels) = ssa::optimize(&program, &labels);
+    assert!(!optimized.iter().any(|instr| matches!(instr, Jmp(_) | Drg(C))));
+    assert_eq!(optimized_labels[":small"], optimized.len() as i32 - 3);
+    assert_eq!(execute(&program, &labels), execute(&optimized, &optimized_labels));
+  }
+
+  #[test]
+  fn ssa_copies() {
+    let (program, labels) = parse_code(
+      "psh 5\nmov a st\nmov b a\nmov c b\nadd c c\nmov e c\nmov e a\nprt c\nhlt",
+      true,
+    );
+    let (optimized, optimized_labels) = ssa::optimize(&program, &labels);
+    assert!(optimized.len() < program.len());
+    assert_eq!(execute(&program, &labels), execute(&optimized, &optimized_labels));
+  }
+
+  #[test]
+  fn ssa_loop() {
+    let (program, labels) = parse_code(
+      "psh 0\nmov a st\nmov b st\npsh 1\nmov c st\npop\npsh 10\nmov d st\npop\n:loop\nadd b c\nadd a b\ntne b d\njmp :loop\nhlt",
+      true,
+    );
+    let (optimized, optimized_labels) = ssa::optimize(&program, &labels);
+    let (registers, stack) = execute(&optimized, &optimized_labels);
+    assert_eq!(registers[A as usize], 55);
+    assert_eq!(execute(&program, &labels), (registers, stack));
+  }
+
+  #[test]
+  fn ssa_unliftable() {
+    let (program, labels) = parse_code(":loop\npsh 1\ngto :loop", true);
+    assert!(ssa::lift(&program).is_none());
+    assert_eq!(ssa::optimize(&program, &labels), (program, labels));
+    let (program, labels) = parse_code("cal :f\nhlt\n:f\nret", true);
+    assert_eq!(ssa::optimize(&program, &labels), (program, labels));
+  }
+
+  fn diagnostics(code: &str) -> Vec<(usize, String)> {
+    let (program, _) = parse_code(code, true);
+    let own = code.split('\n').filter(|line| parser::produces_instruction(line)).count();
+    check::check(&program, own)
+      .into_iter()
+      .map(|d| (d.instruction, d.message))
+      .collect()
+  }
+
+  #[test]
+  fn check_clean() {
+    let source = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/helloAscii.vm")).unwrap();
+    assert_eq!(diagnostics(&source), vec![]);
+    assert_eq!(
+      diagnostics(".include <std/io.vm>\npsh 42\nmov a st\npop\ncal :std_print_int\ndrg a\nhlt"),
+      vec![]
+    );
+  }
+
+  #[test]
+  fn check_values() {
+    assert_eq!(
+      diagnostics("psh 300\nmov a st\nprt a\nmov c b\npsh 2\nmov d st\nsub d st\ndiv a d\nhlt"),
+      vec![
+        (3, "prt a prints nothing, its value (300) is outside 0..256".to_owned()),
+        (4, "reads b, which is never written".to_owned()),
+        (8, "division by zero, d is 0".to_owned()),
+        (9, "unreachable instruction".to_owned()),
+      ]
+    );
+    assert_eq!(
+      diagnostics("psh 5\nmov a st\n:loop\npsh 1\nsub a st\npop\nmov b a\ndiv st b\ntne a eq\njmp :loop\nhlt"),
+      vec![(7, "possible division by zero, b may be 0".to_owned())]
+    );
+  }
+
+  #[test]
+  fn check_control_flow() {
+    assert_eq!(
+      diagnostics("gto :end\ndrg a\ndrg b\n:end\ngto 40\ngto -2\nhlt"),
+      vec![
+        (2, "unreachable instructions, up to 3".to_owned()),
+        (4, "jumps to instruction 40, outside the program (1..=7)".to_owned()),
+        (5, "jumps to negative instruction -2".to_owned()),
+        (5, "unreachable instructions, up to 6".to_owned()),
+      ]
+    );
+  }
+
+  #[test]
+  fn check_stack() {
+    assert_eq!(
+      diagnostics(":loop\npsh 1\ngto :loop"),
+      vec![(1, "stack overflow, sp is already 254".to_owned())]
+    );
+    assert_eq!(
+      diagnostics("psh 1\nmov a st\npsh 2\nmov b st\ntee a b\njmp :skip\npop\n:skip\npop\npop\npop\nhlt"),
+      vec![
+        (9, "stack underflow, the stack is empty".to_owned()),
+        (10, "unreachable instructions, up to 11".to_owned()),
+      ]
+    );
+  }
+
+  #[test]
+  fn control_flow_graph() {
+    let code = "psh 0\nmov a st\n:loop\nadd a st\ntne a b\njmp :loop\ncal :f\nhlt\n:f\nret";
+    let (program, labels) = parse_code(code, true);
+    let blocks = cfg::blocks(&program, &labels)
+      .into_iter()
+      .map(|b| (b.start, b.end, b.edges))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      blocks,
+      vec![
+        (0, 2, vec![(1, "")]),
+        (2, 5, vec![(1, "taken"), (2, "fallthrough")]),
+        (5, 6, vec![(4, "call"), (3, "return")]),
+        (6, 7, vec![]),
+        (7, 8, vec![]),
+        (8, 9, vec![]),
+      ]
+    );
+
+    let dot = cfg::to_dot(&program, &labels, &parser::source_lines(code));
+    assert!(dot.starts_with("digraph cfg {\n"));
+    assert!(dot.contains("i3 [label=\":loop\\l4: add a st\\l5: tne a b\\l6: jmp :loop\\l\"];"));
+    assert!(dot.contains("i9 [label=\"Hlt\\l\"];"));
+    assert!(dot.contains("i3 -> i3 [label=\"taken\"];"));
+    assert!(dot.contains("i6 -> i8 [label=\"call\"];"));
+    assert!(dot.contains("i1 -> i3;"));
+  }
+
+  #[test]
+  fn vm_errors() {
+    let (program, labels) = parse_code("cal :f\nhlt\n:f\npsh 0\nmov b st\ndiv a b\nret", true);
+    let mut vm = vm::Vm::new(program, labels);
+    vm.step().unwrap();
+    assert_eq!(vm.calls, vec![0]);
+    assert_eq!(vm.run(), Err(vm::VmError::DivisionByZero));
+    assert!(!vm.running);
+    assert_eq!(vm.ip(), 4);
+    assert_eq!(vm.steps, 3);
+
+    let (program, labels) = parse_code("pop", true);
+    let mut vm = vm::Vm::new(program, labels);
+    assert_eq!(vm.run(), Err(vm::VmError::StackUnderflow));
+    assert_eq!(vm::VmError::StackUnderflow.code(), "ERR_STACK_UNDERFLOW");
+
+    let (program, labels) = parse_code("cal :f\nhlt\n:f\nret", true);
+    let mut vm = vm::Vm::new(program, labels);
+    assert_eq!(vm.run(), Ok(()));
+    assert!(vm.calls.is_empty());
+  }
+
+  #[test]
+  fn watchpoints() {
+    let watchpoints = vec![
+      watch::Watchpoint::parse("a if a > 2").unwrap(),
+      watch::Watchpoint::parse("[1]").unwrap(),
+      watch::Watchpoint::parse("eq if a  == 4").unwrap(),
+    ];
+    assert_eq!(watchpoints[2].to_string(), "eq if a == 4");
+    assert!(watch::Watchpoint::parse("[255]").is_err());
+    assert!(watch::Watchpoint::parse("a if a ~ 3").is_err());
+    assert!(watch::Watchpoint::parse("a when a > 3").is_err());
+
+    let (program, labels) = parse_code("psh 1\nmov b st\n:loop\nadd a b\npsh 4\ntee a st\npop\njmp :end\ngto :loop\n:end\nhlt", true);
+    let mut vm = vm::Vm::new(program, labels);
+    let mut seen = vec![];
+    while vm.running {
+      let before = watch::snapshot(&watchpoints, &vm);
+      vm.step().unwrap();
+      for hit in watch::hits(&watchpoints, &before, &vm) {
+        seen.push((hit.watchpoint.to_string(), hit.old, hit.new));
+      }
+    }
+    assert_eq!(
+      seen,
+      vec![
+        ("[1]".to_owned(), 0, 4),
+        ("a if a > 2".to_owned(), 2, 3),
+        ("a if a > 2".to_owned(), 3, 4),
+        ("eq if a == 4".to_owned(), 0, 1),
+      ]
+    );
+  }
+
+  #[test]
+  fn reverse_execution() {
+    let (program, labels) = parse_code(
+      "psh 0\nmov a st\npop\n:loop\npsh 1\nadd a st\nsto a a\npop\ncal :f\npsh 150\ntll a st\npop\njmp :loop\nhlt\n:f\nmov b a\nret",
+      true,
+    );
+    let mut vm = vm::Vm::new(program, labels);
+    vm.history = Some(history::History::default());
+    let mut states = vec![];
+    while vm.running {
+      states.push(history::Checkpoint::capture(&vm));
+      vm.step().unwrap();
+    }
+    assert!(states.len() > 1000);
+    assert_eq!(vm.registers[A as usize], 150);
+
+    let write = vm.history.as_ref().unwrap().last_write(watch::Target::Register(B)).unwrap();
+    assert_eq!((write.old, write.new, write.delta.index), (149, 150, 13));
+    let write = vm.history.as_ref().unwrap().last_write(watch::Target::Slot(150)).unwrap();
+    assert_eq!((write.old, write.new, write.delta.index), (0, 150, 5));
+
+    while let Some(expected) = states.pop() {
+      assert!(vm.step_back());
+      assert_eq!(history::Checkpoint::capture(&vm), expected);
+    }
+    assert!(!vm.step_back());
+  }
+
+  #[test]
+  fn edits_in_history() {
+    let (program, labels) = parse_code("psh 1\nmov a st\npsh 2\npop", true);
+    let mut vm = vm::Vm::new(program, labels);
+    vm.history = Some(history::History::default());
+    vm.set_register(B, 5);
+    assert!(!vm.step_back());
+    assert_eq!(vm.registers[B as usize], 5);
+
+    vm.step().unwrap();
+    vm.step().unwrap();
+    vm.set_register(A, 100);
+    vm.step().unwrap();
+    let write = vm.history.as_ref().unwrap().last_write(watch::Target::Register(A)).unwrap();
+    assert_eq!((write.old, write.new, write.delta.edit, write.delta.step), (1, 100, true, 2));
+    assert!(vm.step_back());
+    assert_eq!((vm.registers[A as usize], vm.steps), (100, 2));
+    assert!(vm.step_back());
+    assert_eq!((vm.registers[A as usize], vm.registers[B as usize], vm.steps), (0, 5, 1));
+    assert!(vm.step_back());
+    assert!(!vm.step_back());
+    assert_eq!(vm.registers[B as usize], 5);
+
+    // A step failing after its checkpoint records nothing to undo
+    let (program, labels) = parse_code("pop", true);
+    let mut vm = vm::Vm::new(program, labels);
+    vm.history = Some(history::History::default());
+    assert!(vm.step().is_err());
+    assert!(!vm.step_back());
+  }
+
+  #[test]
+  fn gdb_stub() {
+    let (program, labels) = parse_code("psh 5\nmov a st\nadd a a\nprt a\nhlt", true);
+    let mut stub = gdb::Stub::new(vm::Vm::new(program, labels));
+    let mut handle = |packet: &str| stub.handle(packet, &mut || false);
+
+    assert_eq!(handle("?").unwrap(), "S05");
+    assert_eq!(handle("p7").unwrap(), "ffffffff");
+    assert_eq!(handle("Z0,2,1").unwrap(), "OK");
+    assert_eq!(handle("c").unwrap(), "S05");
+    assert_eq!(handle("p6").unwrap(), "02000000");
+    assert_eq!(handle("g").unwrap(), format!("05000000{}02000000000000000500000000000000", "00000000".repeat(5)));
+    assert_eq!(handle("m0,4").unwrap(), "05000000");
+    assert_eq!(handle("M0,4:07000000").unwrap(), "OK");
+    assert_eq!(handle("m0,8").unwrap(), "0700000000000000");
+    assert_eq!(handle("m3fc,8").unwrap(), "E01");
+    assert_eq!(handle("P0=0a000000").unwrap(), "OK");
+    assert_eq!(handle("s").unwrap(), "S05");
+    assert_eq!(handle("p0").unwrap(), "14000000");
+    assert_eq!(handle("z0,2,1").unwrap(), "OK");
+    assert_eq!(handle("c").unwrap(), "W00");
+    assert_eq!(handle("vMustReplyEmpty").unwrap(), "");
+    assert!(handle("qXfer:features:read:target.xml:0,ffff").unwrap().contains("<reg name=\"eq\" bitsize=\"32\" type=\"int32\" regnum=\"9\"/>"));
+    assert_eq!(handle("k"), None);
+  }
+
+  #[test]
+  fn json() {
+    let text = r#"{"seq":3,"arguments":{"lines":[1,-2.5,true,null],"path":"a \"b\"\n\u00e9\ud83d\ude00"}}"#;
+    let value = json::Json::parse(text).unwrap();
+    assert_eq!(value.get("seq").and_then(json::Json::as_i64), Some(3));
+    let arguments = value.get("arguments").unwrap();
+    assert_eq!(arguments.get("path").and_then(json::Json::as_str), Some("a \"b\"\n\u{e9}\u{1f600}"));
+    assert_eq!(arguments.get("lines").and_then(json::Json::as_array).map(|l| l.len()), Some(4));
+    assert_eq!(json::Json::parse(&value.to_string()).unwrap(), value);
+    assert_eq!(value.to_string(), "{\"arguments\":{\"lines\":[1,-2.5,true,null],\"path\":\"a \\\"b\\\"\\n\u{e9}\u{1f600}\"},\"seq\":3}");
+    assert!(json::Json::parse("{\"a\":}").is_err());
+    assert!(json::Json::parse("[1, 2] 3").is_err());
+  }
+
+  #[test]
+  fn parse_errors() {
+    let errors = parser::diagnostics(&std::fs::read_to_string("examples/errors.vm").unwrap());
+    let found = errors.iter().map(|e| (e.line, e.message.as_str())).collect::<Vec<_>>();
+    assert_eq!(
+      found,
+      [
+        (7, "Type error : peek is not a valid register"),
+        (8, "Type error : ; is not a valid register"),
+      ]
+    );
+    assert!(parser::diagnostics("psh 1\njmp :nowhere\n.include <std/io.vm>").len() == 1);
+  }
+
+  #[test]
+  fn language_server() {
+    let mut server = lsp::Server::new();
+    let mut request = |method: &str, params: &str| {
+      let message = format!("{{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"{}\",\"params\":{}}}", method, params);
+      server.handle(&json::Json::parse(&message).unwrap());
+      server.messages().iter().map(|m| m.to_string()).collect::<Vec<String>>()
+    };
+    let at = |line: usize, character: usize| {
+      format!(
+        "{{\"textDocument\":{{\"uri\":\"file:///a.vm\"}},\"position\":{{\"line\":{},\"character\":{}}},\"newName\":\"next\"}}",
+        line, character
+      )
+    };
+
+    let open = request(
+      "textDocument/didOpen",
+      "{\"textDocument\":{\"uri\":\"file:///a.vm\",\"text\":\":start\\npsh 1 ; one\\nadd a peek\\ngto :start\"}}",
+    );
+    assert_eq!(open.len(), 1);
+    assert!(open[0].contains("\"diagnostics\":[{\"message\":\"Type error : peek is not a valid register\",\"range\":{\"end\":{\"character\":10,\"line\":2},\"start\":{\"character\":0,\"line\":2}}"));
+
+    assert!(request("textDocument/hover", &at(1, 2))[0].contains("Pushes an integer onto the stack"));
+    assert!(request("textDocument/hover", &at(2, 4))[0].contains("Register `a`: Multi purpose register"));
+    assert!(request("textDocument/hover", &at(3, 6))[0].contains("Label `:start`, defined on line 1"));
+    assert!(request("textDocument/hover", &at(1, 9))[0].contains("\"result\":null"));
+
+    let definition = request("textDocument/definition", &at(3, 5));
+    assert!(definition[0].contains("\"result\":{\"range\":{\"end\":{\"character\":6,\"line\":0},\"start\":{\"character\":0,\"line\":0}}"));
+    let references = request("textDocument/references", &at(0, 0));
+    assert_eq!(references[0].matches("\"uri\"").count(), 2);
+
+    let rename = request("textDocument/rename", &at(3, 4));
+    assert_eq!(rename[0].matches("\"newText\":\":next\"").count(), 2);
+    assert!(request("textDocument/rename", &at(2, 4))[0].contains("\"code\":-32602"));
+
+    assert!(request("textDocument/completion", &at(1, 1))[0].contains("\"label\":\"psh\""));
+    assert!(request("textDocument/completion", &at(2, 6))[0].contains("\"label\":\"eq\""));
+    assert!(request("textDocument/completion", &at(3, 4))[0].contains("\"label\":\":start\""));
+    assert!(request("unknown/method", "{}")[0].contains("\"code\":-32601"));
+  }
+
+  #[test]
+  fn formatter() {
+    let source = "  ; messy\r\n:Start PSH 5   ;five\n\n\n\n\tmov A  st ; a\ndiv a st\n.include <std/io.vm>\r\n\n";
+    let tree = fmt::Tree::parse(source);
+    assert_eq!(tree.source(), source);
+    assert_eq!(tree.lines[1].label, Some(":Start"));
+    assert_eq!(tree.lines[1].words, ["PSH", "5"]);
+    assert_eq!(tree.lines[1].comment, Some(";five"));
+    assert_eq!(tree.lines[4].newline, "\n");
+
+    let formatted = tree.format();
+    assert_eq!(
+      formatted,
+      "; messy\n:Start PSH 5   ;five\n\nmov a st ; a\ndiv a st\n.include <std/io.vm>\n"
+    );
+    assert_eq!(fmt::Tree::parse(&formatted).format(), formatted);
+    assert_eq!(fmt::Tree::parse("").format(), "");
+
+    // Formatting keeps the meaning of programs, leaving what follows a
+    // label, which the parser ignores, where it is
+    let source = ":skipped drg a\n:l  prt a ; never run\nHLT";
+    let formatted = fmt::Tree::parse(source).format();
+    assert_eq!(formatted, ":skipped drg a\n:l  prt a ; never run\nhlt\n");
+    assert_eq!(parse_code(&formatted, true), parse_code(":skipped\n:l\nhlt", true));
+    let hello = std::fs::read_to_string("examples/helloAscii.vm").unwrap();
+    let formatted = fmt::Tree::parse(&hello).format();
+    assert_eq!(parse_code(&formatted, true), parse_code(&hello, true));
+  }
+
+  #[test]
+  fn profiler() {
+    let (program, labels) = parse_code("psh 3\nmov a st\n:loop\npsh 1\nsub a st\ntne a e\njmp :loop\nhlt", true);
+    let mut vm = Vm::new(program, labels);
+    let mut profile = profile::Profile::new(vm.program.len());
+    while vm.running {
+      profile.record(&vm);
+      vm.step().unwrap();
+    }
+    assert_eq!(profile.counts, [1, 1, 3, 3, 3, 3, 1, 0]);
+    assert_eq!(profile.total(), 15);
+    let regions = profile.by_region(&vm);
+    assert_eq!(regions.len(), 2);
+    assert_eq!((regions[1].0.name.as_str(), regions[1].0.start, regions[1].0.end), (":loop", 2, 8));
+    assert_eq!((regions[0].1, regions[1].1), (2, 13));
+    // Without subroutines, the folded stacks follow the regions
+    assert_eq!(profile.folded(&vm), "main 2\nmain;:loop 13\n");
+  }
+
+  #[test]
+  fn coverage_tracefile() {
+    let lines = vec![(1, "psh 1".to_owned()), (2, "jmp 1".to_owned()), (4, "add a a".to_owned())];
+    let mut run = coverage::Coverage::new(4);
+    run.record(0, None);
+    run.record(1, Some(false));
+    let record = coverage::Record::new(&run, &lines, &[false, true, false, false], 3);
+    // Line 4 comes from an included library
+    assert_eq!(record.lines.len(), 2);
+    assert_eq!(record.branches[&(2, 0, 0)], Some(0));
+    assert_eq!(record.branches[&(2, 0, 1)], Some(1));
+
+    let lcov = "TN:\nSF:a.vm\nBRDA:2,0,0,-\nBRDA:2,0,1,-\nDA:1,0\nDA:2,0\nend_of_record\n";
+    let mut records = coverage::parse(lcov).unwrap();
+    records.get_mut("a.vm").unwrap().merge(&record);
+    records.get_mut("a.vm").unwrap().merge(&record);
+    assert_eq!(
+      coverage::to_lcov(&records),
+      "TN:\nSF:a.vm\nBRDA:2,0,0,0\nBRDA:2,0,1,2\nBRF:2\nBRH:1\nDA:1,2\nDA:2,2\nLF:2\nLH:2\nend_of_record\n"
+    );
+    assert_eq!(coverage::parse(&coverage::to_lcov(&records)).unwrap(), records);
+    assert!(coverage::parse("SF:a.vm\nDA:x,1\nend_of_record").is_err());
+    assert!(coverage::parse("SF:a.vm\nDA:1,1").is_err());
+  }
+
+  #[test]
+  fn limits() {
+    let (program, labels) = parse_code(":l\npsh 1\npop\ngto :l", true);
+    let mut vm = Vm::new(program.clone(), labels.clone());
+    vm.max_steps = Some(10);
+    assert_eq!(vm.run(), Err(vm::VmError::StepLimit));
+    assert_eq!((vm.steps, vm.remaining_steps(), vm.running), (10, Some(0), true));
+    // The program goes on once the limit is raised
+    vm.max_steps = Some(12);
+    assert_eq!(vm.run(), Err(vm::VmError::StepLimit));
+    assert_eq!(vm.steps, 12);
+
+    let mut vm = Vm::new(program.clone(), labels.clone());
+    vm.costs.parse_assignment("POP=3").unwrap();
+    assert!(vm.costs.parse_assignment("pop").is_err());
+    assert!(vm.costs.parse_assignment("nop=1").is_err());
+    vm.gas = Some(5);
+    assert_eq!(vm.run(), Err(vm::VmError::OutOfGas));
+    assert_eq!((vm.steps, vm.remaining_gas()), (3, Some(0)));
+    vm.gas = Some(vm.gas.unwrap() + 2);
+    assert_eq!(vm.run(), Err(vm::VmError::OutOfGas));
+    // psh, pop, gto, psh cost 1 + 3 + 1 + 1, then the next pop is too much
+    assert_eq!((vm.steps, vm.remaining_gas()), (4, Some(1)));
+    assert_eq!(vm.ip(), 1);
+
+    let mut vm = Vm::new(program, labels);
+    vm.set_timeout(std::time::Duration::from_millis(20));
+    assert_eq!(vm.run(), Err(vm::VmError::Timeout));
+    assert_eq!(vm.remaining_time(), Some(std::time::Duration::from_secs(0)));
+    assert!(vm::VmError::Timeout.is_limit() && !vm::VmError::DivisionByZero.is_limit());
+  }
+
+  #[test]
+  fn snapshots() {
+    let (program, labels) = parse_code(":l\npsh 1\ncal :sub\ngto :l\n:sub\nret", true);
+    let mut vm = Vm::new(program, labels);
+    for _ in 0..2 {
+      vm.step().unwrap();
+    }
+    let bytes = vm.snapshot();
+    let mut restored = Vm::restore(&bytes).unwrap();
+    assert_eq!(restored.program, vm.program);
+    assert_eq!(restored.labels, vm.labels);
+    assert_eq!(history::Checkpoint::capture(&restored), history::Checkpoint::capture(&vm));
+    assert_eq!(restored.calls, [1]);
+    for _ in 0..5 {
+      vm.step().unwrap();
+      restored.step().unwrap();
+    }
+    assert_eq!(history::Checkpoint::capture(&restored), history::Checkpoint::capture(&vm));
+
+    assert_eq!(Vm::restore(&bytes[..bytes.len() - 1]).err().unwrap(), "unexpected end of file");
+    let mut version = bytes.clone();
+    version[4] = 9;
+    assert_eq!(Vm::restore(&version).err().unwrap(), "unsupported format version 9");
+    assert_eq!(Vm::restore(b"WLBC\x01").err().unwrap(), "bad magic number");
+  }
+
+  #[test]
+  fn recordings() {
+    let (program, _) = parse_code("psh 1\ndrg st", true);
+    let mut session = replay::Session::record(&program);
+    assert!(!session.timeout(0, false));
+    assert!(session.output(1, b"1\n"));
+    assert!(session.timeout(2, true));
+    assert!(session.end(2));
+    let text = session.log.to_text();
+    assert!(text.starts_with("wlvm-record 1\nprogram "));
+    assert!(text.ends_with("output 1 310a\ntimeout 2\nend 2\n"));
+    let log = replay::Log::parse(&text).unwrap();
+    assert_eq!(log, session.log);
+
+    // The recording answers for the clock
+    let mut replayed = replay::Session::replay(log.clone(), &program).unwrap();
+    assert!(!replayed.timeout(0, true));
+    assert!(replayed.output(1, b"1\n"));
+    assert!(!replayed.timeout(1, false));
+    assert!(replayed.timeout(2, false));
+    assert!(replayed.end(2));
+
+    let mut diverging = replay::Session::replay(log.clone(), &program).unwrap();
+    assert!(!diverging.output(1, b"2\n"));
+    assert_eq!(
+      diverging.divergence.as_deref(),
+      Some("\"2\\n\" printed at step 2, but the recording has \"1\\n\" printed at step 2")
+    );
+    assert!(!diverging.end(2));
+
+    let (other, _) = parse_code("psh 2\ndrg st", true);
+    assert!(replay::Session::replay(log, &other).is_err());
+    assert!(replay::Log::parse("wlvm-record 1\noutput 1 3").is_err());
+    assert!(replay::Log::parse("wlvm-record 1\nend 3").is_err());
+  }
+
+  #[test]
+  fn line_editing() {
+    use editor::{read_key, Edit, Editor, Key, Step};
+    let keys = |bytes: &[u8]| {
+      let mut input = bytes;
+      let mut keys = vec![];
+      while !input.is_empty() {
+        keys.push(read_key(&mut input).unwrap());
+      }
+      keys
+    };
+    assert_eq!(
+      keys(b"a\x1b[D\x1b[3~\x1bOH\x1b[F\x7f\t\r\x12\x03\x04"),
+      [Key::Char('a'), Key::Left, Key::Delete, Key::Home, Key::End, Key::Backspace, Key::Tab,
+        Key::Enter, Key::Search, Key::Interrupt, Key::Eof]
+    );
+    assert_eq!(keys("é".as_bytes()), [Key::Char('é')]);
+
+    let complete = |before: &str| -> Vec<String> {
+      if before.starts_with("mov") {
+        vec!["a".into(), "b".into()]
+      } else {
+        vec!["mov".into(), "mul".into(), "psh".into()]
+      }
+    };
+    let history = vec!["psh 1".to_owned(), "drg st".to_owned(), "psh 2".to_owned()];
+    let mut edit = Edit::default();
+    let type_keys = |edit: &mut Edit, keys: &[Key]| {
+      keys.iter().map(|k| edit.key(k.clone(), &history, &complete)).last().unwrap()
+    };
+
+    // Moving around and editing
+    let typed = "pshh 3".chars().map(Key::Char).collect::<Vec<Key>>();
+    type_keys(&mut edit, &typed);
+    type_keys(&mut edit, &[Key::Home, Key::Right, Key::Right, Key::Right, Key::Delete, Key::End]);
+    assert_eq!(type_keys(&mut edit, &[Key::Enter]), Step::Accept("psh 3".to_owned()));
+    type_keys(&mut edit, &[Key::Left, Key::KillEnd, Key::Home, Key::Right, Key::KillStart]);
+    assert_eq!((edit.buffer.iter().collect::<String>(), edit.cursor), ("sh ".to_owned(), 0));
+    assert_eq!(edit.render("> ", &history), "\r> sh \x1b[K\x1b[3D");
+
+    // Walking the history, and back to the line being edited
+    let mut edit = Edit::default();
+    type_keys(&mut edit, &[Key::Char('x'), Key::Up, Key::Up]);
+    assert_eq!(type_keys(&mut edit, &[Key::Enter]), Step::Accept("drg st".to_owned()));
+    type_keys(&mut edit, &[Key::Interrupt, Key::Char('x'), Key::Up, Key::Down, Key::Down]);
+    assert_eq!(type_keys(&mut edit, &[Key::Enter]), Step::Accept("x".to_owned()));
+
+    // Searching it
+    let mut edit = Edit::default();
+    type_keys(&mut edit, &[Key::Search, Key::Char('p'), Key::Char('s')]);
+    assert_eq!(edit.render("> ", &history), "\r(search)`ps': psh 2\x1b[K");
+    type_keys(&mut edit, &[Key::Search, Key::Right]);
+    assert_eq!(type_keys(&mut edit, &[Key::Enter]), Step::Accept("psh 1".to_owned()));
+
+    // Completing words
+    let mut edit = Edit::default();
+    type_keys(&mut edit, &[Key::Char('m'), Key::Tab]);
+    assert_eq!(type_keys(&mut edit, &[Key::Tab]), Step::List(vec!["mov".into(), "mul".into()]));
+    type_keys(&mut edit, &[Key::Char('o'), Key::Tab, Key::Char('b'), Key::Tab]);
+    assert_eq!(type_keys(&mut edit, &[Key::Enter]), Step::Accept("mov b ".to_owned()));
+    assert_eq!(Edit::default().key(Key::Eof, &history, &complete), Step::Eof);
+
+    // The history is kept in a file
+    let path = std::env::temp_dir().join(format!("wlvm-history-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut editor = Editor::new(Some(path.clone()));
+    for line in &["psh 1", "psh 1", " ", "drg st"] {
+      editor.remember(line);
+    }
+    assert_eq!(Editor::new(Some(path.clone())).history, ["psh 1", "drg st"]);
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_annotations() {
+    use runner::{diff, run, Expectations};
+    let source = "; expect-stdout: 3\n;expect-reg a = 3\n; expect-error: stack underflow\npsh 3\nmov a st\ndrg a\npop\npop";
+    let expectations = Expectations::parse(source).unwrap().unwrap();
+    assert_eq!(
+      expectations,
+      Expectations {
+        stdout: Some(vec!["3".to_owned()]),
+        registers: vec![(A, 3)],
+        error: Some("stack underflow".to_owned()),
+      }
+    );
+    assert_eq!(run(source, &expectations), Vec::<String>::new());
+    assert_eq!(Expectations::parse("; a comment\npsh 1"), Ok(None));
+    assert_eq!(
+      Expectations::parse("; expect-reg z = 1"),
+      Err("line 1: invalid annotation ; expect-reg z = 1".to_owned())
+    );
+    assert!(Expectations::parse("; expect-exit: 0").is_err());
+
+    let failing = Expectations {
+      registers: vec![(B, 1)],
+      ..Expectations::default()
+    };
+    assert_eq!(
+      run(":l\npsh 1\ngto :l", &failing),
+      ["the program failed: stack overflow at instruction 1", "register b is 0, expected 1"]
+    );
+
+    let expected = ["a", "b", "c"].map(String::from);
+    assert_eq!(diff(&expected, &["a", "c", "d"]), ["  a", "- b", "  c", "+ d"]);
+    assert_eq!(diff(&expected, &[]), ["- a", "- b", "- c"]);
+  }
+
+  #[test]
+  fn assertions() {
+    let source = "psh 1\nmov a st\n:check\ntne a st\nast \"a is; not \"\nhlt";
+    let (program, labels) = parse_code(source, true);
+    assert_eq!(program[3], Ast("a is; not ".into()));
+    assert_eq!(program[3].to_string(), "ast \"a is; not \"");
+    assert_eq!(parser::diagnostics("ast a")[0].message, "Syntax error: valid syntax: `ast \"<message>\"`");
+
+    let mut vm = vm::Vm::new(program.clone(), labels.clone());
+    let error = vm.run().unwrap_err();
+    assert_eq!(error, vm::VmError::AssertionFailed("a is; not ".into()));
+    assert_eq!(error.to_string(), "assertion failed: a is; not ");
+    assert_eq!(error.code(), "ERR_ASSERTION_FAILED");
+    assert_eq!(vm.ip(), 3);
+
+    let (stripped, remapped) = optimizer::strip_assertions(&program, &labels);
+    assert_eq!(stripped, [Psh(1), Mov(A, St), Tne(A, St), Mov(A, A), Hlt, Hlt]);
+    assert_eq!(remapped, labels);
+    let mut vm = vm::Vm::new(stripped, remapped);
+    assert_eq!(vm.run(), Ok(()));
+
+    let image = object::write_image(&program, &labels);
+    assert_eq!(object::read_image(&image).unwrap().0, program);
+    // Equal messages of a program share their text
+    let program = parse_code("ast \"x\"\nast \"x\"", true).0;
+    let image = object::write_image(&program, &BTreeMap::new());
+    for program in [program.clone(), object::read_image(&image).unwrap().0] {
+      match (&program[0], &program[1]) {
+        (Ast(a), Ast(b)) => assert!(std::rc::Rc::ptr_eq(a, b)),
+        _ => unreachable!(),
+      }
+    }
+  }
+
+  #[test]
+  fn differential_runs() {
+    let run = |code: &str| {
+      let (program, labels) = parse_code(code, true);
+      let lines = parser::source_lines(code);
+      diff::Run::new(vm::Vm::new(program, labels), lines, 2)
+    };
+    let (mut left, mut right) = (run("psh 1\ndrg st\npsh 2"), run("psh 1\ndrg st\npsh 2"));
+    assert_eq!(diff::compare(&mut left, &mut right), None);
+    assert_eq!(left.vm.steps, 4);
+
+    let (mut left, mut right) = (run("psh 1\ndrg st\npsh 2"), run("psh 1\nprt st\npsh 2"));
+    assert_eq!(
+      diff::compare(&mut left, &mut right),
+      Some((2, "the left program printed \"1\\n\", the right one \"\\u{1}\"".to_owned()))
+    );
+    assert_eq!(left.context().len(), 2);
+    assert!(left.context()[1].starts_with("step 2: instruction 2 (line 2): drg st"));
+
+    let (mut left, mut right) = (run("psh 1\npsh 2\nsto a b"), run("psh 1\npsh 3\nsto a b"));
+    assert_eq!(
+      diff::compare(&mut left, &mut right).unwrap().1,
+      "st is 2 on the left, 3 on the right"
+    );
+    let (mut left, mut right) = (run("psh 1\npop\npop"), run("psh 1\npop\nhlt"));
+    assert_eq!(
+      diff::compare(&mut left, &mut right),
+      Some((3, "the left program stopped with \"stack underflow\", the right one halted".to_owned()))
+    );
+    let (mut left, mut right) = (run("psh 1\npop\npsh 4"), run("psh 1\npop\npsh 4\nhlt"));
+    left.vm.stack[9] = 7;
+    assert_eq!(
+      diff::difference(&left, &right),
+      Some("stack slot [9] is 7 on the left, 0 on the right".to_owned())
+    );
+    assert!(diff::compare(&mut left, &mut right).is_some());
+  }
+
+  #[test]
+  fn overflows_and_stack_edges() {
+    let run = |instr: Instruction, setup: &[(Register, i32)]| {
+      let (mut stack, mut regs, mut running) = setup_environment();
+      for (r, value) in setup {
+        regs[*r as usize] = *value;
+      }
+      let result = exec(&BTreeMap::new(), instr, &mut running, &mut stack, &mut regs, &mut vec![]);
+      (result, regs)
+    };
+    // Arithmetic wraps around
+    assert_eq!(run(Add(A, B), &[(A, i32::MAX), (B, 1)]).1[A as usize], i32::MIN);
+    assert_eq!(run(Sub(A, B), &[(A, i32::MIN), (B, 1)]).1[A as usize], i32::MAX);
+    assert_eq!(run(Mul(A, B), &[(A, 1 << 16), (B, 1 << 16)]).1[A as usize], 0);
+    assert_eq!(run(Div(A, B), &[(A, i32::MIN), (B, -1)]).1[A as usize], i32::MIN);
+    assert_eq!(run(Mov(Ip, A), &[(A, i32::MIN)]).1[Ip as usize], i32::MIN);
+
+    // The stack pointer may be anything
+    let full = STACK_SIZE as i32 - 1;
+    for sp in [full, i32::MAX, -2, i32::MIN] {
+      assert_eq!(run(Psh(1), &[(Sp, sp)]).0, Err(vm::VmError::StackOverflow));
+      assert_eq!(run(Cal(1), &[(Sp, sp)]).0, Err(vm::VmError::StackOverflow));
+    }
+    assert_eq!(run(Psh(1), &[(Sp, full - 1)]).0, Ok(()));
+    for sp in [-2, i32::MIN] {
+      assert_eq!(run(Pop, &[(Sp, sp)]).0, Err(vm::VmError::StackUnderflow));
+      assert_eq!(run(Ret, &[(Sp, sp)]).0, Err(vm::VmError::StackUnderflow));
+    }
+    assert_eq!(run(Pop, &[(Sp, STACK_SIZE as i32)]).0, Ok(()));
+    for sp in [STACK_SIZE as i32 + 1, i32::MAX] {
+      assert_eq!(run(Pop, &[(Sp, sp)]).0, Err(vm::VmError::InvalidStackAddress));
+    }
+    for sp in [STACK_SIZE as i32, i32::MAX] {
+      assert_eq!(run(Ret, &[(Sp, sp)]).0, Err(vm::VmError::InvalidStackAddress));
+    }
+
+    // Jumping to the largest address stops on the next step
+    let (program, labels) = parse_code("psh 2147483647\nmov ip st", false);
+    let mut vm = vm::Vm::new(program, labels);
+    assert_eq!(vm.run(), Err(vm::VmError::UndefinedInstruction));
+    let (program, labels) = parse_code("psh 2147483647\nmov sp st\npsh 1", false);
+    let mut vm = vm::Vm::new(program, labels);
+    assert_eq!(vm.run(), Err(vm::VmError::StackOverflow));
+
+    // Emptying the stack through sp is understood by the analyzer
+    check::check(&parse_code("psh -1\nmov sp st\npsh 2", false).0, 4);
+  }
+
+  #[test]
+  fn random_programs() {
+    let options = fuzz::Options { size: 30, terminating: true, in_range: true };
+    assert_eq!(fuzz::generate(7, &options), fuzz::generate(7, &options));
+    assert_ne!(fuzz::generate(7, &options), fuzz::generate(8, &options));
+    for seed in 0..200 {
+      let source = fuzz::generate(seed, &options);
+      assert_eq!(parser::diagnostics(&source), vec![], "{}", source);
+      assert_eq!(source.lines().filter(|l| !l.starts_with(':')).count(), 30);
+      let (program, labels) = parse_code(&source, false);
+      assert!(program.iter().all(|instr| optimizer::jump_target(instr).is_none_or(|t| t >= 1 && t as usize <= program.len())));
+      let mut vm = vm::Vm::new(program, labels);
+      vm.output = Some(vec![]);
+      let _ = vm.run();
+      assert!(!vm.running || vm.steps <= 31, "{}", source);
+      assert_eq!(fuzz::check_program(&source).problems, Vec::<String>::new(), "{}", source);
+    }
+    let summary = fuzz::fuzz(0, 100, &fuzz::Options { size: 10, terminating: false, in_range: false });
+    assert_eq!(summary.failures, vec![]);
+    assert!(summary.compared >= 50, "{}", summary.compared);
+
+    let compared = |source: &str| {
+      let checked = fuzz::check_program(source);
+      assert_eq!(checked.problems, Vec::<String>::new());
+      checked.compared
+    };
+    assert!(compared("cal :f\nhlt\n:f\npsh 1\npop\nret"));
+    assert!(compared("psh 1\ndrg st\npop\npop"));
+    // The optimizers leave programs seeing instruction numbers untouched
+    assert!(compared("psh 7\nret\npop\n"));
+    assert!(compared("cal 2\nlod a st\ndrg a"));
+    assert!(compared("cal 2\nmov a st\nhlt"));
+    assert!(compared("mov a ip\nhlt"));
+    // Only programs running out of steps are not compared
+    assert!(!compared(":l\ngto :l"));
+  }
+
+  #[test]
+  fn traced_reads() {
+    assert_eq!(trace::reads(&Cal(3)), vec![Sp, Ip]);
+    assert_eq!(trace::reads(&Add(A, A)), vec![A]);
+    assert_eq!(trace::reads(&Dmp), REGISTERS.to_vec());
+  }
+
+  #[test]
+  fn duplicate_labels() {
+    let errors = parser::diagnostics(":a\npsh 1\n:a\nhlt");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].line, 3);
+    assert_eq!(
+      errors[0].message,
+      "Symbol error: label :a is defined twice, on line 1 and line 3"
+    );
+
+    // A label of the program clashing with one of a library is reported on
+    // the program's line
+    let errors = parser::diagnostics(".include <std/io.vm>\n:std_print_int\nhlt");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].line, 2);
+    assert_eq!(
+      errors[0].message,
+      "Symbol error: label :std_print_int is defined twice, on line 2 and line 7 of <std/io.vm>"
+    );
+  }
+
+  #[test]
+  fn labels_and_halts() {
+    // Labels are worth the (human numbered) instruction following them. They
+    // used to be worth their own line, which is the same number unless
+    // comments, blank lines or other labels come before them
+    assert_eq!(parse_code("psh 1\n:a\npsh 2", true).1[":a"], 2);
+    let (_, labels) = parse_code("; comment\n\npsh 1\n:a\n:b\npsh 2", true);
+    assert_eq!((labels[":a"], labels[":b"]), (2, 2));
+
+    // Parsing used to stop at the first `hlt`, ignoring the rest of the file
+    assert_eq!(parse_code("psh 1\nhlt", true).0, [Psh(1), Hlt, Hlt]);
+    assert_eq!(parse_code("hlt\n:f\npsh 1", true).0, [Hlt, Psh(1), Hlt]);
+    assert_eq!(parser::diagnostics("hlt\nfoo a")[0].line, 2);
+  }
+
+  #[test]
+  fn link_out_of_range_jumps() {
+    let main = parse_module("psh 1\nhlt", true, true);
+    let lib = parse_module(".export :f\n:f\ngto -3\njmp 9\ncal 2", true, true);
+    let (program, _) =
+      object::link(&[("main.o".to_owned(), main), ("lib.o".to_owned(), lib)]).unwrap();
+    // Only the jump to an instruction of the module is moved with it
+    assert_eq!(program[3..], [Gto(-3), Jmp(9), Cal(5), Hlt]);
+  }
 }
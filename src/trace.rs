@@ -0,0 +1,230 @@
+//! Execution trace, behind `--trace`.
+//!
+//! Every step is reported as an event telling which instruction ran, the
+//! registers it read, the ones it changed and what it did to the stack. The
+//! instruction pointer only counts as written when the instruction jumps.
+
+use crate::json::Json;
+use crate::optimizer;
+use crate::vm::VmError;
+use crate::Instruction::{self, *};
+use crate::{reg_name, Register, Register::*};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StackEffect {
+    Push { slot: usize, value: i32 },
+    Pop { slot: usize, value: i32 },
+    Load { slot: usize, value: i32 },
+    Store { slot: usize, old: i32, new: i32 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Number of the step, counted from 0.
+    pub step: u64,
+    /// Index of the instruction run.
+    pub index: usize,
+    /// Source line of the instruction, when known.
+    pub line: Option<usize>,
+    pub instruction: Instruction,
+    /// Registers read, with their value.
+    pub reads: Vec<(Register, i32)>,
+    /// Registers changed, with their old and new values.
+    pub writes: Vec<(Register, i32, i32)>,
+    pub stack: Vec<StackEffect>,
+    pub error: Option<VmError>,
+}
+
+/// Registers an instruction reads, each once.
+pub fn reads(instr: &Instruction) -> Vec<Register> {
+    let mut registers = optimizer::reads(instr);
+    registers.dedup();
+    registers
+}
+
+/// Tells what an instruction did to the stack, given the registers before it
+/// ran, the old value of the slot it may have written and the stack after.
+pub fn stack_effects(
+    instr: &Instruction,
+    before: &[i32],
+    old_slot: Option<i32>,
+    stack: &[i32],
+) -> Vec<StackEffect> {
+    let slot = |s: i32| usize::try_from(s).ok().filter(|s| *s < stack.len());
+    let effect = match *instr {
+        Psh(_) | Cal(_) => slot(before[Sp as usize] + 1).map(|slot| StackEffect::Push {
+            slot,
+            value: stack[slot],
+        }),
+        Pop | Ret => slot(before[Sp as usize]).map(|slot| StackEffect::Pop {
+            slot,
+            value: stack[slot],
+        }),
+        Lod(_, b) => slot(before[b as usize]).map(|slot| StackEffect::Load {
+            slot,
+            value: stack[slot],
+        }),
+        Sto(a, _) => slot(before[a as usize]).map(|slot| StackEffect::Store {
+            slot,
+            old: old_slot.unwrap_or(stack[slot]),
+            new: stack[slot],
+        }),
+        _ => None,
+    };
+    effect.into_iter().collect()
+}
+
+impl fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackEffect::Push { slot, value } => write!(f, "push [{}] = {}", slot, value),
+            StackEffect::Pop { slot, value } => write!(f, "pop [{}] = {}", slot, value),
+            StackEffect::Load { slot, value } => write!(f, "load [{}] = {}", slot, value),
+            StackEffect::Store { slot, old, new } => {
+                write!(f, "store [{}]: {} -> {}", slot, old, new)
+            }
+        }
+    }
+}
+
+fn name(reg: Register) -> String {
+    reg_name(reg as i32).to_lowercase()
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {}: instruction {}", self.step + 1, self.index + 1)?;
+        if let Some(line) = self.line {
+            write!(f, " (line {})", line)?;
+        }
+        write!(f, ": {}", self.instruction)?;
+        if !self.reads.is_empty() {
+            let reads = self
+                .reads
+                .iter()
+                .map(|(r, value)| format!("{}={}", name(*r), value))
+                .collect::<Vec<String>>();
+            write!(f, " | reads {}", reads.join(" "))?;
+        }
+        if !self.writes.is_empty() {
+            let writes = self
+                .writes
+                .iter()
+                .map(|(r, old, new)| format!("{}: {} -> {}", name(*r), old, new))
+                .collect::<Vec<String>>();
+            write!(f, " | writes {}", writes.join(", "))?;
+        }
+        for effect in &self.stack {
+            write!(f, " | {}", effect)?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, " | error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Event {
+    pub fn to_json(&self) -> Json {
+        let slot = |slot: &usize| (*slot as i64).into();
+        let stack = self
+            .stack
+            .iter()
+            .map(|effect| match effect {
+                StackEffect::Push { slot: s, value } => Json::object(vec![
+                    ("effect", "push".into()),
+                    ("slot", slot(s)),
+                    ("value", (*value as i64).into()),
+                ]),
+                StackEffect::Pop { slot: s, value } => Json::object(vec![
+                    ("effect", "pop".into()),
+                    ("slot", slot(s)),
+                    ("value", (*value as i64).into()),
+                ]),
+                StackEffect::Load { slot: s, value } => Json::object(vec![
+                    ("effect", "load".into()),
+                    ("slot", slot(s)),
+                    ("value", (*value as i64).into()),
+                ]),
+                StackEffect::Store { slot: s, old, new } => Json::object(vec![
+                    ("effect", "store".into()),
+                    ("slot", slot(s)),
+                    ("old", (*old as i64).into()),
+                    ("new", (*new as i64).into()),
+                ]),
+            })
+            .collect::<Vec<Json>>();
+        let mut members = vec![
+            ("step", (self.step as i64).into()),
+            ("index", (self.index as i64).into()),
+            ("line", self.line.map_or(Json::Null, |l| (l as i64).into())),
+            ("instruction", self.instruction.to_string().into()),
+            (
+                "reads",
+                self.reads
+                    .iter()
+                    .map(|(r, value)| {
+                        Json::object(vec![
+                            ("register", name(*r).into()),
+                            ("value", (*value as i64).into()),
+                        ])
+                    })
+                    .collect::<Vec<Json>>()
+                    .into(),
+            ),
+            (
+                "writes",
+                self.writes
+                    .iter()
+                    .map(|(r, old, new)| {
+                        Json::object(vec![
+                            ("register", name(*r).into()),
+                            ("old", (*old as i64).into()),
+                            ("new", (*new as i64).into()),
+                        ])
+                    })
+                    .collect::<Vec<Json>>()
+                    .into(),
+            ),
+            ("stack", stack.into()),
+        ];
+        if let Some(error) = &self.error {
+            members.push(("error", error.to_string().into()));
+        }
+        Json::object(members)
+    }
+}
+
+/// Writes trace events, one per line.
+pub struct Tracer {
+    format: Format,
+    out: Box<dyn Write>,
+    /// Source line of each instruction, as returned by `parser::source_lines`.
+    lines: Vec<(usize, String)>,
+}
+
+impl Tracer {
+    pub fn new(format: Format, out: Box<dyn Write>, lines: Vec<(usize, String)>) -> Tracer {
+        Tracer { format, out, lines }
+    }
+
+    pub fn line(&self, index: usize) -> Option<usize> {
+        self.lines.get(index).map(|(line, _)| *line)
+    }
+
+    pub fn emit(&mut self, event: &Event) -> io::Result<()> {
+        match self.format {
+            Format::Text => writeln!(self.out, "{}", event),
+            Format::Json => writeln!(self.out, "{}", event.to_json()),
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! The virtual machine, as driven by `run` and by the debugger.
+
+use crate::coverage::Coverage;
+use crate::gas::Costs;
+use crate::history::{CallChange, Checkpoint, Delta, History};
+use crate::object;
+use crate::optimizer::destination;
+use crate::replay::Session;
+use crate::trace::{self, Event, Tracer};
+use crate::Instruction::*;
+use crate::{
+    exec, setup_environment, Instruction, NumOfRegisters, Register, Register::*, REGISTERS,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Why a program stopped before reaching `hlt`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    NegativeJump,
+    UndefinedInstruction,
+    StackOverflow,
+    StackUnderflow,
+    InvalidStackAddress,
+    DivisionByZero,
+    /// `max_steps` instructions ran. The program can go on once raised.
+    StepLimit,
+    /// The next instruction costs more gas than left. The program can go on
+    /// once given more.
+    OutOfGas,
+    /// The deadline passed. The program can go on once it is moved.
+    Timeout,
+    /// The run no longer does what the recording it replays did.
+    Diverged,
+    /// An `ast` instruction found `eq` false, with its message.
+    AssertionFailed(Rc<str>),
+}
+
+impl VmError {
+    /// The code the interpreter panics with.
+    pub fn code(&self) -> &'static str {
+        match self {
+            VmError::NegativeJump => "ERR_ATEMPTED_TO_JUMP_TO_NEGATIVE_OPERATION_NUMBER",
+            VmError::UndefinedInstruction => "ERR: ATTEMPTED_TO_GO_TO_UNDEFINED_INSTRUCTION",
+            VmError::StackOverflow => "ERR_STACK_OVERFLOW",
+            VmError::StackUnderflow => "ERR_STACK_UNDERFLOW",
+            VmError::InvalidStackAddress => "ERR_INVALID_STACK_ADDRESS",
+            VmError::DivisionByZero => "ERR_DIVISION_BY_ZERO",
+            VmError::StepLimit => "ERR_STEP_LIMIT",
+            VmError::OutOfGas => "ERR_OUT_OF_GAS",
+            VmError::Timeout => "ERR_TIMEOUT",
+            VmError::Diverged => "ERR_DIVERGED",
+            VmError::AssertionFailed(_) => "ERR_ASSERTION_FAILED",
+        }
+    }
+
+    /// Tells whether the program was stopped by a limit put on the run,
+    /// rather than by a fault.
+    pub fn is_limit(&self) -> bool {
+        matches!(
+            self,
+            VmError::StepLimit | VmError::OutOfGas | VmError::Timeout
+        )
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            VmError::NegativeJump => "jump to a negative instruction",
+            VmError::UndefinedInstruction => "jump outside the program",
+            VmError::StackOverflow => "stack overflow",
+            VmError::StackUnderflow => "stack underflow",
+            VmError::InvalidStackAddress => "invalid stack address",
+            VmError::DivisionByZero => "division by zero",
+            VmError::StepLimit => "step limit reached",
+            VmError::OutOfGas => "out of gas",
+            VmError::Timeout => "time limit exceeded",
+            VmError::Diverged => "execution diverged from the recording",
+            VmError::AssertionFailed(message) => return write!(f, "assertion failed: {}", message),
+        })
+    }
+}
+
+pub struct Vm {
+    pub program: Vec<Instruction>,
+    pub labels: BTreeMap<String, i32>,
+    pub stack: Vec<i32>,
+    pub registers: [i32; NumOfRegisters as usize],
+    pub running: bool,
+    /// Reports every step, as `--trace` does.
+    pub trace: Option<Tracer>,
+    /// Index of the `cal` instructions of the subroutines being run.
+    pub calls: Vec<usize>,
+    /// Number of instructions run so far.
+    pub steps: u64,
+    /// Steps run so far, when recording them.
+    pub history: Option<History>,
+    /// Where the program's output goes when captured, instead of stdout.
+    pub output: Option<Vec<u8>>,
+    /// Instructions and branches run, when measuring coverage.
+    pub coverage: Option<Coverage>,
+    /// Number of steps after which the program is stopped.
+    pub max_steps: Option<u64>,
+    /// Gas left, when metered.
+    pub gas: Option<u64>,
+    pub costs: Costs,
+    /// When the program is stopped, when given a time limit.
+    pub deadline: Option<Instant>,
+    /// The recording being made or replayed.
+    pub session: Option<Session>,
+}
+
+impl Vm {
+    pub fn new(program: Vec<Instruction>, labels: BTreeMap<String, i32>) -> Vm {
+        let (stack, registers, running) = setup_environment();
+        Vm {
+            program,
+            labels,
+            stack,
+            registers,
+            running,
+            trace: None,
+            calls: vec![],
+            steps: 0,
+            history: None,
+            output: None,
+            coverage: None,
+            max_steps: None,
+            gas: None,
+            costs: Costs::default(),
+            deadline: None,
+            session: None,
+        }
+    }
+
+    /// Serializes the program and the state of the machine, as read back by
+    /// `restore`. Tracing, coverage, history and limits are not part of it.
+    pub fn snapshot(&self) -> Vec<u8> {
+        object::write_snapshot(self)
+    }
+
+    /// Reads back a machine serialized by `snapshot`.
+    pub fn restore(bytes: &[u8]) -> Result<Vm, String> {
+        object::read_snapshot(bytes)
+    }
+
+    /// Stops the program once it has run for `limit` from now.
+    pub fn set_timeout(&mut self, limit: Duration) {
+        self.deadline = Some(Instant::now() + limit);
+    }
+
+    /// Number of steps left before reaching `max_steps`.
+    pub fn remaining_steps(&self) -> Option<u64> {
+        self.max_steps.map(|max| max.saturating_sub(self.steps))
+    }
+
+    /// Gas left, when metered.
+    pub fn remaining_gas(&self) -> Option<u64> {
+        self.gas
+    }
+
+    /// Time left before the deadline.
+    pub fn remaining_time(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Checks the limits put on the run before running `instr`, taking its gas.
+    fn check_limits(&mut self, instr: &Instruction) -> Result<(), VmError> {
+        if self.remaining_steps() == Some(0) {
+            return Err(VmError::StepLimit);
+        }
+        let clock = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        let timed_out = match &mut self.session {
+            Some(session) => session.timeout(self.steps, clock),
+            None => clock,
+        };
+        if timed_out {
+            return Err(VmError::Timeout);
+        }
+        if let Some(gas) = self.gas {
+            let cost = self.costs.cost(instr);
+            if cost > gas {
+                return Err(VmError::OutOfGas);
+            }
+            self.gas = Some(gas - cost);
+        }
+        Ok(())
+    }
+
+    /// Index of the next instruction to run.
+    pub fn ip(&self) -> usize {
+        self.registers[Ip as usize] as usize
+    }
+
+    /// The stack up to `sp`, or an error when `sp` is past its end.
+    pub fn used_stack(&self) -> Result<&[i32], String> {
+        let sp = self.registers[Sp as usize];
+        if sp as i64 >= self.stack.len() as i64 {
+            return Err(format!("sp is {}, past the end of the stack", sp));
+        }
+        Ok(&self.stack[..(sp + 1).max(0) as usize])
+    }
+
+    /// Returns the next instruction to run, if it is in the program.
+    pub fn current(&self) -> Option<Instruction> {
+        if self.registers[Ip as usize] < 0 {
+            return None;
+        }
+        self.program.get(self.ip()).cloned()
+    }
+
+    /// Name of the subroutine a `cal` instruction enters.
+    pub fn subroutine(&self, call: usize) -> String {
+        match self.program.get(call) {
+            Some(Cal(target)) => self
+                .labels
+                .iter()
+                .find(|(_, value)| *value == target)
+                .map(|(label, _)| label.clone())
+                .unwrap_or_else(|| format!("instruction {}", target)),
+            _ => "?".to_owned(),
+        }
+    }
+
+    /// Runs the next instruction.
+    pub fn step(&mut self) -> Result<(), VmError> {
+        let ip = self.ip();
+        let instr = match self.current() {
+            Some(instr) => instr,
+            None => {
+                self.running = false;
+                return Err(VmError::UndefinedInstruction);
+            }
+        };
+        // Limits leave the machine running, so that it can go on once raised
+        self.check_limits(&instr)?;
+        if self.history.as_ref().is_some_and(|h| h.wants_checkpoint()) {
+            let checkpoint = Checkpoint::capture(self);
+            self.history.as_mut().unwrap().start_segment(checkpoint);
+        }
+        let before = self.registers;
+        // Stack slot the instruction may write
+        let slot = match instr {
+            Psh(_) | Cal(_) => Some(before[Sp as usize].wrapping_add(1)),
+            Sto(a, _) => Some(before[a as usize]),
+            _ => None,
+        };
+        let old_slot = slot
+            .filter(|s| (0..self.stack.len() as i32).contains(s))
+            .map(|s| self.stack[s as usize]);
+
+        // Output is held back while recording or replaying, to be checked
+        let mut printed = vec![];
+        let stdout = &mut io::stdout();
+        let out: &mut dyn Write = match (&mut self.output, &self.session) {
+            (_, Some(_)) => &mut printed,
+            (Some(buffer), None) => buffer,
+            (None, None) => stdout,
+        };
+        let result = exec(
+            &self.labels,
+            instr.clone(),
+            &mut self.running,
+            &mut self.stack,
+            &mut self.registers,
+            out,
+        );
+        if let Some(session) = &mut self.session {
+            match &mut self.output {
+                Some(buffer) => buffer.extend_from_slice(&printed),
+                None => {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&printed).unwrap();
+                    stdout.flush().unwrap();
+                }
+            }
+            if !session.output(self.steps, &printed) {
+                self.running = false;
+                return Err(VmError::Diverged);
+            }
+        }
+        if let Some(coverage) = &mut self.coverage {
+            let taken = match (&instr, &result) {
+                (Jmp(_), Ok(())) => Some(before[Eq as usize] == 1),
+                _ => None,
+            };
+            coverage.record(ip, taken);
+        }
+        if let Err(e) = result {
+            self.running = false;
+            self.trace(ip, &instr, &before, old_slot, Some(e.clone()));
+            return Err(e);
+        }
+        match instr {
+            Cal(_) => self.calls.push(ip),
+            Ret => {
+                self.calls.pop();
+            }
+            _ => {}
+        }
+        self.registers[Ip as usize] = self.registers[Ip as usize].wrapping_add(1);
+        self.trace(ip, &instr, &before, old_slot, None);
+
+        if let Some(history) = &mut self.history {
+            let (registers, stack) = (&self.registers, &self.stack);
+            let written = destination(&instr);
+            let delta = Delta {
+                step: self.steps,
+                index: ip,
+                registers: REGISTERS
+                    .iter()
+                    .filter(|r| {
+                        Some(**r) == written || before[**r as usize] != registers[**r as usize]
+                    })
+                    .map(|r| (*r, registers[*r as usize]))
+                    .collect(),
+                slot: slot
+                    .filter(|s| (0..stack.len() as i32).contains(s))
+                    .map(|s| (s as usize, stack[s as usize])),
+                call: match instr {
+                    Cal(_) => Some(CallChange::Push(ip)),
+                    Ret => Some(CallChange::Pop),
+                    _ => None,
+                },
+                halted: !self.running,
+                edit: false,
+            };
+            history.record(delta);
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    /// Reports a step to the tracer, if any.
+    fn trace(
+        &mut self,
+        ip: usize,
+        instr: &Instruction,
+        before: &[i32; NumOfRegisters as usize],
+        old_slot: Option<i32>,
+        error: Option<VmError>,
+    ) {
+        let tracer = match &mut self.trace {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        let registers = &self.registers;
+        let event = Event {
+            step: self.steps,
+            index: ip,
+            line: tracer.line(ip),
+            instruction: instr.clone(),
+            reads: trace::reads(instr)
+                .into_iter()
+                .map(|r| (r, before[r as usize]))
+                .collect(),
+            writes: REGISTERS
+                .iter()
+                .filter(|r| before[**r as usize] != registers[**r as usize])
+                // Moving on to the next instruction is not a write to ip
+                .filter(|r| **r != Ip || registers[Ip as usize] != before[Ip as usize] + 1)
+                .map(|r| (*r, before[*r as usize], registers[*r as usize]))
+                .collect(),
+            stack: match error {
+                Some(_) => vec![],
+                None => trace::stack_effects(instr, before, old_slot, &self.stack),
+            },
+            error,
+        };
+        if let Err(e) = tracer.emit(&event) {
+            eprintln!("Error: failed to write the trace: {}", e);
+            self.trace = None;
+        }
+    }
+
+    /// Changes a register on behalf of the user, recording the change in the
+    /// history, if any.
+    pub fn set_register(&mut self, reg: Register, value: i32) {
+        if self.history.as_ref().is_some_and(|h| h.wants_checkpoint()) {
+            let checkpoint = Checkpoint::capture(self);
+            self.history.as_mut().unwrap().start_segment(checkpoint);
+        }
+        self.registers[reg as usize] = value;
+        let index = self.ip();
+        if let Some(history) = &mut self.history {
+            history.record(Delta {
+                step: self.steps,
+                index,
+                registers: vec![(reg, value)],
+                slot: None,
+                call: None,
+                halted: !self.running,
+                edit: true,
+            });
+        }
+    }
+
+    /// Goes back to the state before the last step, telling whether it was
+    /// recorded.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.take() {
+            Some(mut history) => {
+                let undone = history.undo(self);
+                self.history = Some(history);
+                undone
+            }
+            None => false,
+        }
+    }
+
+    /// Runs the program until it halts.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        while self.running {
+            self.step()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! Watchpoints, stopping the debugger or reporting when a value changes.
+
+use crate::parser::register;
+use crate::vm::Vm;
+use crate::{Register, STACK_SIZE};
+use std::fmt;
+
+/// A value of the machine that can be watched.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Register(Register),
+    /// A stack slot, written `[n]`.
+    Slot(usize),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    Target(Target),
+    Value(i32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub target: Target,
+    condition: Option<(Operand, Comparison, Operand)>,
+    /// The specification the watchpoint was created from.
+    spec: String,
+}
+
+/// A watched value that changed while its condition holds.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Hit<'a> {
+    pub watchpoint: &'a Watchpoint,
+    pub old: i32,
+    pub new: i32,
+}
+
+fn target(raw: &str) -> Result<Target, String> {
+    if let Some(reg) = register(raw) {
+        return Ok(Target::Register(reg));
+    }
+    match raw
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .map(|slot| slot.parse::<usize>())
+    {
+        Some(Ok(slot)) if slot < STACK_SIZE => Ok(Target::Slot(slot)),
+        Some(_) => Err(format!(
+            "Invalid stack slot {}, slots go from [0] to [{}]",
+            raw,
+            STACK_SIZE - 1
+        )),
+        None => Err(format!("Unknown register {}", raw)),
+    }
+}
+
+fn operand(raw: &str) -> Result<Operand, String> {
+    match raw.parse::<i32>() {
+        Ok(value) => Ok(Operand::Value(value)),
+        Err(_) => target(raw).map(Operand::Target),
+    }
+}
+
+impl Target {
+    pub fn value(self, vm: &Vm) -> i32 {
+        match self {
+            Target::Register(reg) => vm.registers[reg as usize],
+            Target::Slot(slot) => vm.stack[slot],
+        }
+    }
+}
+
+impl Operand {
+    fn value(self, vm: &Vm) -> i32 {
+        match self {
+            Operand::Target(target) => target.value(vm),
+            Operand::Value(value) => value,
+        }
+    }
+}
+
+impl Watchpoint {
+    /// Parses `<target> [if <operand> <comparison> <operand>]`, targets being
+    /// registers or stack slots such as `[3]`, and operands targets or
+    /// integers.
+    pub fn parse(spec: &str) -> Result<Watchpoint, String> {
+        let words = spec.split_whitespace().collect::<Vec<&str>>();
+        let condition = match words.len() {
+            1 => None,
+            5 if words[1] == "if" => {
+                let comparison = match words[3] {
+                    "==" => Comparison::Equal,
+                    "!=" => Comparison::NotEqual,
+                    "<" => Comparison::Less,
+                    ">" => Comparison::Greater,
+                    "<=" => Comparison::LessOrEqual,
+                    ">=" => Comparison::GreaterOrEqual,
+                    other => return Err(format!("Unknown comparison {}", other)),
+                };
+                Some((operand(words[2])?, comparison, operand(words[4])?))
+            }
+            _ => {
+                return Err(
+                    "Syntax error: valid syntax: `<register|[slot]> [if <a> <comparison> <b>]`"
+                        .to_owned(),
+                )
+            }
+        };
+        Ok(Watchpoint {
+            target: target(words[0])?,
+            condition,
+            spec: words.join(" "),
+        })
+    }
+
+    fn holds(&self, vm: &Vm) -> bool {
+        let (left, comparison, right) = match self.condition {
+            Some(condition) => condition,
+            None => return true,
+        };
+        let (a, b) = (left.value(vm), right.value(vm));
+        match comparison {
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::Less => a < b,
+            Comparison::Greater => a > b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::GreaterOrEqual => a >= b,
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+/// Returns the current values of the watched targets, to be given to `hits`
+/// after the next step.
+pub fn snapshot(watchpoints: &[Watchpoint], vm: &Vm) -> Vec<i32> {
+    watchpoints.iter().map(|w| w.target.value(vm)).collect()
+}
+
+/// Returns the watchpoints whose value changed since `before` was taken and
+/// whose condition holds.
+pub fn hits<'a>(watchpoints: &'a [Watchpoint], before: &[i32], vm: &Vm) -> Vec<Hit<'a>> {
+    watchpoints
+        .iter()
+        .zip(before)
+        .filter_map(|(watchpoint, old)| {
+            let new = watchpoint.target.value(vm);
+            if new != *old && watchpoint.holds(vm) {
+                Some(Hit {
+                    watchpoint,
+                    old: *old,
+                    new,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,45 @@
+mod common;
+
+const PROGRAM: &str = "psh 3
+mov a st
+psh 4
+tee a st ; a should be 4
+ast \"a is 4\"
+drg a";
+
+fn run(name: &str, code: &str, flags: &[&str]) -> (Option<i32>, String, String) {
+  common::run_program(name, code, "run", flags)
+}
+
+#[test]
+fn failure_report() {
+  assert_eq!(
+    run("report", PROGRAM, &[]),
+    (
+      Some(134),
+      String::new(),
+      "Error: assertion failed: a is 4
+  at instruction 5, line 5: ast \"a is 4\"
+  registers: a = 3, b = 0, c = 0, d = 0, e = 0, f = 0, ip = 4, sp = 1, st = 4, eq = 0
+  stack: 3 4
+"
+      .to_owned()
+    )
+  );
+}
+
+#[test]
+fn stripped() {
+  assert_eq!(run("stripped", PROGRAM, &["--strip-assertions"]), (Some(0), "3\n".to_owned(), String::new()));
+  // The instructions after an assertion keep their numbers, which `ret`
+  // jumps to
+  let returns = "psh 3\nret\nast \"x\"\nhlt\npsh 66\nmov a st\nprt a\nhlt";
+  assert_eq!(run("stripped-ret", returns, &[]), run("stripped-ret", returns, &["--strip-assertions"]));
+}
+
+#[test]
+fn stack_pointer_past_the_stack() {
+  let (code, _, stderr) = run("sp", "psh 1000\nmov sp st\ntne a a\nast \"x\"", &[]);
+  assert_eq!(code, Some(134));
+  assert!(stderr.ends_with("  stack: sp is 1000, past the end of the stack\n"), "{}", stderr);
+}
@@ -0,0 +1,25 @@
+mod common;
+
+fn check(name: &str, code: &str) -> (Option<i32>, String) {
+  let (code, stdout, _) = common::run_program(name, code, "check", &[]);
+  (code, stdout)
+}
+
+#[test]
+fn clean() {
+  assert_eq!(
+    check("clean", ".include <std/io.vm>\npsh 7\nmov a st\ncal :std_print_int\nhlt"),
+    (Some(0), String::new())
+  );
+}
+
+#[test]
+fn problems() {
+  assert_eq!(
+    check("problems", "pop\ndrg a\nhlt"),
+    (
+      Some(65),
+      "prog.vm:1: stack underflow, the stack is empty\nprog.vm:2: unreachable instructions, up to 3\n".to_owned()
+    )
+  );
+}
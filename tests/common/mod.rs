@@ -0,0 +1,59 @@
+//! Helpers shared by the integration tests, which run the `wlvm` binary.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A path in the temporary directory, the id of the test process being
+/// added to `name` before its extension.
+pub fn temp_path(name: &str) -> PathBuf {
+  let name = Path::new(name);
+  let stem = name.file_stem().unwrap().to_str().unwrap();
+  let mut file = format!("wlvm-{}-{}", stem, std::process::id());
+  if let Some(extension) = name.extension() {
+    file = format!("{}.{}", file, extension.to_str().unwrap());
+  }
+  std::env::temp_dir().join(file)
+}
+
+/// Writes a program to a temporary file, returning its path.
+pub fn write_program(name: &str, code: &str) -> PathBuf {
+  let path = temp_path(&format!("{}.vm", name));
+  fs::write(&path, code).unwrap();
+  path
+}
+
+pub fn wlvm() -> Command {
+  Command::new(env!("CARGO_BIN_EXE_wlvm"))
+}
+
+/// Runs a command with `input` on its standard input, returning its exit
+/// code, standard output and standard error.
+pub fn output(command: &mut Command, input: &str) -> (Option<i32>, String, String) {
+  let mut child = command
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+  let output = child.wait_with_output().unwrap();
+  (
+    output.status.code(),
+    String::from_utf8(output.stdout).unwrap(),
+    String::from_utf8(output.stderr).unwrap(),
+  )
+}
+
+/// Runs `wlvm <command> <program> <flags>` on a temporary program, the path
+/// of the program being shown as `prog.vm` in what it prints.
+pub fn run_program(name: &str, code: &str, command: &str, flags: &[&str]) -> (Option<i32>, String, String) {
+  let path = write_program(name, code);
+  let (status, stdout, stderr) = output(wlvm().arg(command).arg(&path).args(flags), "");
+  fs::remove_file(&path).unwrap();
+  let shown = path.to_str().unwrap();
+  (status, stdout.replace(shown, "prog.vm"), stderr.replace(shown, "prog.vm"))
+}
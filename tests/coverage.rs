@@ -0,0 +1,56 @@
+mod common;
+
+use std::fs;
+
+const PROGRAM: &str = "; count down
+psh 2
+mov a st
+:loop
+psh 1
+sub a st
+pop
+psh 0
+tee a st
+pop
+jmp :end
+gto :loop
+:never
+psh 9
+:end
+hlt";
+
+fn run(program: &std::path::Path, lcov: &std::path::Path, flags: &[&str]) -> (Option<i32>, String) {
+  let mut command = common::wlvm();
+  command.arg("run").arg(program).arg("--coverage").arg(lcov).args(flags);
+  let (code, _, stderr) = common::output(&mut command, "");
+  (code, stderr)
+}
+
+#[test]
+fn merged_runs() {
+  let program = common::write_program("coverage", PROGRAM);
+  let lcov = common::temp_path("coverage.lcov");
+  let _ = fs::remove_file(&lcov);
+
+  assert_eq!(run(&program, &lcov, &[]).0, Some(0));
+  let first = fs::read_to_string(&lcov).unwrap();
+  assert!(first.contains(&format!("SF:{}\n", fs::canonicalize(&program).unwrap().display())));
+  assert!(first.contains("BRDA:11,0,0,1\nBRDA:11,0,1,1\nBRF:2\nBRH:2\n"));
+  assert!(first.contains("DA:5,2\n"));
+  assert!(first.contains("DA:14,0\n"));
+  assert!(first.contains("LF:12\nLH:11\nend_of_record\n"));
+
+  assert_eq!(run(&program, &lcov, &[]).0, Some(0));
+  let second = fs::read_to_string(&lcov).unwrap();
+  assert!(second.contains("BRDA:11,0,0,2\nBRDA:11,0,1,2\n"));
+  assert!(second.contains("DA:5,4\n"));
+  assert!(second.contains("DA:14,0\n"));
+  assert_eq!(second.matches("end_of_record").count(), 1);
+
+  assert_eq!(
+    run(&program, &lcov, &["-O"]),
+    (Some(64), "Error: --coverage cannot be used with -O\n".to_owned())
+  );
+  fs::remove_file(&program).unwrap();
+  fs::remove_file(&lcov).unwrap();
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use std::fs;
+
+const PROGRAM: &str = "psh 0
+mov a st
+pop
+:loop
+psh 1
+add a st
+pop
+cal :double
+psh 20
+tll a st
+pop
+jmp :loop
+drg a
+hlt
+:double
+add a a
+ret";
+
+/// Sends requests to `wlvm dap`, returning the bodies of the messages it sent.
+fn session(name: &str, requests: &[String]) -> Vec<String> {
+  let mut input = String::new();
+  for (seq, request) in requests.iter().enumerate() {
+    let body = format!("{{\"seq\":{},\"type\":\"request\",{}}}", seq + 1, request);
+    input += &format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+  }
+  let (code, mut output, _) = common::output(common::wlvm().arg("dap"), &input);
+  assert_eq!(code, Some(0), "{}", name);
+  let mut messages = vec![];
+  while let Some(start) = output.find("\r\n\r\n") {
+    let length = output["Content-Length: ".len()..start].parse::<usize>().unwrap();
+    messages.push(output[start + 4..start + 4 + length].to_owned());
+    output = output[start + 4 + length..].to_owned();
+  }
+  messages
+}
+
+fn launch(path: &std::path::Path, breakpoints: &str) -> Vec<String> {
+  vec![
+    "\"command\":\"initialize\",\"arguments\":{\"adapterID\":\"wlvm\"}".to_owned(),
+    format!("\"command\":\"launch\",\"arguments\":{{\"program\":{:?}}}", path),
+    format!(
+      "\"command\":\"setBreakpoints\",\"arguments\":{{\"source\":{{\"path\":{:?}}},\"breakpoints\":[{}]}}",
+      path, breakpoints
+    ),
+    "\"command\":\"configurationDone\"".to_owned(),
+  ]
+}
+
+#[test]
+fn breakpoints_and_frames() {
+  let path = common::write_program("frames", PROGRAM);
+  let mut requests = launch(&path, "{\"line\":4},{\"line\":16},{\"line\":40}");
+  requests.extend(
+    [
+      "\"command\":\"stackTrace\",\"arguments\":{\"threadId\":1}",
+      "\"command\":\"continue\",\"arguments\":{\"threadId\":1}",
+      "\"command\":\"stackTrace\",\"arguments\":{\"threadId\":1}",
+      "\"command\":\"variables\",\"arguments\":{\"variablesReference\":2}",
+      "\"command\":\"stepOut\",\"arguments\":{\"threadId\":1}",
+      "\"command\":\"variables\",\"arguments\":{\"variablesReference\":1}",
+      "\"command\":\"setBreakpoints\",\"arguments\":{\"source\":{},\"breakpoints\":[]}",
+      "\"command\":\"continue\",\"arguments\":{\"threadId\":1}",
+      "\"command\":\"disconnect\"",
+    ]
+    .iter()
+    .map(|r| r.to_string()),
+  );
+  let messages = session("frames", &requests);
+  fs::remove_file(&path).unwrap();
+
+  let find = |needle: &str| {
+    messages
+      .iter()
+      .position(|m| m.contains(needle))
+      .unwrap_or_else(|| panic!("no message with {} in {:#?}", needle, messages))
+  };
+  assert!(messages[find("\"event\":\"initialized\"")].contains("\"seq\":2"));
+  assert!(messages[find("\"command\":\"setBreakpoints\"")].contains(
+    "\"breakpoints\":[{\"line\":5,\"verified\":true},{\"line\":16,\"verified\":true},{\"line\":40,\"message\":\"No instruction at or after this line\",\"verified\":false}]"
+  ));
+  let stops = messages
+    .iter()
+    .filter(|m| m.contains("\"event\":\"stopped\""))
+    .collect::<Vec<_>>();
+  assert_eq!(stops.len(), 3);
+  assert!(stops.iter().take(2).all(|m| m.contains("\"reason\":\"breakpoint\"")));
+  assert!(stops[2].contains("\"reason\":\"step\""));
+
+  let traces = messages
+    .iter()
+    .filter(|m| m.contains("\"stackFrames\""))
+    .collect::<Vec<_>>();
+  assert!(traces[0].contains("\"stackFrames\":[{\"column\":1,\"id\":0,\"line\":5,\"name\":\"main\""));
+  assert!(traces[1].contains("\"id\":0,\"line\":16,\"name\":\":double\""));
+  assert!(traces[1].contains("\"id\":1,\"line\":8,\"name\":\"main\""));
+  assert!(traces[1].contains("\"totalFrames\":2"));
+
+  assert!(messages[find("\"request_seq\":8")].contains("\"variables\":[{\"name\":\"[0]\",\"value\":\"6\",\"variablesReference\":0}]"));
+  assert!(messages[find("\"request_seq\":10")].contains("{\"name\":\"a\",\"value\":\"2\",\"variablesReference\":0}"));
+  assert!(messages[find("\"event\":\"output\"")].contains("\"output\":\"30\\n\""));
+  assert!(messages[find("\"event\":\"exited\"")].contains("\"exitCode\":0"));
+  assert!(find("\"event\":\"terminated\"") < find("\"command\":\"disconnect\""));
+}
+
+#[test]
+fn exceptions() {
+  let path = common::write_program("exceptions", "psh 1\nmov a st\ndiv a b\nhlt");
+  let mut requests = launch(&path, "");
+  requests.push("\"command\":\"stackTrace\",\"arguments\":{\"threadId\":1}".to_owned());
+  requests.push("\"command\":\"continue\",\"arguments\":{\"threadId\":1}".to_owned());
+  requests.push("\"command\":\"disconnect\"".to_owned());
+  let messages = session("exceptions", &requests);
+  fs::remove_file(&path).unwrap();
+
+  let stop = messages.iter().find(|m| m.contains("\"event\":\"stopped\"")).unwrap();
+  assert!(stop.contains("\"description\":\"division by zero\",\"reason\":\"exception\""));
+  assert!(messages.iter().any(|m| m.contains("\"line\":3,\"name\":\"main\"")));
+  assert!(messages.iter().any(|m| m.contains("\"exitCode\":1")));
+}
+
+#[test]
+fn unlaunched() {
+  let messages = session(
+    "unlaunched",
+    &[
+      "\"command\":\"stackTrace\",\"arguments\":{\"threadId\":1}".to_owned(),
+      "\"command\":\"launch\",\"arguments\":{\"program\":\"/nonexistent/wlvm.vm\"}".to_owned(),
+    ],
+  );
+  assert!(messages[0].contains("\"message\":\"the program is not launched\",\"request_seq\":1"));
+  assert!(messages[1].contains("\"success\":false"));
+  assert!(messages[1].contains("/nonexistent/wlvm.vm"));
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use std::fs;
+
+fn debug(name: &str, code: &str, commands: &str) -> String {
+  let path = common::write_program(name, code);
+  let (_, stdout, _) = common::output(common::wlvm().arg("debug").arg(&path), commands);
+  fs::remove_file(&path).unwrap();
+  stdout.replace("(wldb) ", "")
+}
+
+const PROGRAM: &str = "psh 0
+mov a st
+pop
+:loop
+psh 1
+add a st
+pop
+cal :double
+psh 20
+tll a st
+pop
+jmp :loop
+drg a
+hlt
+:double
+add a a
+ret";
+
+#[test]
+fn breakpoints() {
+  let output = debug("breakpoints", PROGRAM, "b :loop\nc\nc\np a\nd :loop\nc\nc\n");
+  assert_eq!(
+    output,
+    "16 instructions loaded, type help for a list of commands
+=> line 1: psh 0
+Breakpoint set at line 5: psh 1
+Breakpoint reached
+=> line 5: psh 1
+Breakpoint reached
+=> line 5: psh 1
+a = 2
+Breakpoint removed from line 5: psh 1
+30
+Program halted after 45 steps
+The program is not running
+"
+  );
+}
+
+#[test]
+fn subroutines() {
+  let output = debug("subroutines", PROGRAM, "b 15\nc\nwhere\nfinish\nnext\nset a 100\nc\n");
+  assert_eq!(
+    output,
+    "16 instructions loaded, type help for a list of commands
+=> line 1: psh 0
+Breakpoint set at line 16: add a a
+Breakpoint reached
+=> line 16: add a a
+=> line 16: add a a
+   called from line 8: cal :double
+=> line 9: psh 20
+=> line 10: tll a st
+100
+Program halted after 15 steps
+"
+  );
+}
+
+#[test]
+fn watchpoints() {
+  let output = debug("watchpoints", PROGRAM, "watch a if a >= 14\nc\nunwatch a if a >= 14\nwatch [0]\nc\nq\n");
+  assert_eq!(
+    output,
+    "16 instructions loaded, type help for a list of commands
+=> line 1: psh 0
+Watchpoint a if a >= 14 set
+Watchpoint a if a >= 14: 7 -> 14 at line 16: add a a
+=> line 17: ret
+Watchpoint a if a >= 14 removed
+Watchpoint [0] set
+Watchpoint [0]: 6 -> 20 at line 9: psh 20
+=> line 10: tll a st
+"
+  );
+}
+
+#[test]
+fn batch_watchpoints() {
+  let flags = ["--watch", "a if a > 20", "--watch", "eq"];
+  let (_, stdout, stderr) = common::run_program("watch", PROGRAM, "run", &flags);
+  assert_eq!(stdout, "30\n");
+  assert_eq!(
+    stderr,
+    "watch eq: instruction 9: 0 -> 1
+watch a if a > 20: instruction 14: 15 -> 30
+watch eq: instruction 9: 1 -> 0
+"
+  );
+}
+
+#[test]
+fn reverse_execution() {
+  let output = debug("reverse", PROGRAM, "b 16\nc\nc\nlw a\nrs\np a\nrc\np a\nrc\nq\n");
+  assert_eq!(
+    output,
+    "16 instructions loaded, type help for a list of commands
+=> line 1: psh 0
+Breakpoint set at line 16: add a a
+Breakpoint reached
+=> line 16: add a a
+Breakpoint reached
+=> line 16: add a a
+a was last changed from 2 to 3 by step 15, line 6: add a st
+=> line 8: cal :double
+a = 3
+Breakpoint reached
+=> line 16: add a a
+a = 1
+Reached the start of the recorded history
+=> line 1: psh 0
+"
+  );
+}
+
+#[test]
+fn stack() {
+  let output = debug("stack", "psh 4\npsh 5\npop\npop", "stack\nn\nn\nstack\nset sp 300\nstack\nset sp -1\nstack\n");
+  assert_eq!(
+    output,
+    "5 instructions loaded, type help for a list of commands
+=> line 1: psh 4
+The stack is empty
+=> line 2: psh 5
+=> line 3: pop
+[0] 4
+[1] 5
+sp is 300, past the end of the stack
+The stack is empty
+"
+  );
+}
+
+#[test]
+fn edits() {
+  let output = debug("edits", "psh 1\nmov a st\ndrg a", "n\nn\nset a 7\nlw a\nc\nrs 2\np a\nrs\np a\n");
+  assert_eq!(
+    output,
+    "4 instructions loaded, type help for a list of commands
+=> line 1: psh 1
+=> line 2: mov a st
+=> line 3: drg a
+a was last changed from 1 to 7 by set, after step 2
+7
+Program halted after 4 steps
+=> line 3: drg a
+a = 7
+=> line 2: mov a st
+a = 0
+"
+  );
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use std::fs;
+
+fn diff(programs: &[(&str, &str)], flags: &[&str]) -> (Option<i32>, String) {
+  let paths = programs
+    .iter()
+    .map(|(name, code)| common::write_program(name, code))
+    .collect::<Vec<_>>();
+  let (code, mut stdout, _) = common::output(common::wlvm().arg("diff").args(&paths).args(flags), "");
+  for path in &paths {
+    stdout = stdout.replace(path.to_str().unwrap(), "prog.vm");
+    fs::remove_file(path).unwrap();
+  }
+  (code, stdout)
+}
+
+#[test]
+fn programs() {
+  assert_eq!(
+    diff(&[("a", "psh 5\nmov a st\ndrg a"), ("b", "psh 5\nmov b st\ndrg st")], &["--context", "1"]),
+    (
+      Some(70),
+      "The runs differ after step 2: a is 5 on the left, 0 on the right, b is 0 on the left, 5 on the right
+
+Left: prog.vm
+  step 2: instruction 2 (line 2): mov a st | reads st=5 | writes a: 0 -> 5
+
+Right: prog.vm
+  step 2: instruction 2 (line 2): mov b st | reads st=5 | writes b: 0 -> 5
+"
+      .to_owned()
+    )
+  );
+  assert_eq!(
+    diff(&[("same", "psh 5\ndrg st")], &[]),
+    (Some(0), "The runs are the same over 3 steps\n".to_owned())
+  );
+}
+
+#[test]
+fn builds() {
+  let program = "psh 1\ntne st st\nast \"never\"\ndrg st";
+  let (code, stdout) = diff(&[("strip", program)], &["--right", "--strip-assertions", "--context", "1"]);
+  assert_eq!(code, Some(70));
+  assert!(stdout.starts_with("The runs differ after step 3: the left program stopped with \"assertion failed: never\", the right one still running\n"), "{}", stdout);
+  assert!(stdout.ends_with("Right: prog.vm --strip-assertions\n  step 3: instruction 3: mov a a | reads a=0\n"), "{}", stdout);
+  assert_eq!(
+    diff(&[("loop", ":l\ngto :l")], &["--right", "-O", "--max-steps", "10"]),
+    (Some(0), "The runs are the same over 10 steps, both stopping with \"step limit reached\"\n".to_owned())
+  );
+}
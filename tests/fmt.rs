@@ -0,0 +1,40 @@
+mod common;
+
+use std::fs;
+
+const MESSY: &str = "; Counts to 3\n\n\n  PSH 0 ; zero\r\nMOV A st\n:loop ; next\npsh 1\nadd a St\n\tpsh 3\ntll a st\njmp :loop\n\n";
+
+const FORMATTED: &str = "; Counts to 3\n\npsh 0 ; zero\nmov a st\n:loop ; next\npsh 1\nadd a st\npsh 3\ntll a st\njmp :loop\n";
+
+fn fmt(name: &str, code: &str, check: bool) -> (Option<i32>, String, String) {
+  let path = common::write_program(name, code);
+  let mut command = common::wlvm();
+  command.arg("fmt").arg(&path);
+  if check {
+    command.arg("--check");
+  }
+  let (status, stdout, _) = common::output(&mut command, "");
+  let code = fs::read_to_string(&path).unwrap();
+  fs::remove_file(&path).unwrap();
+  (status, stdout.replace(path.to_str().unwrap(), "prog.vm"), code)
+}
+
+#[test]
+fn rewrite() {
+  assert_eq!(fmt("rewrite", MESSY, false), (Some(0), String::new(), FORMATTED.to_owned()));
+}
+
+#[test]
+fn check() {
+  assert_eq!(
+    fmt("check", MESSY, true),
+    (Some(65), "prog.vm is not formatted\n".to_owned(), MESSY.to_owned())
+  );
+  assert_eq!(fmt("formatted", FORMATTED, true), (Some(0), String::new(), FORMATTED.to_owned()));
+}
+
+#[test]
+fn text_after_labels() {
+  let code = ":l drg a\nhlt\n";
+  assert_eq!(fmt("label", code, true), (Some(0), String::new(), code.to_owned()));
+}
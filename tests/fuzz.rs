@@ -0,0 +1,35 @@
+mod common;
+
+use std::fs;
+
+fn wlvm(args: &[&str]) -> (Option<i32>, String) {
+  let (code, stdout, _) = common::output(common::wlvm().args(args), "");
+  (code, stdout)
+}
+
+#[test]
+fn generate() {
+  let (code, source) = wlvm(&["gen", "--seed", "42", "--size", "50", "--terminating", "--in-range"]);
+  assert_eq!(code, Some(0));
+  assert_eq!(wlvm(&["gen", "--seed", "42", "--size", "50", "--terminating", "--in-range"]).1, source);
+  assert_eq!(source.lines().filter(|l| !l.starts_with(':')).count(), 50);
+
+  let path = common::write_program("gen", &source);
+  let (code, _) = wlvm(&["run", path.to_str().unwrap(), "--max-steps", "51"]);
+  fs::remove_file(&path).unwrap();
+  assert_ne!(code, Some(75));
+
+  assert_eq!(wlvm(&["gen", "--seed", "x"]).0, Some(64));
+}
+
+#[test]
+fn fuzz() {
+  assert_eq!(
+    wlvm(&["fuzz", "--runs", "200"]),
+    (Some(0), "\n200 programs, 189 compared with their optimized builds, 0 failed\n".to_owned())
+  );
+  assert_eq!(
+    wlvm(&["fuzz", "--seed", "1000", "--runs", "200", "--size", "8", "--terminating", "--in-range"]),
+    (Some(0), "\n200 programs, 200 compared with their optimized builds, 0 failed\n".to_owned())
+  );
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Stdio;
+
+fn packet(data: &str) -> String {
+  let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+  format!("${}#{:02x}", data, checksum)
+}
+
+/// Sends a packet and returns the reply, acknowledging it.
+fn exchange(stream: &mut TcpStream, data: &str) -> String {
+  stream.write_all(packet(data).as_bytes()).unwrap();
+  let mut byte = [0];
+  stream.read_exact(&mut byte).unwrap();
+  assert_eq!(byte[0], b'+');
+  stream.read_exact(&mut byte).unwrap();
+  assert_eq!(byte[0], b'$');
+  let mut reply = vec![];
+  loop {
+    stream.read_exact(&mut byte).unwrap();
+    if byte[0] == b'#' {
+      break;
+    }
+    reply.push(byte[0]);
+  }
+  let mut checksum = [0; 2];
+  stream.read_exact(&mut checksum).unwrap();
+  stream.write_all(b"+").unwrap();
+  String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn session() {
+  let path = common::write_program("gdb", "psh 72\nmov a st\nprt a\nhlt");
+  let mut child = common::wlvm()
+    .arg("run")
+    .arg(&path)
+    .args(["--gdb", "127.0.0.1:0"])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  let mut stderr = BufReader::new(child.stderr.take().unwrap());
+  let mut line = String::new();
+  stderr.read_line(&mut line).unwrap();
+  let address = line.trim().strip_prefix("Waiting for GDB on ").unwrap().to_owned();
+
+  let mut stream = TcpStream::connect(address).unwrap();
+  assert!(exchange(&mut stream, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+  assert_eq!(exchange(&mut stream, "?"), "S05");
+  assert_eq!(exchange(&mut stream, "Z0,2,1"), "OK");
+  assert_eq!(exchange(&mut stream, "c"), "S05");
+  assert_eq!(exchange(&mut stream, "p0"), "48000000");
+  assert_eq!(exchange(&mut stream, "c"), "W00");
+  assert_eq!(exchange(&mut stream, "k"), "OK");
+
+  let output = child.wait_with_output().unwrap();
+  fs::remove_file(&path).unwrap();
+  assert!(output.status.success());
+  assert_eq!(String::from_utf8(output.stdout).unwrap(), "H");
+}
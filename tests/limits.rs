@@ -0,0 +1,40 @@
+mod common;
+
+fn run(name: &str, code: &str, flags: &[&str]) -> (Option<i32>, String) {
+  let (code, _, stderr) = common::run_program(name, code, "run", flags);
+  (code, stderr)
+}
+
+const LOOP: &str = ":loop\ngto :loop";
+
+#[test]
+fn max_steps() {
+  assert_eq!(
+    run("steps", LOOP, &["--max-steps", "100"]),
+    (Some(75), "Error: step limit reached after 100 instructions\n".to_owned())
+  );
+  assert_eq!(run("enough", "psh 1\nhlt", &["--max-steps", "100"]), (Some(0), String::new()));
+}
+
+#[test]
+fn gas() {
+  assert_eq!(
+    run("gas", LOOP, &["--gas", "10", "--gas-cost", "gto=4"]),
+    (Some(75), "Error: out of gas after 2 instructions\n".to_owned())
+  );
+  assert_eq!(
+    run("cost", LOOP, &["--gas", "10", "--gas-cost", "gto"]),
+    (Some(64), "Error: expected <instruction>=<cost>, found gto\n".to_owned())
+  );
+}
+
+#[test]
+fn timeout() {
+  let (code, stderr) = run("timeout", LOOP, &["--timeout", "50"]);
+  assert_eq!(code, Some(75));
+  assert!(stderr.starts_with("Error: time limit exceeded after "));
+  assert_eq!(
+    run("invalid", LOOP, &["--timeout", "soon"]),
+    (Some(64), "Error: --timeout expects a number, found soon\n".to_owned())
+  );
+}
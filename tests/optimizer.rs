@@ -0,0 +1,69 @@
+mod common;
+
+use std::fs;
+
+fn run(path: &str, flag: Option<&str>) -> String {
+  let (code, stdout, stderr) = common::output(common::wlvm().arg("run").arg(path).args(flag), "");
+  assert_eq!(code, Some(0), "{}", stderr);
+  stdout
+}
+
+#[test]
+fn same_output() {
+  let path = common::write_program(
+    "optimizer",
+    ".include <std/io.vm>
+.include <std/sort.vm>
+psh 3
+psh 1
+psh 2
+psh 0
+mov a st
+pop
+mov a a
+psh 3
+mov b st
+pop
+cal :std_sort
+psh 0
+mov f st
+pop
+:print
+lod a f
+cal :std_print_int
+psh 1
+add f st
+pop
+psh 3
+tne f st
+pop
+jmp :print
+gto :end
+drg a
+:end
+hlt",
+  );
+  let path = path.to_str().unwrap();
+  assert_eq!(run(path, None), "123");
+  assert_eq!(run(path, Some("--optimize")), "123");
+  assert_eq!(run(path, Some("--ssa")), "123");
+  fs::remove_file(path).unwrap();
+
+  let hello = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/helloAscii.vm");
+  assert_eq!(run(hello, None), run(hello, Some("--optimize")));
+  assert_eq!(run(hello, None), run(hello, Some("--ssa")));
+}
+
+#[test]
+fn return_addresses() {
+  for (name, code) in [
+    ("return-read", "mov a a\ncal :f\nhlt\n:f\ndrg st\nret"),
+    ("return-pushed", "mov a a\npsh 4\nret\nhlt\npsh 66\nmov a st\nprt a\nhlt"),
+  ] {
+    let path = common::write_program(name, code);
+    let path = path.to_str().unwrap();
+    assert_eq!(run(path, Some("--optimize")), run(path, None));
+    assert_eq!(run(path, Some("--ssa")), run(path, None));
+    fs::remove_file(path).unwrap();
+  }
+}
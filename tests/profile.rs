@@ -0,0 +1,74 @@
+mod common;
+
+use std::fs;
+
+const PROGRAM: &str = "psh 3
+mov a st
+:loop
+cal :double
+psh 1
+sub a st
+pop
+psh 0
+tee a st
+pop
+jmp :end
+gto :loop
+:double
+mov b a
+add b a
+ret
+:end
+hlt";
+
+fn profile(name: &str, code: &str, flags: &[&str]) -> (Option<i32>, String, String) {
+  common::run_program(name, code, "profile", flags)
+}
+
+#[test]
+fn report() {
+  let (code, stdout, _) = profile("report", PROGRAM, &["--top", "2"]);
+  assert_eq!(code, Some(0));
+  assert_eq!(
+    stdout,
+    "38 instructions run
+
+Hot instructions:
+         3   7.9%  instruction 3 (line 4): cal 12
+         3   7.9%  instruction 4 (line 5): psh 1
+
+Hot lines:
+         3   7.9%  line 4: cal :double
+         3   7.9%  line 5: psh 1
+
+Hot regions:
+        26  68.4%  :loop (instructions 3-11)
+         9  23.7%  :double (instructions 12-14)
+"
+  );
+}
+
+#[test]
+fn folded_stacks() {
+  let folded = common::temp_path("profile.folded");
+  let (code, _, _) = profile("folded", PROGRAM, &["--folded", folded.to_str().unwrap()]);
+  assert_eq!(code, Some(0));
+  assert_eq!(fs::read_to_string(&folded).unwrap(), "main 29\nmain;:double 9\n");
+  fs::remove_file(&folded).unwrap();
+}
+
+#[test]
+fn error() {
+  let (code, stdout, stderr) = profile("error", "psh 0\ndiv a st", &[]);
+  assert_eq!(code, Some(65));
+  assert!(stdout.starts_with("2 instructions run\n"));
+  assert_eq!(stderr, "Error: division by zero at instruction 2\n");
+}
+
+#[test]
+fn limited() {
+  let (code, stdout, stderr) = profile("limited", ":loop\ngto :loop", &["--max-steps", "50"]);
+  assert_eq!(code, Some(75));
+  assert!(stdout.starts_with("50 instructions run\n"));
+  assert_eq!(stderr, "Error: step limit reached at instruction 1\n");
+}
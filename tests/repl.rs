@@ -0,0 +1,51 @@
+mod common;
+
+fn repl(input: &str) -> String {
+  let (code, stdout, _) = common::output(&mut common::wlvm(), input);
+  assert_eq!(code, Some(0));
+  // Drops the prompts
+  let mut rest = stdout.as_str();
+  let mut shown = String::new();
+  while let Some(start) = rest.find("(wlvm:") {
+    shown.push_str(&rest[..start]);
+    rest = &rest[start + rest[start..].find(") ").unwrap() + 2..];
+  }
+  shown.push_str(rest);
+  shown.replace("...       ", "")
+}
+
+#[test]
+fn blocks_and_jumps() {
+  let output = repl("psh 3\nmov a st\n:loop\ndrg a\npsh 1\nsub a st\ntne a e\njmp :loop\n\ndrg a\n:quit\n");
+  assert!(output.contains("3\n2\n1\n0"));
+}
+
+#[test]
+fn errors_and_undo() {
+  let output = repl("psh 2\nfoo a\npsh 0\ndiv a st\n:stack\n:undo\n:stack\n:undo\n:undo\n:stack\n:quit\n");
+  assert!(output.contains("Error: Unexpected token: foo (line 1: foo a)"));
+  assert!(output.contains("Error: division by zero at instruction 3, the snippet was undone"));
+  assert!(output.contains("[0] 2\n[1] 0\n"));
+  assert!(output.contains("[0] 2\nNothing to undo"));
+  assert!(output.contains("The stack is empty"));
+}
+
+#[test]
+fn load_and_save() {
+  let path = common::temp_path("repl.vm");
+  let path = path.to_str().unwrap();
+  let output = repl(&format!(
+    "psh 7\n:l\nmov a st\n\n:save {0}\n:reset\n:load {0}\ndrg a\n:quit\n",
+    path
+  ));
+  assert_eq!(std::fs::read_to_string(path).unwrap(), "psh 7\n:l\nmov a st\n");
+  assert!(output.contains(&format!("Saved 3 lines to {}", path)));
+  assert!(output.contains("7"));
+  std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn stack_pointer_past_the_stack() {
+  let output = repl("psh 600\nmov sp st\n:stack\n:quit\n");
+  assert!(output.contains("sp is 600, past the end of the stack\n"), "{}", output);
+}
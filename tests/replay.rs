@@ -0,0 +1,52 @@
+mod common;
+
+use std::fs;
+
+const PROGRAM: &str = "psh 0
+mov a st
+psh 1
+mov b st
+:loop
+add a b
+drg a
+gto :loop";
+
+fn run(program: &str, flags: &[&str]) -> (Option<i32>, String, String) {
+  common::output(common::wlvm().arg("run").arg(program).args(flags), "")
+}
+
+#[test]
+fn record_and_replay() {
+  let program = common::write_program("replay", PROGRAM);
+  let log = common::temp_path("replay.log");
+  let (program, log) = (program.to_str().unwrap(), log.to_str().unwrap());
+
+  let recorded = run(program, &["--timeout", "20", "--record", log]);
+  assert_eq!(recorded.0, Some(75));
+  let recording = fs::read_to_string(log).unwrap();
+  assert!(recording.contains("\noutput 5 310a\noutput 8 320a\n"));
+  assert!(recording.contains("\ntimeout "));
+
+  // The time limit is hit at the same step, without --timeout
+  let replayed = run(program, &["--replay", log]);
+  assert_eq!(replayed, recorded);
+
+  fs::write(log, recording.replace("output 8 320a", "output 8 330a")).unwrap();
+  let diverged = run(program, &["--replay", log]);
+  assert_eq!(diverged.0, Some(70));
+  assert_eq!(diverged.1, "1\n2\n");
+  assert_eq!(
+    diverged.2,
+    "Error: execution diverged from the recording: \"2\\n\" printed at step 9, but the recording has \"3\\n\" printed at step 9\n"
+  );
+
+  fs::write(program, PROGRAM.replace("psh 1", "psh 2")).unwrap();
+  let other = run(program, &["--replay", log]);
+  assert_eq!(other.0, Some(65));
+  assert_eq!(
+    other.2,
+    format!("Error: {}: the recording was made with another program\n", log)
+  );
+  fs::remove_file(program).unwrap();
+  fs::remove_file(log).unwrap();
+}
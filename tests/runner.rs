@@ -0,0 +1,44 @@
+mod common;
+
+use std::fs;
+
+fn wlvm_test(paths: &[&str]) -> (Option<i32>, String) {
+  let (code, stdout, _) = common::output(common::wlvm().arg("test").args(paths), "");
+  (code, stdout)
+}
+
+#[test]
+fn programs() {
+  let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/programs");
+  let (code, stdout) = wlvm_test(&[dir, "--jobs", "2"]);
+  assert_eq!(code, Some(0), "{}", stdout);
+  assert!(stdout.ends_with("\n5 passed, 0 failed\n"));
+}
+
+#[test]
+fn failures() {
+  let dir = common::temp_path("runner");
+  fs::create_dir_all(dir.join("sub")).unwrap();
+  fs::write(dir.join("lib.vm"), "psh 1").unwrap();
+  fs::write(dir.join("notes.txt"), "; expect-reg a = 1").unwrap();
+  fs::write(dir.join("sub/output.vm"), "; expect-stdout: 1\n; expect-stdout: 3\npsh 1\ndrg st\npsh 2\ndrg st").unwrap();
+  fs::write(dir.join("sub/error.vm"), "; expect-error: division by zero\n; expect-reg a = 1\npsh 1\nmov a st").unwrap();
+  let (code, stdout) = wlvm_test(&[dir.to_str().unwrap()]);
+  fs::remove_dir_all(&dir).unwrap();
+  let stdout = stdout.replace(dir.to_str().unwrap(), "");
+  assert_eq!(code, Some(65));
+  assert_eq!(
+    stdout,
+    "FAIL /sub/error.vm
+    expected the error \"division by zero\", but the program ended normally
+FAIL /sub/output.vm
+    the output differs (- expected, + found):
+      1
+    - 3
+    + 2
+
+0 passed, 2 failed
+"
+  );
+  assert_eq!(wlvm_test(&["/nonexistent"]).0, Some(66));
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use std::fs;
+
+const PROGRAM: &str = "psh 0
+mov a st
+psh 1
+mov b st
+psh 5
+mov c st
+:loop
+add a b
+drg a
+tee a c
+jmp :end
+gto :loop
+:end
+hlt";
+
+fn wlvm(args: &[&str]) -> (Option<i32>, String, String) {
+  common::output(common::wlvm().args(args), "")
+}
+
+#[test]
+fn resume() {
+  let program = common::write_program("snapshot", PROGRAM);
+  let state = common::temp_path("snapshot.wlss");
+  let (program, state) = (program.to_str().unwrap(), state.to_str().unwrap());
+
+  let (code, stdout, _) = wlvm(&["run", program, "--max-steps", "14", "--snapshot-on-exit", state]);
+  assert_eq!(code, Some(75));
+  assert_eq!(stdout, "1\n2\n");
+
+  // Limits count from where the program was saved
+  let (code, stdout, _) = wlvm(&["resume", state, "--max-steps", "5", "--snapshot-on-exit", state]);
+  assert_eq!(code, Some(75));
+  assert_eq!(stdout, "3\n");
+
+  let (code, stdout, _) = wlvm(&["resume", state, "--snapshot-on-exit", state]);
+  assert_eq!(code, Some(0));
+  assert_eq!(stdout, "4\n5\n");
+
+  // A program saved once halted does nothing more
+  let (code, stdout, _) = wlvm(&["resume", state]);
+  assert_eq!(code, Some(0));
+  assert_eq!(stdout, "");
+
+  fs::write(state, "WLSS").unwrap();
+  let (code, _, stderr) = wlvm(&["resume", state]);
+  assert_eq!(code, Some(65));
+  assert_eq!(stderr, format!("Error: {}: unexpected end of file\n", state));
+  fs::remove_file(program).unwrap();
+  fs::remove_file(state).unwrap();
+}
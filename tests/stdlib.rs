@@ -0,0 +1,267 @@
+mod common;
+
+fn run(name: &str, code: &str) -> String {
+  let (code, stdout, stderr) = common::run_program(name, code, "run", &[]);
+  assert_eq!(code, Some(0), "{}", stderr);
+  stdout
+}
+
+#[test]
+fn print_int() {
+  let out = run(
+    "print_int",
+    ".include <std/io.vm>
+psh 1234
+mov a st
+cal :std_print_int
+cal :std_newline
+psh -56
+mov a st
+cal :std_print_int
+cal :std_newline
+psh 0
+mov a st
+cal :std_print_int
+cal :std_newline
+pop
+pop
+pop
+drg sp",
+  );
+  assert_eq!(out, "1234\n-56\n0\n-1\n");
+}
+
+#[test]
+fn print_str() {
+  let out = run(
+    "print_str",
+    ".include <std/io.vm>
+psh 119
+psh 108
+psh 118
+psh 109
+psh 0
+psh 0
+mov a st
+cal :std_print_str",
+  );
+  assert_eq!(out, "wlvm");
+}
+
+#[test]
+fn strings() {
+  let out = run(
+    "strings",
+    ".include <std/string.vm>
+psh 111 ; Slots 0..3 : \"ok\"
+psh 107
+psh 0
+psh 0 ; Slots 3..6 : copy
+psh 0
+psh 0
+psh 0
+mov a st
+cal :std_strlen
+drg a
+psh 3
+mov a st
+pop
+psh 0
+mov b st
+pop
+cal :std_strcpy
+psh 3
+mov a st
+pop
+psh 0
+mov b st
+pop
+cal :std_strcmp
+drg a
+psh 4
+mov a st
+pop
+psh 0
+mov b st
+pop
+cal :std_strcmp
+drg a
+drg sp",
+  );
+  assert_eq!(out, "2\n0\n-1\n6\n");
+}
+
+#[test]
+fn itoa() {
+  let out = run(
+    "itoa",
+    ".include <std/string.vm>
+.include <std/io.vm>
+psh 0
+psh 0
+psh 0
+psh 0
+psh 0
+psh 0
+psh -9071
+mov a st
+pop
+psh 0
+mov b st
+pop
+cal :std_itoa
+drg a
+psh 0
+mov a st
+pop
+cal :std_print_str",
+  );
+  assert_eq!(out, "5\n-9071");
+}
+
+#[test]
+fn memory() {
+  let out = run(
+    "memory",
+    ".include <std/mem.vm>
+psh 1
+psh 2
+psh 3
+psh 0
+psh 0
+psh 0
+psh 3
+mov a st
+pop
+psh 0
+mov b st
+pop
+psh 3
+mov c st
+pop
+cal :std_memcpy
+psh 0
+mov a st
+pop
+psh 9
+mov b st
+pop
+psh 2
+mov c st
+pop
+cal :std_memset
+psh 0
+mov a st
+lod b a
+drg b
+psh 2
+mov a st
+lod b a
+drg b
+psh 5
+mov a st
+lod b a
+drg b",
+  );
+  assert_eq!(out, "9\n3\n3\n");
+}
+
+#[test]
+fn sort() {
+  let out = run(
+    "sort",
+    ".include <std/sort.vm>
+.include <std/io.vm>
+psh 5
+psh -3
+psh 8
+psh 0
+psh 5
+psh 1
+psh 0
+mov a st
+psh 6
+mov b st
+pop
+pop
+cal :std_sort
+psh 0
+mov f st
+pop
+:print
+lod a f
+cal :std_print_int
+psh 1
+add f st
+pop
+psh 6
+tne f st
+pop
+jmp :print",
+  );
+  assert_eq!(out, "-301558");
+}
+
+#[test]
+fn random() {
+  let out = run(
+    "random",
+    ".include <std/random.vm>
+psh 42
+mov a st
+pop
+cal :std_rand
+drg a
+cal :std_rand
+drg a
+psh 6
+mov b st
+pop
+cal :std_rand_range
+drg a
+drg b",
+  );
+  assert_eq!(out, "3224\n45263\n52412\n2\n");
+}
+
+#[test]
+fn math() {
+  let out = run(
+    "math",
+    ".include <std/math.vm>
+psh 17
+mov a st
+psh 5
+mov b st
+cal :std_mod
+drg a
+psh -4
+mov a st
+cal :std_abs
+drg a
+psh 3
+mov a st
+psh 4
+mov b st
+cal :std_pow
+drg a
+psh 3
+mov a st
+psh 7
+mov b st
+cal :std_min
+drg a
+psh 3
+mov a st
+cal :std_max
+drg a",
+  );
+  assert_eq!(out, "2\n4\n81\n3\n7\n");
+}
+
+#[test]
+fn unknown_library() {
+  let (code, _, stderr) = common::run_program("unknown", ".include <std/nothing.vm>\nhlt", "run", &[]);
+  assert_ne!(code, Some(0));
+  assert!(stderr.contains("no bundled library named <std/nothing.vm>"));
+}
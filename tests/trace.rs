@@ -0,0 +1,69 @@
+mod common;
+
+use std::fs;
+
+const PROGRAM: &str = "psh 2
+mov a st
+:loop
+psh 1
+sub a st
+pop
+tee a b
+jmp :end
+gto :loop
+:end
+sto a b
+lod c a
+div a a";
+
+fn run(name: &str, flags: &[&str]) -> (String, String) {
+  let (code, stdout, stderr) = common::run_program(name, PROGRAM, "run", flags);
+  assert_ne!(code, Some(0));
+  (stdout, stderr)
+}
+
+#[test]
+fn text() {
+  let (stdout, stderr) = run("text", &["--trace"]);
+  assert_eq!(stdout, "");
+  let lines = stderr.lines().take(8).collect::<Vec<&str>>();
+  assert_eq!(
+    lines,
+    [
+      "step 1: instruction 1 (line 1): psh 2 | reads sp=-1 | writes sp: -1 -> 0, st: 0 -> 2 | push [0] = 2",
+      "step 2: instruction 2 (line 2): mov a st | reads st=2 | writes a: 0 -> 2",
+      "step 3: instruction 3 (line 4): psh 1 | reads sp=0 | writes sp: 0 -> 1, st: 2 -> 1 | push [1] = 1",
+      "step 4: instruction 4 (line 5): sub a st | reads a=2 st=1 | writes a: 2 -> 1",
+      "step 5: instruction 5 (line 6): pop | reads sp=1 | writes sp: 1 -> 0, st: 1 -> 2 | pop [1] = 1",
+      "step 6: instruction 6 (line 7): tee a b | reads a=1 b=0",
+      "step 7: instruction 7 (line 8): jmp 9 | reads eq=0",
+      "step 8: instruction 8 (line 9): gto 3 | writes ip: 7 -> 2",
+    ]
+  );
+  assert!(stderr.contains(
+    "step 13: instruction 7 (line 8): jmp 9 | reads eq=1 | writes ip: 6 -> 8\n\
+     step 14: instruction 9 (line 11): sto a b | reads a=0 b=0 | writes st: 2 -> 0 | store [0]: 2 -> 0\n\
+     step 15: instruction 10 (line 12): lod c a | reads a=0 | load [0] = 0\n\
+     step 16: instruction 11 (line 13): div a a | reads a=0 | error: division by zero\n"
+  ));
+  assert_eq!(run("details", &["-d"]).1.lines().next(), Some(lines[0]));
+}
+
+#[test]
+fn json_lines() {
+  let trace = common::temp_path("trace.jsonl");
+  let (_, stderr) = run("json", &["--trace=json", "--trace-file", trace.to_str().unwrap()]);
+  assert!(!stderr.contains("\"step\""));
+  let events = fs::read_to_string(&trace).unwrap();
+  fs::remove_file(&trace).unwrap();
+  let events = events.lines().collect::<Vec<&str>>();
+  assert_eq!(events.len(), 16);
+  assert_eq!(
+    events[0],
+    r#"{"index":0,"instruction":"psh 2","line":1,"reads":[{"register":"sp","value":-1}],"stack":[{"effect":"push","slot":0,"value":2}],"step":0,"writes":[{"new":0,"old":-1,"register":"sp"},{"new":2,"old":0,"register":"st"}]}"#
+  );
+  assert_eq!(
+    events[15],
+    r#"{"error":"division by zero","index":10,"instruction":"div a a","line":13,"reads":[{"register":"a","value":0}],"stack":[],"step":15,"writes":[]}"#
+  );
+}